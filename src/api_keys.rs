@@ -0,0 +1,3 @@
+pub(crate) mod app;
+pub(crate) mod repo;
+pub(crate) mod service;
@@ -0,0 +1,124 @@
+use super::dtos::{ReqApiKeyCreation, RespApiKeyCreation};
+use super::errors::from_service_error;
+use crate::api_keys::service::errors::Error as ServiceError;
+use crate::api_keys::service::idos::{ApiKey, NewApiKey, Secret};
+use crate::auth;
+use crate::problem::{self, Problem};
+
+use std::sync::Arc;
+
+use actix_web::middleware::from_fn;
+use actix_web::{HttpRequest, HttpResponse, Responder, delete, get, post, web};
+use serde_json::json;
+use uuid::Uuid;
+
+// ADMIN_SCOPE is required for every key management endpoint
+pub const ADMIN_SCOPE: &str = "api_keys:admin";
+
+#[async_trait::async_trait]
+pub trait Service: Send + Sync + 'static {
+    // create_key returns the secret alongside the key, it is not stored and cannot be recovered
+    async fn create_key(&self, key: NewApiKey) -> Result<(ApiKey, Secret), ServiceError>;
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, ServiceError>;
+
+    async fn revoke_key(&self, id: Uuid) -> Result<(), ServiceError>;
+}
+
+#[derive(Clone)]
+pub struct App {
+    scope: String,
+    service: Arc<dyn Service>,
+}
+
+impl App {
+    pub fn new(scope: String, service: Arc<dyn Service>) -> Self {
+        Self { scope, service }
+    }
+
+    pub fn configure(&self) -> impl FnOnce(&mut web::ServiceConfig) + Clone {
+        let scope = self.scope.clone();
+        let service: Arc<dyn Service + 'static> = self.service.clone();
+
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.service(
+                web::scope(&scope)
+                    .wrap(from_fn(auth::middleware))
+                    .app_data::<web::Data<Arc<dyn Service>>>(web::Data::new(service))
+                    .service(create_key)
+                    .service(list_keys)
+                    .service(revoke_key),
+            );
+        }
+    }
+}
+
+#[post("")]
+async fn create_key(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    key: web::Json<ReqApiKeyCreation>,
+) -> impl Responder {
+    if let Some(resp) = require_admin(&req, &principal) {
+        return resp;
+    }
+
+    let new_key = match key.into_inner().into_new_key() {
+        Ok(new_key) => new_key,
+        Err(e) => return from_service_error(&req, ServiceError::Validation(e)),
+    };
+
+    match service.create_key(new_key).await {
+        Ok((api_key, secret)) => HttpResponse::Created().json(RespApiKeyCreation {
+            api_key,
+            key: secret.as_str().to_string(),
+        }),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+#[get("")]
+async fn list_keys(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+) -> impl Responder {
+    if let Some(resp) = require_admin(&req, &principal) {
+        return resp;
+    }
+
+    match service.list_keys().await {
+        Ok(keys) => HttpResponse::Ok().json(json!({ "keys": keys })),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+// revoked keys are kept, so they still show up in listings with the time they were revoked
+#[delete("/{id}")]
+async fn revoke_key(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    key_id: web::Path<Uuid>,
+) -> impl Responder {
+    if let Some(resp) = require_admin(&req, &principal) {
+        return resp;
+    }
+
+    match service.revoke_key(key_id.into_inner()).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+fn require_admin(req: &HttpRequest, principal: &auth::Principal) -> Option<HttpResponse> {
+    if principal.scopes.iter().any(|scope| scope == ADMIN_SCOPE) {
+        return None;
+    }
+
+    let reason = format!("managing api keys requires the {} scope", ADMIN_SCOPE);
+    tracing::info!("Denied request: {}", reason);
+
+    Some(Problem::new(req, &problem::FORBIDDEN, Some(reason)).into_response())
+}
@@ -0,0 +1,54 @@
+use crate::api_keys::service::idos;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const MAX_NAME_LENGTH: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ReqApiKeyCreation {
+    pub name: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ReqApiKeyCreation {
+    pub fn into_new_key(self) -> Result<idos::NewApiKey, String> {
+        let name = self.name.trim().to_string();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+            return Err(format!(
+                "name must be between 1 and {} characters",
+                MAX_NAME_LENGTH
+            ));
+        }
+
+        // scopes end up space separated wherever tokens carry them, so they cannot contain spaces
+        if let Some(scope) = self
+            .scopes
+            .iter()
+            .find(|scope| scope.is_empty() || !scope.chars().all(|c| c.is_ascii_graphic()))
+        {
+            return Err(format!("invalid scope: {:?}", scope));
+        }
+
+        let mut scopes = self.scopes;
+        scopes.sort();
+        scopes.dedup();
+
+        Ok(idos::NewApiKey {
+            name,
+            scopes,
+            expires_at: self.expires_at,
+        })
+    }
+}
+
+// RespApiKeyCreation is the only response that includes the key itself, it cannot be retrieved
+// again later
+#[derive(Serialize)]
+pub struct RespApiKeyCreation {
+    #[serde(flatten)]
+    pub api_key: idos::ApiKey,
+    pub key: String,
+}
@@ -0,0 +1,38 @@
+use crate::api_keys::service::errors;
+use crate::problem::{INTERNAL_ERROR, INVALID_REQUEST, Problem, ProblemType};
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+
+// the catalogue of problem types for service errors, sharing the entries in problem where the
+// meaning is the same
+
+pub const API_KEY_NOT_FOUND: ProblemType = ProblemType {
+    uri: "/problems/api-key-not-found",
+    title: "API key not found",
+    status: StatusCode::NOT_FOUND,
+};
+
+pub fn problem_type(svc_err: &errors::Error) -> &'static ProblemType {
+    match svc_err {
+        errors::Error::NotFound => &API_KEY_NOT_FOUND,
+        errors::Error::Validation(_) => &INVALID_REQUEST,
+        errors::Error::Internal => &INTERNAL_ERROR,
+    }
+}
+
+pub fn from_service_error(req: &HttpRequest, svc_err: errors::Error) -> HttpResponse {
+    let problem_type = problem_type(&svc_err);
+
+    let problem = match svc_err {
+        errors::Error::NotFound => Problem::new(req, problem_type, Some(svc_err.to_string())),
+        errors::Error::Validation(e) => Problem::new(req, problem_type, Some(e)),
+        errors::Error::Internal => {
+            tracing::error!("Unhandled service error: {:?}", svc_err);
+
+            Problem::new(req, problem_type, None)
+        }
+    };
+
+    problem.into_response()
+}
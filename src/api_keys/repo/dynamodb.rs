@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::errors::Error;
+use crate::api_keys::service;
+use crate::api_keys::service::idos;
+use crate::dynamodb::attrs::{get_datetime, get_optional_datetime, get_string, get_uuid};
+use crate::dynamodb::health::TableCheck;
+use crate::dynamodb::instrument;
+use crate::health::Check;
+
+use aws_sdk_dynamodb::{
+    Client,
+    types::{AttributeValue, ReturnConsumedCapacity},
+};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Clone)]
+pub struct Repo {
+    client: Client,
+    table_name: String,
+}
+
+impl Repo {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+
+    pub fn health_checks(&self) -> Vec<Arc<dyn Check>> {
+        vec![Arc::new(TableCheck::new(
+            self.client.clone(),
+            self.table_name.clone(),
+        ))]
+    }
+
+    // update_key sets a single timestamp attribute on an existing key
+    async fn update_key(
+        &self,
+        id: Uuid,
+        update_expression: &str,
+        at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let request = self
+            .client
+            .update_item()
+            .table_name(self.table_name.clone())
+            .key("id".to_string(), AttributeValue::S(id.to_string()))
+            .update_expression(update_expression)
+            .condition_expression("attribute_exists(id)")
+            .expression_attribute_values(":at", AttributeValue::S(at.to_rfc3339()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("UpdateItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::NotFound)
+            }
+            Err(e) => {
+                tracing::error!("Failed to update api key: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "dynamodb.create_key", skip_all, fields(key_id = %key.id))]
+    async fn create_key(&self, key: &idos::ApiKey) -> Result<(), Error> {
+        let request = self
+            .client
+            .put_item()
+            .table_name(self.table_name.clone())
+            .set_item(Some(key_to_attrs(key)))
+            .condition_expression("attribute_not_exists(id)")
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("PutItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::AlreadyExists)
+            }
+            Err(e) => {
+                tracing::error!("Failed to put api key: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.get_key", skip_all, fields(key_id = %id))]
+    async fn get_key(&self, id: Uuid) -> Result<idos::ApiKey, Error> {
+        // every request is authenticated with this read, so it must see a revocation as soon as
+        // it has been written
+        let request = self
+            .client
+            .get_item()
+            .table_name(self.table_name.clone())
+            .key("id".to_string(), AttributeValue::S(id.to_string()))
+            .consistent_read(true)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("GetItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(output) => match output.item() {
+                Some(attrs) => key_from_attrs(attrs),
+                None => Err(Error::NotFound),
+            },
+            Err(e) => {
+                tracing::error!("Failed to get api key: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    // there are only ever a handful of keys, so listing reads them all
+    #[tracing::instrument(name = "dynamodb.list_keys", skip_all)]
+    async fn list_keys(&self) -> Result<Vec<idos::ApiKey>, Error> {
+        let mut keys = vec![];
+        let mut start_key = None;
+
+        loop {
+            let request = self
+                .client
+                .scan()
+                .table_name(self.table_name.clone())
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+            let output = match instrument::call("Scan", &[&self.table_name], request.send()).await {
+                Ok(output) => output,
+                Err(e) => {
+                    tracing::error!("Failed to scan api keys: {:?}", e);
+                    return Err(Error::Internal);
+                }
+            };
+
+            for attrs in output.items() {
+                keys.push(key_from_attrs(attrs)?);
+            }
+
+            match output.last_evaluated_key {
+                Some(key) => start_key = Some(key),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    #[tracing::instrument(name = "dynamodb.revoke_key", skip_all, fields(key_id = %id))]
+    async fn revoke_key(&self, id: Uuid, revoked_at: DateTime<Utc>) -> Result<(), Error> {
+        self.update_key(
+            id,
+            "SET revoked_at = if_not_exists(revoked_at, :at)",
+            revoked_at,
+        )
+        .await
+    }
+
+    #[tracing::instrument(name = "dynamodb.record_use", skip_all, fields(key_id = %id))]
+    async fn record_use(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), Error> {
+        self.update_key(id, "SET last_used_at = :at", used_at).await
+    }
+}
+
+fn key_to_attrs(key: &idos::ApiKey) -> HashMap<String, AttributeValue> {
+    let mut attrs = HashMap::from([
+        ("id".to_string(), AttributeValue::S(key.id.to_string())),
+        ("name".to_string(), AttributeValue::S(key.name.clone())),
+        // a string set cannot be empty, so scopes are stored as a list
+        (
+            "scopes".to_string(),
+            AttributeValue::L(
+                key.scopes
+                    .iter()
+                    .map(|scope| AttributeValue::S(scope.clone()))
+                    .collect(),
+            ),
+        ),
+        ("hash".to_string(), AttributeValue::S(key.hash.clone())),
+        (
+            "created_at".to_string(),
+            AttributeValue::S(key.created_at.to_rfc3339()),
+        ),
+    ]);
+
+    for (name, value) in [
+        ("expires_at", key.expires_at),
+        ("last_used_at", key.last_used_at),
+        ("revoked_at", key.revoked_at),
+    ] {
+        if let Some(value) = value {
+            attrs.insert(name.to_string(), AttributeValue::S(value.to_rfc3339()));
+        }
+    }
+
+    attrs
+}
+
+fn key_from_attrs(attrs: &HashMap<String, AttributeValue>) -> Result<idos::ApiKey, Error> {
+    let id = get_uuid(attrs, "id")?;
+
+    let scopes = match attrs.get("scopes") {
+        Some(AttributeValue::L(values)) => values
+            .iter()
+            .map(|value| match value {
+                AttributeValue::S(scope) => Ok(scope.clone()),
+                _ => Err(Error::MalformedResponse(
+                    "incorrect type for scope".to_string(),
+                )),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => {
+            return Err(Error::MalformedResponse(
+                "incorrect type for scopes".to_string(),
+            ));
+        }
+        None => vec![],
+    };
+
+    Ok(idos::ApiKey {
+        id,
+        name: get_string(attrs, "name")?,
+        scopes,
+        hash: get_string(attrs, "hash")?,
+        created_at: get_datetime(attrs, "created_at")?,
+        expires_at: get_optional_datetime(attrs, "expires_at")?,
+        last_used_at: get_optional_datetime(attrs, "last_used_at")?,
+        revoked_at: get_optional_datetime(attrs, "revoked_at")?,
+    })
+}
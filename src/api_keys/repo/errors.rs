@@ -0,0 +1,22 @@
+use crate::dynamodb::attrs::Malformed;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("api key not found")]
+    NotFound,
+
+    #[error("api key already exists")]
+    AlreadyExists,
+
+    #[error("malformed response: {0}")]
+    MalformedResponse(String),
+
+    #[error("internal error")]
+    Internal,
+}
+
+impl From<Malformed> for Error {
+    fn from(malformed: Malformed) -> Self {
+        Error::MalformedResponse(malformed.0)
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::errors::Error;
+use crate::api_keys::service;
+use crate::api_keys::service::idos;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Clone, Default)]
+pub struct Repo {
+    keys: Arc<RwLock<HashMap<Uuid, idos::ApiKey>>>,
+}
+
+impl Repo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "memory.create_key", skip_all, fields(key_id = %key.id))]
+    async fn create_key(&self, key: &idos::ApiKey) -> Result<(), Error> {
+        let mut keys = self.keys.write().await;
+
+        if keys.contains_key(&key.id) {
+            return Err(Error::AlreadyExists);
+        }
+
+        keys.insert(key.id, key.clone());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.get_key", skip_all, fields(key_id = %id))]
+    async fn get_key(&self, id: Uuid) -> Result<idos::ApiKey, Error> {
+        let keys = self.keys.read().await;
+
+        keys.get(&id).cloned().ok_or(Error::NotFound)
+    }
+
+    #[tracing::instrument(name = "memory.list_keys", skip_all)]
+    async fn list_keys(&self) -> Result<Vec<idos::ApiKey>, Error> {
+        let keys = self.keys.read().await;
+
+        Ok(keys.values().cloned().collect())
+    }
+
+    #[tracing::instrument(name = "memory.revoke_key", skip_all, fields(key_id = %id))]
+    async fn revoke_key(&self, id: Uuid, revoked_at: DateTime<Utc>) -> Result<(), Error> {
+        let mut keys = self.keys.write().await;
+
+        let key = keys.get_mut(&id).ok_or(Error::NotFound)?;
+        key.revoked_at.get_or_insert(revoked_at);
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.record_use", skip_all, fields(key_id = %id))]
+    async fn record_use(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), Error> {
+        let mut keys = self.keys.write().await;
+
+        let key = keys.get_mut(&id).ok_or(Error::NotFound)?;
+        key.last_used_at = Some(used_at);
+
+        Ok(())
+    }
+}
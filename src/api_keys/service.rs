@@ -0,0 +1,3 @@
+pub(crate) mod core;
+pub(crate) mod errors;
+pub(crate) mod idos;
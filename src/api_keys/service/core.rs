@@ -0,0 +1,134 @@
+use super::errors::Error;
+use super::idos::{ApiKey, NewApiKey, Secret};
+use crate::api_keys::app::core::Service as AppService;
+use crate::api_keys::repo::errors::Error as RepoError;
+use crate::auth;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use uuid::Uuid;
+
+// last_used_at is only written when it is at least this stale, so a busy caller does not turn
+// every request into a write
+const LAST_USED_RESOLUTION: TimeDelta = TimeDelta::minutes(1);
+
+#[async_trait::async_trait]
+pub trait Repo: Send + Sync + Clone + 'static {
+    async fn create_key(&self, key: &ApiKey) -> Result<(), RepoError>;
+
+    async fn get_key(&self, id: Uuid) -> Result<ApiKey, RepoError>;
+
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, RepoError>;
+
+    // revoke_key keeps the time the key was first revoked, so revoking twice changes nothing
+    async fn revoke_key(&self, id: Uuid, revoked_at: DateTime<Utc>) -> Result<(), RepoError>;
+
+    async fn record_use(&self, id: Uuid, used_at: DateTime<Utc>) -> Result<(), RepoError>;
+}
+
+#[derive(Clone)]
+pub struct Service<R: Repo> {
+    repo: R,
+}
+
+impl<R: Repo> Service<R> {
+    pub fn new(repo: R) -> Self {
+        Self { repo }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Repo> AppService for Service<R> {
+    #[tracing::instrument(name = "service.create_key", skip_all, fields(name = %key.name))]
+    async fn create_key(&self, key: NewApiKey) -> Result<(ApiKey, Secret), Error> {
+        let now = Utc::now();
+
+        if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(Error::Validation(
+                "expires_at must be in the future".to_string(),
+            ));
+        }
+
+        let id = Uuid::new_v4();
+        let secret = Secret::generate(id);
+
+        let api_key = ApiKey {
+            id,
+            name: key.name,
+            scopes: key.scopes,
+            hash: secret.hash(),
+            created_at: now,
+            expires_at: key.expires_at,
+            last_used_at: None,
+            revoked_at: None,
+        };
+
+        self.repo
+            .create_key(&api_key)
+            .await
+            .map_err(Error::from_repo_error)?;
+
+        tracing::info!("Created api key {}", api_key.id);
+
+        Ok((api_key, secret))
+    }
+
+    #[tracing::instrument(name = "service.list_keys", skip_all)]
+    async fn list_keys(&self) -> Result<Vec<ApiKey>, Error> {
+        let mut keys = self
+            .repo
+            .list_keys()
+            .await
+            .map_err(Error::from_repo_error)?;
+
+        keys.sort_by_key(|key| key.created_at);
+
+        Ok(keys)
+    }
+
+    #[tracing::instrument(name = "service.revoke_key", skip_all, fields(key_id = %id))]
+    async fn revoke_key(&self, id: Uuid) -> Result<(), Error> {
+        self.repo
+            .revoke_key(id, Utc::now())
+            .await
+            .map_err(Error::from_repo_error)?;
+
+        tracing::info!("Revoked api key {}", id);
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Repo> auth::ApiKeys for Service<R> {
+    #[tracing::instrument(name = "service.authenticate_key", skip_all)]
+    async fn authenticate(&self, key: &str) -> Result<auth::Principal, auth::Error> {
+        let secret = Secret::parse(key).ok_or(auth::Error::InvalidApiKey)?;
+
+        let api_key = match self.repo.get_key(secret.id()).await {
+            Ok(api_key) => api_key,
+            Err(RepoError::NotFound) => return Err(auth::Error::InvalidApiKey),
+            Err(e) => {
+                tracing::error!("Failed to get api key {}: {}", secret.id(), e);
+                return Err(auth::Error::Unavailable);
+            }
+        };
+
+        let now = Utc::now();
+        if !api_key.matches(&secret) || api_key.is_revoked() || api_key.is_expired(now) {
+            return Err(auth::Error::InvalidApiKey);
+        }
+
+        let stale = api_key
+            .last_used_at
+            .is_none_or(|last_used_at| now - last_used_at >= LAST_USED_RESOLUTION);
+        if stale && let Err(e) = self.repo.record_use(api_key.id, now).await {
+            // the key is still good, a missed timestamp is not worth failing the request over
+            tracing::warn!("Failed to record use of api key {}: {}", api_key.id, e);
+        }
+
+        Ok(auth::Principal {
+            subject: format!("api_key:{}", api_key.id),
+            scopes: api_key.scopes,
+        })
+    }
+}
@@ -0,0 +1,25 @@
+use crate::api_keys::repo;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("api key not found")]
+    NotFound,
+
+    #[error("validation error: {0}")]
+    Validation(String),
+
+    #[error("internal error")]
+    Internal,
+}
+
+impl Error {
+    pub fn from_repo_error(repo_err: repo::errors::Error) -> Self {
+        match repo_err {
+            repo::errors::Error::NotFound => Error::NotFound,
+            // key ids are generated, so a clash is a bug rather than something the caller did
+            repo::errors::Error::AlreadyExists
+            | repo::errors::Error::MalformedResponse(_)
+            | repo::errors::Error::Internal => Error::Internal,
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use crate::tokens;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+
+const KEY_PREFIX: &str = "ak_";
+
+// ApiKey is a stored key. Only a hash of the key itself is kept, so a leaked table does not
+// give away working keys.
+#[derive(Serialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    #[serde(skip)]
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn matches(&self, secret: &Secret) -> bool {
+        secret.0.matches(&self.hash)
+    }
+}
+
+// NewApiKey is what an administrator asks for when creating a key
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Secret is a key as handed to its holder, ak_<key id>_<random>. Carrying the id lets a key be
+// fetched directly, rather than by searching for its hash.
+#[derive(Debug)]
+pub struct Secret(tokens::Secret<1>);
+
+impl Secret {
+    pub fn generate(id: Uuid) -> Self {
+        Self(tokens::Secret::generate(KEY_PREFIX, [id]))
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        tokens::Secret::parse(KEY_PREFIX, value).map(Self)
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.0.ids()[0]
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn hash(&self) -> String {
+        self.0.hash()
+    }
+}
@@ -0,0 +1,343 @@
+pub(crate) mod login;
+pub(crate) mod recovery;
+pub(crate) mod tokens;
+
+use crate::config;
+use crate::problem::{self, Problem};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error as ActixError, FromRequest, HttpMessage, HttpRequest, HttpResponse, web};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+// service to service callers authenticate with an API key in this header instead of a token
+pub const X_API_KEY: HeaderName = HeaderName::from_static("x-api-key");
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read JWKS file {0}: {1}")]
+    ReadJwks(PathBuf, std::io::Error),
+
+    #[error("failed to parse JWKS file {0}: {1}")]
+    ParseJwks(PathBuf, serde_json::Error),
+
+    #[error("invalid key {0} in JWKS file: {1}")]
+    InvalidJwk(String, jsonwebtoken::errors::Error),
+
+    #[error("missing bearer token")]
+    MissingToken,
+
+    #[error("token signed with unsupported algorithm {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+
+    #[error("no key matches token key id {0:?}")]
+    UnknownKey(Option<String>),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+
+    #[error("invalid, expired or revoked api key")]
+    InvalidApiKey,
+
+    #[error("invalid email or password")]
+    InvalidCredentials,
+
+    #[error("invalid, expired or revoked refresh token")]
+    InvalidRefreshToken,
+
+    #[error("invalid, expired or already used token")]
+    InvalidOneTimeToken,
+
+    #[error("failed to sign token: {0}")]
+    Sign(jsonwebtoken::errors::Error),
+
+    // the credentials could not be checked, which says nothing about whether they are valid
+    #[error("authentication is unavailable")]
+    Unavailable,
+}
+
+// ApiKeys verifies API keys, which unlike tokens can only be checked against stored state
+#[async_trait::async_trait]
+pub trait ApiKeys: Send + Sync + 'static {
+    async fn authenticate(&self, key: &str) -> Result<Principal, Error>;
+}
+
+// Credentials verifies the email and password a user logs in with, returning the subject the
+// tokens issued to them are for
+#[async_trait::async_trait]
+pub trait Credentials: Send + Sync + 'static {
+    async fn verify_password(&self, email: &str, password: &str) -> Result<String, Error>;
+}
+
+// Sessions keeps the refresh tokens issued on login. Each token can be used once, to get the
+// next one in its session.
+#[async_trait::async_trait]
+pub trait Sessions: Send + Sync + 'static {
+    // start begins a session for subject, returning its first refresh token
+    async fn start(&self, subject: &str) -> Result<String, Error>;
+
+    // refresh swaps a refresh token for the next one, returning the subject along with it
+    async fn refresh(&self, refresh_token: &str) -> Result<(String, String), Error>;
+
+    // end revokes the session a refresh token belongs to
+    async fn end(&self, refresh_token: &str) -> Result<(), Error>;
+}
+
+// Recovery lets users prove they own their email address with a one-time token mailed to it,
+// either to verify the address or to set a new password when they cannot log in
+#[async_trait::async_trait]
+pub trait Recovery: Send + Sync + 'static {
+    async fn verify_email(&self, token: &str) -> Result<(), Error>;
+
+    // request_password_reset mails a reset token to the user with the email, if there is one
+    async fn request_password_reset(&self, email: &str) -> Result<(), Error>;
+
+    // reset_password sets a new password, returning the subject whose password it was
+    async fn reset_password(&self, token: &str, password: &str) -> Result<String, Error>;
+}
+
+// Principal is who a request was made by, as asserted by its bearer token
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub subject: String,
+    pub scopes: Vec<String>,
+}
+
+// handlers behind the middleware take the principal it authenticated as an argument. Should one
+// be reached without the middleware in front of it, the request is treated as unauthenticated.
+impl FromRequest for Principal {
+    type Error = ActixError;
+    type Future = std::future::Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let result = match req.extensions().get::<Principal>() {
+            Some(principal) => Ok(principal.clone()),
+            None => {
+                tracing::error!("No principal for request, is it behind the auth middleware?");
+                let response = unauthorized(req, &Error::MissingToken);
+                Err(InternalError::from_response(Error::MissingToken, response).into())
+            }
+        };
+
+        std::future::ready(result)
+    }
+}
+
+// Claims are the members of a token the API relies on. Scopes are accepted both as a space
+// separated scope claim (RFC 8693) and as an scp array, which some issuers use instead.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scp: Vec<String>,
+}
+
+impl Claims {
+    fn into_principal(self) -> Principal {
+        let mut scopes: Vec<String> = self
+            .scope
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .map(|scope| scope.to_string())
+            .chain(self.scp)
+            .collect();
+        scopes.sort();
+        scopes.dedup();
+
+        Principal {
+            subject: self.sub,
+            scopes,
+        }
+    }
+}
+
+// Authenticator verifies bearer tokens, signed either with the shared HS256 secret or with one
+// of the RS256 keys in the JWKS file, and API keys
+pub struct Authenticator {
+    api_keys: Arc<dyn ApiKeys>,
+    hs256_key: Option<DecodingKey>,
+    // RS256 keys by key id
+    rs256_keys: HashMap<String, DecodingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway_seconds: u64,
+}
+
+impl Authenticator {
+    pub fn new(config: &config::core::Auth, api_keys: Arc<dyn ApiKeys>) -> Result<Self, Error> {
+        let hs256_key = config
+            .hs256_secret
+            .as_ref()
+            .map(|secret| DecodingKey::from_secret(secret.as_bytes()));
+
+        let rs256_keys = match &config.jwks_path {
+            Some(path) => load_jwks(path)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            api_keys,
+            hs256_key,
+            rs256_keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway_seconds: config.leeway_seconds,
+        })
+    }
+
+    pub fn authenticate(&self, token: &str) -> Result<Principal, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+
+        // the key is picked by the algorithm the token claims, so a token can never get an
+        // RS256 public key used as an HS256 secret
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256_key.as_ref(),
+            Algorithm::RS256 => self.rs256_key(header.kid.as_deref()),
+            alg => return Err(Error::UnsupportedAlgorithm(alg)),
+        }
+        .ok_or(Error::UnknownKey(header.kid))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.leeway_seconds;
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let token = jsonwebtoken::decode::<Claims>(token, key, &validation)?;
+
+        Ok(token.claims.into_principal())
+    }
+
+    // a token without a key id can still be verified when there is only one key it could be
+    fn rs256_key(&self, kid: Option<&str>) -> Option<&DecodingKey> {
+        match kid {
+            Some(kid) => self.rs256_keys.get(kid),
+            None if self.rs256_keys.len() == 1 => self.rs256_keys.values().next(),
+            None => None,
+        }
+    }
+}
+
+// load_jwks reads the RSA keys from a JWKS file, skipping any other kind of key it holds
+fn load_jwks(path: &PathBuf) -> Result<HashMap<String, DecodingKey>, Error> {
+    let contents = std::fs::read_to_string(path).map_err(|e| Error::ReadJwks(path.clone(), e))?;
+    let jwks: JwkSet =
+        serde_json::from_str(&contents).map_err(|e| Error::ParseJwks(path.clone(), e))?;
+
+    let mut keys = HashMap::new();
+
+    for (i, jwk) in jwks.keys.iter().enumerate() {
+        if !matches!(jwk.algorithm, AlgorithmParameters::RSA(_)) {
+            tracing::warn!("Skipping non-RSA key {} in JWKS file", i);
+            continue;
+        }
+
+        // keys without an id are numbered by their position in the file
+        let kid = jwk.common.key_id.clone().unwrap_or_else(|| i.to_string());
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| Error::InvalidJwk(kid.clone(), e))?;
+        keys.insert(kid, key);
+    }
+
+    Ok(keys)
+}
+
+// middleware rejects requests without a valid API key or bearer token with a 401, and makes the
+// principal of those with one available to handlers through the request's extensions. An API
+// key takes precedence when a request carries both.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, ActixError> {
+    let result = match req.app_data::<web::Data<Authenticator>>() {
+        Some(authenticator) => match api_key(&req) {
+            Some(key) => authenticator.api_keys.authenticate(key).await,
+            None => bearer_token(&req)
+                .ok_or(Error::MissingToken)
+                .and_then(|token| authenticator.authenticate(token)),
+        },
+        None => {
+            tracing::error!("No authenticator configured, rejecting request");
+            Err(Error::Unavailable)
+        }
+    };
+
+    match result {
+        Ok(principal) => {
+            tracing::Span::current().record("subject", principal.subject.as_str());
+            req.extensions_mut().insert(principal);
+
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        Err(e) => {
+            tracing::info!("Rejected unauthenticated request: {}", e);
+
+            let response = unauthorized(req.request(), &e);
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+fn api_key(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get(&X_API_KEY)
+        .and_then(|value| value.to_str().ok())
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    // the scheme is case insensitive (RFC 7235 section 2.1)
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    Some(token.trim()).filter(|token| !token.is_empty())
+}
+
+// unauthorized tells the client how to authenticate (RFC 6750 section 3), without saying which
+// check bad credentials failed
+fn unauthorized(req: &HttpRequest, err: &Error) -> HttpResponse {
+    let (detail, challenge) = match err {
+        Error::Unavailable => {
+            return Problem::new(req, &problem::AUTHENTICATION_UNAVAILABLE, None).into_response();
+        }
+        Error::MissingToken => ("a bearer token or api key is required", "Bearer"),
+        Error::InvalidApiKey => ("the api key is invalid, expired or revoked", "Bearer"),
+        Error::InvalidCredentials => ("the email or password is incorrect", "Bearer"),
+        Error::InvalidRefreshToken => {
+            ("the refresh token is invalid, expired or revoked", "Bearer")
+        }
+        _ => (
+            "the bearer token is invalid or has expired",
+            r#"Bearer error="invalid_token""#,
+        ),
+    };
+
+    let mut response =
+        Problem::new(req, &problem::UNAUTHORIZED, Some(detail.to_string())).into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(challenge),
+    );
+
+    response
+}
@@ -0,0 +1,175 @@
+use super::recovery;
+use super::tokens::Issuer;
+use super::{Credentials, Recovery, Sessions, unauthorized};
+use crate::problem::{self, INTERNAL_ERROR, Problem};
+use crate::users::app::core::Sessions as UserSessions;
+
+use std::sync::Arc;
+
+use actix_web::http::header::{self, CacheDirective};
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct ReqLogin {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReqRefreshToken {
+    pub refresh_token: String,
+}
+
+// State is what the endpoints need, without an issuer logins and refreshes are refused
+struct State {
+    credentials: Arc<dyn Credentials>,
+    sessions: Arc<dyn Sessions>,
+    issuer: Option<Issuer>,
+}
+
+#[derive(Clone)]
+pub struct App {
+    scope: String,
+    state: Arc<State>,
+    recovery: Arc<recovery::State>,
+}
+
+impl App {
+    pub fn new(
+        scope: String,
+        credentials: Arc<dyn Credentials>,
+        sessions: Arc<dyn Sessions>,
+        issuer: Option<Issuer>,
+        recovery: Arc<dyn Recovery>,
+        user_sessions: Arc<dyn UserSessions>,
+    ) -> Self {
+        Self {
+            scope,
+            state: Arc::new(State {
+                credentials,
+                sessions,
+                issuer,
+            }),
+            recovery: Arc::new(recovery::State {
+                recovery,
+                user_sessions,
+            }),
+        }
+    }
+
+    // the endpoints are how users get a token in the first place, or get back in when they
+    // cannot log in, so they are not behind the auth middleware
+    pub fn configure(&self) -> impl FnOnce(&mut web::ServiceConfig) + Clone {
+        let scope = self.scope.clone();
+        let state = self.state.clone();
+        let recovery = self.recovery.clone();
+
+        move |cfg: &mut web::ServiceConfig| {
+            cfg.service(
+                web::scope(&scope)
+                    .app_data(web::Data::from(state))
+                    .app_data(web::Data::from(recovery))
+                    .service(login)
+                    .service(refresh)
+                    .service(logout)
+                    .service(recovery::verify_email)
+                    .service(recovery::forgot_password)
+                    .service(recovery::reset_password),
+            );
+        }
+    }
+}
+
+// login starts a new session, so every device a user logs in on can be logged out on its own
+#[post("/login")]
+async fn login(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqLogin>,
+) -> impl Responder {
+    let Some(issuer) = &state.issuer else {
+        return issuer_unavailable(&req);
+    };
+
+    let body = body.into_inner();
+    let subject = match state
+        .credentials
+        .verify_password(&body.email, &body.password)
+        .await
+    {
+        Ok(subject) => subject,
+        Err(e) => {
+            tracing::info!("Rejected login: {}", e);
+            return unauthorized(&req, &e);
+        }
+    };
+
+    tracing::Span::current().record("subject", subject.as_str());
+
+    match state.sessions.start(&subject).await {
+        Ok(refresh_token) => token_response(&req, issuer, &subject, refresh_token),
+        Err(e) => unauthorized(&req, &e),
+    }
+}
+
+#[post("/refresh")]
+async fn refresh(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqRefreshToken>,
+) -> impl Responder {
+    let Some(issuer) = &state.issuer else {
+        return issuer_unavailable(&req);
+    };
+
+    let (subject, refresh_token) = match state.sessions.refresh(&body.refresh_token).await {
+        Ok(refreshed) => refreshed,
+        Err(e) => {
+            tracing::info!("Rejected refresh: {}", e);
+            return unauthorized(&req, &e);
+        }
+    };
+
+    tracing::Span::current().record("subject", subject.as_str());
+
+    token_response(&req, issuer, &subject, refresh_token)
+}
+
+// logout succeeds even for a token that is no longer good for anything, as the client is logged
+// out either way (RFC 7009 section 2.2). Access tokens already issued stay valid until they
+// expire.
+#[post("/logout")]
+async fn logout(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqRefreshToken>,
+) -> impl Responder {
+    match state.sessions.end(&body.refresh_token).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => unauthorized(&req, &e),
+    }
+}
+
+fn token_response(
+    req: &HttpRequest,
+    issuer: &Issuer,
+    subject: &str,
+    refresh_token: String,
+) -> HttpResponse {
+    match issuer.issue(subject, refresh_token) {
+        // responses carrying tokens must not be cached (RFC 6749 section 5.1)
+        Ok(tokens) => HttpResponse::Ok()
+            .insert_header(header::CacheControl(vec![CacheDirective::NoStore]))
+            .json(tokens),
+        Err(e) => {
+            tracing::error!("Failed to issue tokens: {}", e);
+            Problem::new(req, &INTERNAL_ERROR, None).into_response()
+        }
+    }
+}
+
+fn issuer_unavailable(req: &HttpRequest) -> HttpResponse {
+    tracing::error!("Issuing tokens requires auth.hs256_secret to sign them with");
+    Problem::new(req, &problem::AUTHENTICATION_UNAVAILABLE, None).into_response()
+}
@@ -0,0 +1,122 @@
+use super::{Error, Recovery};
+use crate::problem::{self, INTERNAL_ERROR, Problem, ProblemType};
+use crate::users::app::core::Sessions as UserSessions;
+use crate::validation::Validator;
+
+use std::sync::Arc;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse, Responder, post, web};
+use serde::Deserialize;
+use uuid::Uuid;
+
+pub const INVALID_TOKEN: ProblemType = ProblemType {
+    uri: "/problems/invalid-token",
+    title: "Invalid token",
+    status: StatusCode::BAD_REQUEST,
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReqVerifyEmail {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReqForgotPassword {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReqResetPassword {
+    pub token: String,
+    pub new_password: String,
+}
+
+pub(super) struct State {
+    pub(super) recovery: Arc<dyn Recovery>,
+    // a reset password logs the user out everywhere, so whoever knew the old one is logged out
+    // too
+    pub(super) user_sessions: Arc<dyn UserSessions>,
+}
+
+#[post("/verify-email")]
+pub(super) async fn verify_email(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqVerifyEmail>,
+) -> impl Responder {
+    match state.recovery.verify_email(&body.token).await {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(e) => recovery_error(&req, &e),
+    }
+}
+
+// forgot_password is accepted whether or not the email belongs to anyone, so that it cannot be
+// used to find out which accounts exist
+#[post("/forgot-password")]
+pub(super) async fn forgot_password(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqForgotPassword>,
+) -> impl Responder {
+    match state.recovery.request_password_reset(&body.email).await {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(e) => recovery_error(&req, &e),
+    }
+}
+
+#[post("/reset-password")]
+pub(super) async fn reset_password(
+    req: HttpRequest,
+    state: web::Data<State>,
+    body: web::Json<ReqResetPassword>,
+) -> impl Responder {
+    let body = body.into_inner();
+
+    let mut v = Validator::new();
+    let Some(password) = v.password("new_password", body.new_password) else {
+        return problem::validation_failed(&req, v.into_errors());
+    };
+
+    let subject = match state
+        .recovery
+        .reset_password(&body.token, password.as_str())
+        .await
+    {
+        Ok(subject) => subject,
+        Err(e) => return recovery_error(&req, &e),
+    };
+
+    tracing::Span::current().record("subject", subject.as_str());
+
+    // the password has been reset whether or not this works, and the user can log in with it
+    // to revoke their sessions themselves
+    match subject.parse::<Uuid>() {
+        Ok(id) => {
+            if let Err(e) = state.user_sessions.revoke_user_sessions(id).await {
+                tracing::error!("Failed to revoke sessions after password reset: {}", e);
+            }
+        }
+        Err(_) => tracing::error!("Password was reset for non-user subject {}", subject),
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+fn recovery_error(req: &HttpRequest, err: &Error) -> HttpResponse {
+    match err {
+        Error::InvalidOneTimeToken => {
+            Problem::new(req, &INVALID_TOKEN, Some(err.to_string())).into_response()
+        }
+        Error::Unavailable => {
+            Problem::new(req, &problem::AUTHENTICATION_UNAVAILABLE, None).into_response()
+        }
+        _ => {
+            tracing::error!("Unexpected recovery error: {}", err);
+            Problem::new(req, &INTERNAL_ERROR, None).into_response()
+        }
+    }
+}
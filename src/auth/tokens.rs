@@ -0,0 +1,75 @@
+use super::Error;
+use crate::config;
+
+use chrono::Utc;
+use jsonwebtoken::{EncodingKey, Header};
+use serde::Serialize;
+use uuid::Uuid;
+
+// TokenPair is what a client gets on login and refresh (RFC 6749 section 5.1). The refresh token
+// is opaque, it is only good for getting the next pair.
+#[derive(Serialize, Debug)]
+pub struct TokenPair {
+    pub access_token: String,
+    pub token_type: &'static str,
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    sub: &'a str,
+    iat: i64,
+    exp: i64,
+    jti: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<&'a str>,
+}
+
+// Issuer signs the access tokens users get on login with the HS256 secret, carrying the issuer
+// and audience the authenticator expects, so they are accepted like any other token
+pub struct Issuer {
+    key: EncodingKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    access_token_ttl_seconds: u64,
+}
+
+impl Issuer {
+    // new returns None without an HS256 secret, as there is nothing to sign tokens with
+    pub fn new(config: &config::core::Auth) -> Option<Self> {
+        let secret = config.hs256_secret.as_ref()?;
+
+        Some(Self {
+            key: EncodingKey::from_secret(secret.as_bytes()),
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            access_token_ttl_seconds: config.access_token_ttl_seconds,
+        })
+    }
+
+    // issue pairs a new access token for subject with the refresh token of their session
+    pub fn issue(&self, subject: &str, refresh_token: String) -> Result<TokenPair, Error> {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: subject,
+            iat: now,
+            exp: now.saturating_add_unsigned(self.access_token_ttl_seconds),
+            jti: Uuid::new_v4().to_string(),
+            iss: self.issuer.as_deref(),
+            aud: self.audience.as_deref(),
+        };
+
+        let access_token =
+            jsonwebtoken::encode(&Header::default(), &claims, &self.key).map_err(Error::Sign)?;
+
+        Ok(TokenPair {
+            access_token,
+            token_type: "Bearer",
+            expires_in: self.access_token_ttl_seconds,
+            refresh_token,
+        })
+    }
+}
@@ -0,0 +1,1183 @@
+use super::errors::Error;
+
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+// Config is resolved in layers, each overriding the last:
+// built-in defaults < config file (TOML) < environment variables < command line flags
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub app: App,
+    pub users: Users,
+    pub telemetry: Telemetry,
+    pub health: Health,
+    pub auth: Auth,
+    pub api_keys: ApiKeys,
+    pub sessions: Sessions,
+    pub notifications: Notifications,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub host: String,
+    pub port: u16,
+    // how long to keep serving after readiness starts failing on shutdown, giving load balancers
+    // time to stop sending new requests
+    pub shutdown_delay_seconds: u64,
+    // how long in-flight requests get to finish before they are dropped
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            shutdown_delay_seconds: 0,
+            shutdown_timeout_seconds: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct App {
+    pub name: String,
+    pub version: String,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self {
+            name: "My Actix Web App".to_string(),
+            version: "1.0.0".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Users {
+    pub scope: String,
+    pub default_page_size: usize,
+    pub max_page_size: usize,
+    pub purge_retention_days: u32,
+    // how long the one-time tokens mailed to users stay usable
+    pub verify_email_ttl_seconds: u64,
+    pub reset_password_ttl_seconds: u64,
+    pub repo: RepoKind,
+    pub dynamodb: DynamoDb,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Self {
+            scope: "/api/users".to_string(),
+            default_page_size: 25,
+            max_page_size: 100,
+            purge_retention_days: 30,
+            verify_email_ttl_seconds: 24 * 60 * 60,
+            reset_password_ttl_seconds: 60 * 60,
+            repo: RepoKind::DynamoDb,
+            dynamodb: DynamoDb::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RepoKind {
+    #[value(name = "dynamodb")]
+    DynamoDb,
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DynamoDb {
+    pub table_name: String,
+    pub email_lookup_table_name: String,
+    pub credentials_table_name: String,
+    pub tokens_table_name: String,
+}
+
+impl Default for DynamoDb {
+    fn default() -> Self {
+        Self {
+            table_name: "users".to_string(),
+            email_lookup_table_name: "users_email_lookup".to_string(),
+            credentials_table_name: "users_credentials".to_string(),
+            tokens_table_name: "users_tokens".to_string(),
+        }
+    }
+}
+
+impl DynamoDb {
+    fn table_names(&self) -> [&String; 4] {
+        [
+            &self.table_name,
+            &self.email_lookup_table_name,
+            &self.credentials_table_name,
+            &self.tokens_table_name,
+        ]
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeys {
+    pub scope: String,
+    pub repo: RepoKind,
+    pub dynamodb: ApiKeysDynamoDb,
+}
+
+impl Default for ApiKeys {
+    fn default() -> Self {
+        Self {
+            scope: "/api/keys".to_string(),
+            repo: RepoKind::DynamoDb,
+            dynamodb: ApiKeysDynamoDb::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeysDynamoDb {
+    pub table_name: String,
+}
+
+impl Default for ApiKeysDynamoDb {
+    fn default() -> Self {
+        Self {
+            table_name: "api_keys".to_string(),
+        }
+    }
+}
+
+// Sessions are where the refresh tokens issued on login are kept
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sessions {
+    pub repo: RepoKind,
+    pub dynamodb: SessionsDynamoDb,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self {
+            repo: RepoKind::DynamoDb,
+            dynamodb: SessionsDynamoDb::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsDynamoDb {
+    pub table_name: String,
+}
+
+impl Default for SessionsDynamoDb {
+    fn default() -> Self {
+        Self {
+            table_name: "sessions".to_string(),
+        }
+    }
+}
+
+// Notifications are the emails sent to users
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Notifications {
+    pub mailer: MailerKind,
+    // the address every message is sent from
+    pub from: String,
+    // file the log mailer appends messages to, rather than logging their text
+    pub outbox_path: Option<PathBuf>,
+    // messages waiting to be delivered in the background, beyond which new ones are dropped
+    pub queue_capacity: usize,
+    // how long queued messages get to be delivered on shutdown before they are dropped
+    pub drain_timeout_seconds: u64,
+    pub smtp: NotificationsSmtp,
+    pub maildir: NotificationsMaildir,
+}
+
+impl Default for Notifications {
+    fn default() -> Self {
+        Self {
+            mailer: MailerKind::Log,
+            from: "no-reply@example.com".to_string(),
+            outbox_path: None,
+            queue_capacity: 1000,
+            drain_timeout_seconds: 10,
+            smtp: NotificationsSmtp::default(),
+            maildir: NotificationsMaildir::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MailerKind {
+    Log,
+    Smtp,
+    Maildir,
+}
+
+// the defaults point at the SMTP server docker-compose runs locally
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsSmtp {
+    pub host: String,
+    pub port: u16,
+    pub tls: SmtpTls,
+    // credentials are only sent when both are set
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // applies to each SMTP command, rather than to the whole message
+    pub timeout_seconds: u64,
+}
+
+impl Default for NotificationsSmtp {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 1025,
+            tls: SmtpTls::None,
+            username: None,
+            password: None,
+            timeout_seconds: 10,
+        }
+    }
+}
+
+// the password is kept out of Debug output, so the config can be logged safely
+impl std::fmt::Debug for NotificationsSmtp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NotificationsSmtp")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("timeout_seconds", &self.timeout_seconds)
+            .finish()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    // plain text, only fit for a server on the same host or network
+    None,
+    // upgrades the connection with STARTTLS, failing rather than carrying on without it
+    #[value(name = "starttls")]
+    StartTls,
+    // TLS from the start, usually on port 465
+    Tls,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsMaildir {
+    // created along with its tmp, new and cur folders when it does not exist
+    pub path: PathBuf,
+}
+
+impl Default for NotificationsMaildir {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("maildir"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Telemetry {
+    pub exporter: ExporterKind,
+    // OTLP/HTTP traces endpoint, only used by the otlp exporter
+    pub endpoint: String,
+    // fraction of new traces that are sampled, requests with a sampled parent always are
+    pub sampling_ratio: f64,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            exporter: ExporterKind::None,
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            sampling_ratio: 1.0,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExporterKind {
+    None,
+    Stdout,
+    Otlp,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    // how long a readiness result is reused before the dependencies are checked again
+    pub cache_ttl_seconds: u64,
+    pub check_timeout_ms: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            cache_ttl_seconds: 5,
+            check_timeout_ms: 2000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub scope: String,
+    // shared secret HS256 tokens are signed with, and the tokens issued on login are signed with
+    pub hs256_secret: Option<String>,
+    // JWKS file holding the public keys RS256 tokens are verified with
+    pub jwks_path: Option<PathBuf>,
+    // when set, tokens must carry this iss claim
+    pub issuer: Option<String>,
+    // when set, tokens must list this in their aud claim
+    pub audience: Option<String>,
+    // allowance for clock skew when checking exp and nbf
+    pub leeway_seconds: u64,
+    pub access_token_ttl_seconds: u64,
+    pub refresh_token_ttl_seconds: u64,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            scope: "/api/auth".to_string(),
+            hs256_secret: None,
+            jwks_path: None,
+            issuer: None,
+            audience: None,
+            leeway_seconds: 60,
+            access_token_ttl_seconds: 15 * 60,
+            refresh_token_ttl_seconds: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+// the secret is kept out of Debug output, so the config can be logged safely
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Auth")
+            .field("scope", &self.scope)
+            .field(
+                "hs256_secret",
+                &self.hs256_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("jwks_path", &self.jwks_path)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("leeway_seconds", &self.leeway_seconds)
+            .field("access_token_ttl_seconds", &self.access_token_ttl_seconds)
+            .field("refresh_token_ttl_seconds", &self.refresh_token_ttl_seconds)
+            .finish()
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Runs a maintenance command instead of the server
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to a TOML config file
+    #[arg(long, env = "APP_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, env = "APP_SERVER_HOST")]
+    pub server_host: Option<String>,
+
+    #[arg(long, env = "APP_SERVER_PORT")]
+    pub server_port: Option<u16>,
+
+    #[arg(long, env = "APP_SERVER_SHUTDOWN_DELAY_SECONDS")]
+    pub server_shutdown_delay_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_SERVER_SHUTDOWN_TIMEOUT_SECONDS")]
+    pub server_shutdown_timeout_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_NAME")]
+    pub app_name: Option<String>,
+
+    #[arg(long, env = "APP_VERSION")]
+    pub app_version: Option<String>,
+
+    #[arg(long, env = "APP_USERS_SCOPE")]
+    pub users_scope: Option<String>,
+
+    #[arg(long, env = "APP_USERS_DEFAULT_PAGE_SIZE")]
+    pub users_default_page_size: Option<usize>,
+
+    #[arg(long, env = "APP_USERS_MAX_PAGE_SIZE")]
+    pub users_max_page_size: Option<usize>,
+
+    #[arg(long, env = "APP_USERS_PURGE_RETENTION_DAYS")]
+    pub users_purge_retention_days: Option<u32>,
+
+    #[arg(long, env = "APP_USERS_VERIFY_EMAIL_TTL_SECONDS")]
+    pub users_verify_email_ttl_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_USERS_RESET_PASSWORD_TTL_SECONDS")]
+    pub users_reset_password_ttl_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_USERS_REPO")]
+    pub users_repo: Option<RepoKind>,
+
+    #[arg(long, env = "APP_USERS_TABLE_NAME")]
+    pub users_table_name: Option<String>,
+
+    #[arg(long, env = "APP_USERS_EMAIL_LOOKUP_TABLE_NAME")]
+    pub users_email_lookup_table_name: Option<String>,
+
+    #[arg(long, env = "APP_USERS_CREDENTIALS_TABLE_NAME")]
+    pub users_credentials_table_name: Option<String>,
+
+    #[arg(long, env = "APP_USERS_TOKENS_TABLE_NAME")]
+    pub users_tokens_table_name: Option<String>,
+
+    #[arg(long, env = "APP_API_KEYS_SCOPE")]
+    pub api_keys_scope: Option<String>,
+
+    #[arg(long, env = "APP_API_KEYS_REPO")]
+    pub api_keys_repo: Option<RepoKind>,
+
+    #[arg(long, env = "APP_API_KEYS_TABLE_NAME")]
+    pub api_keys_table_name: Option<String>,
+
+    #[arg(long, env = "APP_SESSIONS_REPO")]
+    pub sessions_repo: Option<RepoKind>,
+
+    #[arg(long, env = "APP_SESSIONS_TABLE_NAME")]
+    pub sessions_table_name: Option<String>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_MAILER")]
+    pub notifications_mailer: Option<MailerKind>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_FROM")]
+    pub notifications_from: Option<String>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_OUTBOX_PATH")]
+    pub notifications_outbox_path: Option<PathBuf>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_QUEUE_CAPACITY")]
+    pub notifications_queue_capacity: Option<usize>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_DRAIN_TIMEOUT_SECONDS")]
+    pub notifications_drain_timeout_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_HOST")]
+    pub notifications_smtp_host: Option<String>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_PORT")]
+    pub notifications_smtp_port: Option<u16>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_TLS")]
+    pub notifications_smtp_tls: Option<SmtpTls>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_USERNAME")]
+    pub notifications_smtp_username: Option<String>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_PASSWORD", hide_env_values = true)]
+    pub notifications_smtp_password: Option<String>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_SMTP_TIMEOUT_SECONDS")]
+    pub notifications_smtp_timeout_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_NOTIFICATIONS_MAILDIR_PATH")]
+    pub notifications_maildir_path: Option<PathBuf>,
+
+    #[arg(long, env = "APP_TELEMETRY_EXPORTER")]
+    pub telemetry_exporter: Option<ExporterKind>,
+
+    #[arg(long, env = "APP_TELEMETRY_ENDPOINT")]
+    pub telemetry_endpoint: Option<String>,
+
+    #[arg(long, env = "APP_TELEMETRY_SAMPLING_RATIO")]
+    pub telemetry_sampling_ratio: Option<f64>,
+
+    #[arg(long, env = "APP_HEALTH_CACHE_TTL_SECONDS")]
+    pub health_cache_ttl_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_HEALTH_CHECK_TIMEOUT_MS")]
+    pub health_check_timeout_ms: Option<u64>,
+
+    #[arg(long, env = "APP_AUTH_SCOPE")]
+    pub auth_scope: Option<String>,
+
+    #[arg(long, env = "APP_AUTH_HS256_SECRET", hide_env_values = true)]
+    pub auth_hs256_secret: Option<String>,
+
+    #[arg(long, env = "APP_AUTH_JWKS_PATH")]
+    pub auth_jwks_path: Option<PathBuf>,
+
+    #[arg(long, env = "APP_AUTH_ISSUER")]
+    pub auth_issuer: Option<String>,
+
+    #[arg(long, env = "APP_AUTH_AUDIENCE")]
+    pub auth_audience: Option<String>,
+
+    #[arg(long, env = "APP_AUTH_LEEWAY_SECONDS")]
+    pub auth_leeway_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_AUTH_ACCESS_TOKEN_TTL_SECONDS")]
+    pub auth_access_token_ttl_seconds: Option<u64>,
+
+    #[arg(long, env = "APP_AUTH_REFRESH_TOKEN_TTL_SECONDS")]
+    pub auth_refresh_token_ttl_seconds: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Checks the users table against the email lookup table and prints a JSON report of every
+    /// inconsistency found
+    Repair {
+        /// Fix the inconsistencies that can be fixed safely, rather than only reporting them
+        #[arg(long)]
+        apply: bool,
+    },
+}
+
+impl Config {
+    pub fn load(args: &Args) -> Result<Self, Error> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        // clap has already resolved flags over environment variables
+        config.apply_args(args);
+        config.validate(args.command.as_ref())?;
+
+        Ok(config)
+    }
+
+    fn from_file(path: &PathBuf) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).map_err(|e| Error::Read(path.clone(), e))?;
+
+        toml::from_str(&contents).map_err(|e| Error::Parse(path.clone(), e))
+    }
+
+    fn apply_args(&mut self, args: &Args) {
+        if let Some(host) = &args.server_host {
+            self.server.host = host.clone();
+        }
+
+        if let Some(port) = args.server_port {
+            self.server.port = port;
+        }
+
+        if let Some(delay) = args.server_shutdown_delay_seconds {
+            self.server.shutdown_delay_seconds = delay;
+        }
+
+        if let Some(timeout) = args.server_shutdown_timeout_seconds {
+            self.server.shutdown_timeout_seconds = timeout;
+        }
+
+        if let Some(name) = &args.app_name {
+            self.app.name = name.clone();
+        }
+
+        if let Some(version) = &args.app_version {
+            self.app.version = version.clone();
+        }
+
+        if let Some(scope) = &args.users_scope {
+            self.users.scope = scope.clone();
+        }
+
+        if let Some(page_size) = args.users_default_page_size {
+            self.users.default_page_size = page_size;
+        }
+
+        if let Some(page_size) = args.users_max_page_size {
+            self.users.max_page_size = page_size;
+        }
+
+        if let Some(days) = args.users_purge_retention_days {
+            self.users.purge_retention_days = days;
+        }
+
+        if let Some(ttl) = args.users_verify_email_ttl_seconds {
+            self.users.verify_email_ttl_seconds = ttl;
+        }
+
+        if let Some(ttl) = args.users_reset_password_ttl_seconds {
+            self.users.reset_password_ttl_seconds = ttl;
+        }
+
+        if let Some(repo) = args.users_repo {
+            self.users.repo = repo;
+        }
+
+        if let Some(table_name) = &args.users_table_name {
+            self.users.dynamodb.table_name = table_name.clone();
+        }
+
+        if let Some(table_name) = &args.users_email_lookup_table_name {
+            self.users.dynamodb.email_lookup_table_name = table_name.clone();
+        }
+
+        if let Some(table_name) = &args.users_credentials_table_name {
+            self.users.dynamodb.credentials_table_name = table_name.clone();
+        }
+
+        if let Some(table_name) = &args.users_tokens_table_name {
+            self.users.dynamodb.tokens_table_name = table_name.clone();
+        }
+
+        if let Some(scope) = &args.api_keys_scope {
+            self.api_keys.scope = scope.clone();
+        }
+
+        if let Some(repo) = args.api_keys_repo {
+            self.api_keys.repo = repo;
+        }
+
+        if let Some(table_name) = &args.api_keys_table_name {
+            self.api_keys.dynamodb.table_name = table_name.clone();
+        }
+
+        if let Some(repo) = args.sessions_repo {
+            self.sessions.repo = repo;
+        }
+
+        if let Some(table_name) = &args.sessions_table_name {
+            self.sessions.dynamodb.table_name = table_name.clone();
+        }
+
+        if let Some(mailer) = args.notifications_mailer {
+            self.notifications.mailer = mailer;
+        }
+
+        if let Some(from) = &args.notifications_from {
+            self.notifications.from = from.clone();
+        }
+
+        if let Some(path) = &args.notifications_outbox_path {
+            self.notifications.outbox_path = Some(path.clone());
+        }
+
+        if let Some(capacity) = args.notifications_queue_capacity {
+            self.notifications.queue_capacity = capacity;
+        }
+
+        if let Some(timeout) = args.notifications_drain_timeout_seconds {
+            self.notifications.drain_timeout_seconds = timeout;
+        }
+
+        if let Some(host) = &args.notifications_smtp_host {
+            self.notifications.smtp.host = host.clone();
+        }
+
+        if let Some(port) = args.notifications_smtp_port {
+            self.notifications.smtp.port = port;
+        }
+
+        if let Some(tls) = args.notifications_smtp_tls {
+            self.notifications.smtp.tls = tls;
+        }
+
+        if let Some(username) = &args.notifications_smtp_username {
+            self.notifications.smtp.username = Some(username.clone());
+        }
+
+        if let Some(password) = &args.notifications_smtp_password {
+            self.notifications.smtp.password = Some(password.clone());
+        }
+
+        if let Some(timeout) = args.notifications_smtp_timeout_seconds {
+            self.notifications.smtp.timeout_seconds = timeout;
+        }
+
+        if let Some(path) = &args.notifications_maildir_path {
+            self.notifications.maildir.path = path.clone();
+        }
+
+        if let Some(exporter) = args.telemetry_exporter {
+            self.telemetry.exporter = exporter;
+        }
+
+        if let Some(endpoint) = &args.telemetry_endpoint {
+            self.telemetry.endpoint = endpoint.clone();
+        }
+
+        if let Some(ratio) = args.telemetry_sampling_ratio {
+            self.telemetry.sampling_ratio = ratio;
+        }
+
+        if let Some(ttl) = args.health_cache_ttl_seconds {
+            self.health.cache_ttl_seconds = ttl;
+        }
+
+        if let Some(timeout) = args.health_check_timeout_ms {
+            self.health.check_timeout_ms = timeout;
+        }
+
+        if let Some(scope) = &args.auth_scope {
+            self.auth.scope = scope.clone();
+        }
+
+        if let Some(secret) = &args.auth_hs256_secret {
+            self.auth.hs256_secret = Some(secret.clone());
+        }
+
+        if let Some(path) = &args.auth_jwks_path {
+            self.auth.jwks_path = Some(path.clone());
+        }
+
+        if let Some(issuer) = &args.auth_issuer {
+            self.auth.issuer = Some(issuer.clone());
+        }
+
+        if let Some(audience) = &args.auth_audience {
+            self.auth.audience = Some(audience.clone());
+        }
+
+        if let Some(leeway) = args.auth_leeway_seconds {
+            self.auth.leeway_seconds = leeway;
+        }
+
+        if let Some(ttl) = args.auth_access_token_ttl_seconds {
+            self.auth.access_token_ttl_seconds = ttl;
+        }
+
+        if let Some(ttl) = args.auth_refresh_token_ttl_seconds {
+            self.auth.refresh_token_ttl_seconds = ttl;
+        }
+    }
+
+    // validate checks the config for the command about to run, where no command means serving
+    fn validate(&self, command: Option<&Command>) -> Result<(), Error> {
+        if self.server.host.is_empty() {
+            return Err(Error::Invalid("server.host must be populated".to_string()));
+        }
+
+        if self.app.name.is_empty() {
+            return Err(Error::Invalid("app.name must be populated".to_string()));
+        }
+
+        if !self.users.scope.starts_with('/') || self.users.scope.ends_with('/') {
+            return Err(Error::Invalid(format!(
+                "users.scope must start with '/' and must not end with '/': {}",
+                self.users.scope
+            )));
+        }
+
+        if self.users.default_page_size == 0
+            || self.users.default_page_size > self.users.max_page_size
+        {
+            return Err(Error::Invalid(format!(
+                "users.default_page_size must be between 1 and users.max_page_size ({}): {}",
+                self.users.max_page_size, self.users.default_page_size
+            )));
+        }
+
+        if self.users.repo == RepoKind::DynamoDb {
+            validate_table_name("users.dynamodb.table_name", &self.users.dynamodb.table_name)?;
+            validate_table_name(
+                "users.dynamodb.email_lookup_table_name",
+                &self.users.dynamodb.email_lookup_table_name,
+            )?;
+
+            validate_table_name(
+                "users.dynamodb.credentials_table_name",
+                &self.users.dynamodb.credentials_table_name,
+            )?;
+
+            validate_table_name(
+                "users.dynamodb.tokens_table_name",
+                &self.users.dynamodb.tokens_table_name,
+            )?;
+
+            let tables = self.users.dynamodb.table_names();
+            if tables
+                .iter()
+                .enumerate()
+                .any(|(i, table)| tables[..i].contains(table))
+            {
+                return Err(Error::Invalid(
+                    "users.dynamodb.table_name, users.dynamodb.email_lookup_table_name, users.dynamodb.credentials_table_name and users.dynamodb.tokens_table_name must differ"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.users.verify_email_ttl_seconds == 0 || self.users.reset_password_ttl_seconds == 0 {
+            return Err(Error::Invalid(
+                "users.verify_email_ttl_seconds and users.reset_password_ttl_seconds must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        if !self.api_keys.scope.starts_with('/') || self.api_keys.scope.ends_with('/') {
+            return Err(Error::Invalid(format!(
+                "api_keys.scope must start with '/' and must not end with '/': {}",
+                self.api_keys.scope
+            )));
+        }
+
+        if self.api_keys.scope == self.users.scope {
+            return Err(Error::Invalid(
+                "api_keys.scope and users.scope must differ".to_string(),
+            ));
+        }
+
+        if self.api_keys.repo == RepoKind::DynamoDb {
+            validate_table_name(
+                "api_keys.dynamodb.table_name",
+                &self.api_keys.dynamodb.table_name,
+            )?;
+
+            if self.users.repo == RepoKind::DynamoDb
+                && self
+                    .users
+                    .dynamodb
+                    .table_names()
+                    .contains(&&self.api_keys.dynamodb.table_name)
+            {
+                return Err(Error::Invalid(
+                    "api_keys.dynamodb.table_name must differ from the users tables".to_string(),
+                ));
+            }
+        }
+
+        if self.sessions.repo == RepoKind::DynamoDb {
+            validate_table_name(
+                "sessions.dynamodb.table_name",
+                &self.sessions.dynamodb.table_name,
+            )?;
+
+            let mut tables = vec![];
+            if self.users.repo == RepoKind::DynamoDb {
+                tables.extend(self.users.dynamodb.table_names());
+            }
+            if self.api_keys.repo == RepoKind::DynamoDb {
+                tables.push(&self.api_keys.dynamodb.table_name);
+            }
+
+            if tables.contains(&&self.sessions.dynamodb.table_name) {
+                return Err(Error::Invalid(
+                    "sessions.dynamodb.table_name must differ from the users and api_keys tables"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if !self.notifications.from.contains('@') {
+            return Err(Error::Invalid(format!(
+                "notifications.from must be an email address: {}",
+                self.notifications.from
+            )));
+        }
+
+        if self.notifications.queue_capacity == 0 {
+            return Err(Error::Invalid(
+                "notifications.queue_capacity must be greater than 0".to_string(),
+            ));
+        }
+
+        if self.notifications.mailer == MailerKind::Smtp {
+            let smtp = &self.notifications.smtp;
+
+            if smtp.host.is_empty() {
+                return Err(Error::Invalid(
+                    "notifications.smtp.host must be populated for the smtp mailer".to_string(),
+                ));
+            }
+
+            if smtp.username.is_some() != smtp.password.is_some() {
+                return Err(Error::Invalid(
+                    "notifications.smtp.username and notifications.smtp.password must be set \
+                     together"
+                        .to_string(),
+                ));
+            }
+
+            if smtp.timeout_seconds == 0 {
+                return Err(Error::Invalid(
+                    "notifications.smtp.timeout_seconds must be greater than 0".to_string(),
+                ));
+            }
+        }
+
+        if self.notifications.mailer == MailerKind::Maildir
+            && self.notifications.maildir.path.as_os_str().is_empty()
+        {
+            return Err(Error::Invalid(
+                "notifications.maildir.path must be populated for the maildir mailer".to_string(),
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&self.telemetry.sampling_ratio) {
+            return Err(Error::Invalid(format!(
+                "telemetry.sampling_ratio must be between 0 and 1: {}",
+                self.telemetry.sampling_ratio
+            )));
+        }
+
+        if self.telemetry.exporter == ExporterKind::Otlp && self.telemetry.endpoint.is_empty() {
+            return Err(Error::Invalid(
+                "telemetry.endpoint must be populated for the otlp exporter".to_string(),
+            ));
+        }
+
+        if self.health.check_timeout_ms == 0 {
+            return Err(Error::Invalid(
+                "health.check_timeout_ms must be greater than 0".to_string(),
+            ));
+        }
+
+        // the API is never served unauthenticated, so there must be a key to check tokens with,
+        // but offline commands such as repair never check a token
+        if command.is_none() && self.auth.hs256_secret.is_none() && self.auth.jwks_path.is_none() {
+            return Err(Error::Invalid(
+                "auth.hs256_secret or auth.jwks_path must be populated".to_string(),
+            ));
+        }
+
+        // RFC 7518 requires HS256 keys to be at least as long as the hash
+        if let Some(secret) = &self.auth.hs256_secret
+            && secret.len() < MIN_HS256_SECRET_LENGTH
+        {
+            return Err(Error::Invalid(format!(
+                "auth.hs256_secret must be at least {} bytes",
+                MIN_HS256_SECRET_LENGTH
+            )));
+        }
+
+        if !self.auth.scope.starts_with('/') || self.auth.scope.ends_with('/') {
+            return Err(Error::Invalid(format!(
+                "auth.scope must start with '/' and must not end with '/': {}",
+                self.auth.scope
+            )));
+        }
+
+        if [&self.users.scope, &self.api_keys.scope].contains(&&self.auth.scope) {
+            return Err(Error::Invalid(
+                "auth.scope must differ from users.scope and api_keys.scope".to_string(),
+            ));
+        }
+
+        if self.auth.access_token_ttl_seconds == 0 || self.auth.refresh_token_ttl_seconds == 0 {
+            return Err(Error::Invalid(
+                "auth.access_token_ttl_seconds and auth.refresh_token_ttl_seconds must be greater than 0"
+                    .to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+const MIN_HS256_SECRET_LENGTH: usize = 32;
+
+// DynamoDB table names are 3-255 characters from [a-zA-Z0-9_.-]
+fn validate_table_name(key: &str, name: &str) -> Result<(), Error> {
+    let valid_chars = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+    if name.len() < 3 || name.len() > 255 || !valid_chars {
+        return Err(Error::Invalid(format!(
+            "{} is not a valid DynamoDB table name: {}",
+            key, name
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    // Invalidate breaks a valid config in one way
+    type Invalidate = fn(&mut Config);
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.auth.hs256_secret = Some(SECRET.to_string());
+        config
+    }
+
+    #[test]
+    fn layers_override_each_other_in_order() {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "[server]\nhost = \"0.0.0.0\"\nport = 9000\n\n[app]\nname = \"toml\"\n",
+        )
+        .unwrap();
+
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            std::env::set_var("APP_SERVER_PORT", "9001");
+            std::env::set_var("APP_APP_NAME", "env");
+        }
+        let args = Args::try_parse_from([
+            "app",
+            "--config",
+            path.to_str().unwrap(),
+            "--app-name",
+            "flag",
+            "--auth-hs256-secret",
+            SECRET,
+        ]);
+        unsafe {
+            std::env::remove_var("APP_SERVER_PORT");
+            std::env::remove_var("APP_APP_NAME");
+        }
+        let config = Config::load(&args.unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+
+        // default < TOML
+        assert_eq!(config.app.version, "1.0.0");
+        assert_eq!(config.server.host, "0.0.0.0");
+        // TOML < env
+        assert_eq!(config.server.port, 9001);
+        // env < flags
+        assert_eq!(config.app.name, "flag");
+    }
+
+    #[test]
+    fn reports_unreadable_and_invalid_files() {
+        let missing = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        assert!(matches!(
+            Config::from_file(&missing),
+            Err(Error::Read(path, _)) if path == missing
+        ));
+
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "[server]\nhots = \"0.0.0.0\"\n").unwrap();
+        let result = Config::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(Error::Parse(_, _))));
+    }
+
+    #[test]
+    fn defaults_are_valid_once_there_is_a_key() {
+        assert!(valid().validate(None).is_ok());
+        assert!(Config::default().validate(None).is_err());
+    }
+
+    #[test]
+    fn commands_need_no_key() {
+        let command = Command::Repair { apply: false };
+
+        assert!(Config::default().validate(Some(&command)).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let cases: [(&str, Invalidate); 22] = [
+            ("server.host", |c| c.server.host.clear()),
+            ("app.name", |c| c.app.name.clear()),
+            ("users.scope", |c| c.users.scope = "/api/users/".to_string()),
+            ("users.default_page_size", |c| c.users.default_page_size = 0),
+            ("users.default_page_size", |c| {
+                c.users.default_page_size = c.users.max_page_size + 1
+            }),
+            ("users.dynamodb.table_name", |c| {
+                c.users.dynamodb.table_name = "u!".to_string()
+            }),
+            ("must differ", |c| {
+                c.users.dynamodb.tokens_table_name = c.users.dynamodb.table_name.clone()
+            }),
+            ("users.verify_email_ttl_seconds", |c| {
+                c.users.reset_password_ttl_seconds = 0
+            }),
+            ("api_keys.scope and users.scope", |c| {
+                c.api_keys.scope = c.users.scope.clone()
+            }),
+            ("api_keys.dynamodb.table_name", |c| {
+                c.api_keys.dynamodb.table_name = c.users.dynamodb.table_name.clone()
+            }),
+            ("sessions.dynamodb.table_name", |c| {
+                c.sessions.dynamodb.table_name = c.api_keys.dynamodb.table_name.clone()
+            }),
+            ("notifications.from", |c| {
+                c.notifications.from = "nobody".to_string()
+            }),
+            ("notifications.queue_capacity", |c| {
+                c.notifications.queue_capacity = 0
+            }),
+            ("notifications.smtp.host", |c| {
+                c.notifications.mailer = MailerKind::Smtp;
+                c.notifications.smtp.host.clear();
+            }),
+            ("notifications.smtp.username", |c| {
+                c.notifications.mailer = MailerKind::Smtp;
+                c.notifications.smtp.username = Some("ada".to_string());
+            }),
+            ("notifications.maildir.path", |c| {
+                c.notifications.mailer = MailerKind::Maildir;
+                c.notifications.maildir.path = PathBuf::new();
+            }),
+            ("telemetry.sampling_ratio", |c| {
+                c.telemetry.sampling_ratio = 1.5
+            }),
+            ("telemetry.endpoint", |c| {
+                c.telemetry.exporter = ExporterKind::Otlp;
+                c.telemetry.endpoint.clear();
+            }),
+            ("health.check_timeout_ms", |c| c.health.check_timeout_ms = 0),
+            ("auth.hs256_secret", |c| {
+                c.auth.hs256_secret = Some("short".to_string())
+            }),
+            ("auth.scope must differ", |c| {
+                c.auth.scope = c.users.scope.clone()
+            }),
+            ("auth.access_token_ttl_seconds", |c| {
+                c.auth.refresh_token_ttl_seconds = 0
+            }),
+        ];
+
+        for (expected, invalidate) in cases {
+            let mut config = valid();
+            invalidate(&mut config);
+
+            match config.validate(None) {
+                Err(Error::Invalid(message)) => {
+                    assert!(message.contains(expected), "{}: {}", expected, message)
+                }
+                result => panic!("{}: {:?}", expected, result),
+            }
+        }
+    }
+
+    #[test]
+    fn table_names_are_only_checked_for_dynamodb() {
+        let mut config = valid();
+        config.users.repo = RepoKind::Memory;
+        config.users.dynamodb.table_name = "u!".to_string();
+
+        assert!(config.validate(None).is_ok());
+    }
+}
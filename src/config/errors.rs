@@ -0,0 +1,13 @@
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("failed to parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+
+    #[error("invalid config: {0}")]
+    Invalid(String),
+}
@@ -0,0 +1,3 @@
+pub(crate) mod attrs;
+pub(crate) mod health;
+pub(crate) mod instrument;
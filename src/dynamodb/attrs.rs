@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// Malformed describes an item attribute that is missing or cannot be read. Each repo's errors
+// convert from it, so the helpers below can be used with ? whatever the repo.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub(crate) struct Malformed(pub String);
+
+type Attrs = HashMap<String, AttributeValue>;
+
+pub(crate) fn get_string(attrs: &Attrs, key: &str) -> Result<String, Malformed> {
+    match attrs.get(key) {
+        Some(AttributeValue::S(val)) => Ok(val.clone()),
+        Some(_) => Err(incorrect_type(key)),
+        None => Err(Malformed(format!("{} missing", key))),
+    }
+}
+
+// get_optional_string reads a missing attribute as empty
+pub(crate) fn get_optional_string(attrs: &Attrs, key: &str) -> Result<String, Malformed> {
+    match attrs.get(key) {
+        Some(AttributeValue::S(val)) => Ok(val.clone()),
+        Some(_) => Err(incorrect_type(key)),
+        None => Ok("".to_string()),
+    }
+}
+
+pub(crate) fn get_uuid(attrs: &Attrs, key: &str) -> Result<Uuid, Malformed> {
+    get_string(attrs, key)
+        .and_then(|val| Uuid::parse_str(&val).map_err(|_| Malformed(format!("invalid {}", key))))
+}
+
+pub(crate) fn get_datetime(attrs: &Attrs, key: &str) -> Result<DateTime<Utc>, Malformed> {
+    get_string(attrs, key).and_then(|val| {
+        DateTime::parse_from_rfc3339(&val)
+            .map(|dt| dt.with_timezone(&Utc))
+            .map_err(|_| Malformed(format!("invalid {} format", key)))
+    })
+}
+
+pub(crate) fn get_optional_datetime(
+    attrs: &Attrs,
+    key: &str,
+) -> Result<Option<DateTime<Utc>>, Malformed> {
+    if !attrs.contains_key(key) {
+        return Ok(None);
+    }
+
+    get_datetime(attrs, key).map(Some)
+}
+
+pub(crate) fn get_number(attrs: &Attrs, key: &str) -> Result<u64, Malformed> {
+    match attrs.get(key) {
+        Some(AttributeValue::N(val)) => val
+            .parse()
+            .map_err(|_| Malformed(format!("invalid {} format", key))),
+        Some(_) => Err(incorrect_type(key)),
+        None => Err(Malformed(format!("{} missing", key))),
+    }
+}
+
+pub(crate) fn get_optional_number(attrs: &Attrs, key: &str) -> Result<Option<u64>, Malformed> {
+    if !attrs.contains_key(key) {
+        return Ok(None);
+    }
+
+    get_number(attrs, key).map(Some)
+}
+
+pub(crate) fn get_optional_bool(attrs: &Attrs, key: &str) -> Result<Option<bool>, Malformed> {
+    match attrs.get(key) {
+        Some(AttributeValue::Bool(val)) => Ok(Some(*val)),
+        Some(_) => Err(incorrect_type(key)),
+        None => Ok(None),
+    }
+}
+
+fn incorrect_type(key: &str) -> Malformed {
+    Malformed(format!("incorrect type for {}", key))
+}
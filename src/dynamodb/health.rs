@@ -0,0 +1,45 @@
+use super::instrument;
+use crate::health::Check;
+
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::error::DisplayErrorContext;
+use aws_sdk_dynamodb::types::TableStatus;
+
+// TableCheck passes when DynamoDB can be reached and the table exists and is usable
+pub(crate) struct TableCheck {
+    client: Client,
+    table_name: String,
+}
+
+impl TableCheck {
+    pub(crate) fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+}
+
+#[async_trait::async_trait]
+impl Check for TableCheck {
+    fn name(&self) -> String {
+        format!("dynamodb:{}", self.table_name)
+    }
+
+    async fn check(&self) -> Result<(), String> {
+        let request = self.client.describe_table().table_name(&self.table_name);
+
+        let output = instrument::call("DescribeTable", &[&self.table_name], request.send())
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(svc_err) if svc_err.is_resource_not_found_exception() => {
+                    "table does not exist".to_string()
+                }
+                _ => format!("describe table failed: {}", DisplayErrorContext(&e)),
+            })?;
+
+        // an updating table still serves reads and writes
+        match output.table().and_then(|table| table.table_status()) {
+            Some(TableStatus::Active | TableStatus::Updating) => Ok(()),
+            Some(status) => Err(format!("table is {}", status.as_str())),
+            None => Err("table status missing from response".to_string()),
+        }
+    }
+}
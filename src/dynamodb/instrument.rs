@@ -0,0 +1,113 @@
+use std::future::Future;
+
+use aws_sdk_dynamodb::operation::{
+    delete_item::DeleteItemOutput, describe_table::DescribeTableOutput, get_item::GetItemOutput,
+    put_item::PutItemOutput, query::QueryOutput, scan::ScanOutput,
+    transact_write_items::TransactWriteItemsOutput, update_item::UpdateItemOutput,
+};
+use tracing::Instrument;
+use tracing::field::Empty;
+
+// ConsumedCapacity is implemented by the outputs of the operations the repo calls, so the
+// capacity each call consumed can be recorded on its span. Requests must ask for it with
+// ReturnConsumedCapacity::Total, otherwise there is nothing to record.
+pub(crate) trait ConsumedCapacity {
+    fn capacity_units(&self) -> Option<f64>;
+}
+
+impl ConsumedCapacity for GetItemOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacity for PutItemOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacity for DeleteItemOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacity for UpdateItemOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacity for ScanOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+impl ConsumedCapacity for QueryOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .and_then(|capacity| capacity.capacity_units())
+    }
+}
+
+// describing a table is a control plane call, which consumes no capacity
+impl ConsumedCapacity for DescribeTableOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        None
+    }
+}
+
+// transactions report the capacity consumed on each table separately
+impl ConsumedCapacity for TransactWriteItemsOutput {
+    fn capacity_units(&self) -> Option<f64> {
+        self.consumed_capacity()
+            .iter()
+            .filter_map(|capacity| capacity.capacity_units())
+            .reduce(|total, units| total + units)
+    }
+}
+
+// call runs a single DynamoDB API call inside a client span named after the operation
+pub(crate) async fn call<O, E>(
+    operation: &'static str,
+    table_names: &[&str],
+    request: impl Future<Output = Result<O, E>>,
+) -> Result<O, E>
+where
+    O: ConsumedCapacity,
+{
+    let span = tracing::info_span!(
+        "dynamodb.call",
+        otel.name = %format!("DynamoDB.{}", operation),
+        otel.kind = "client",
+        otel.status_code = Empty,
+        rpc.system = "aws-api",
+        rpc.service = "DynamoDB",
+        rpc.method = operation,
+        db.system = "dynamodb",
+        aws.dynamodb.table_names = %table_names.join(","),
+        aws.dynamodb.consumed_capacity = Empty,
+    );
+
+    let result = request.instrument(span.clone()).await;
+
+    match &result {
+        Ok(output) => {
+            if let Some(units) = output.capacity_units() {
+                span.record("aws.dynamodb.consumed_capacity", units);
+            }
+        }
+        Err(_) => {
+            span.record("otel.status_code", "ERROR");
+        }
+    }
+
+    result
+}
@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpResponse, Responder, get, web};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+// Check is a dependency the service cannot serve traffic without
+#[async_trait::async_trait]
+pub trait Check: Send + Sync + 'static {
+    fn name(&self) -> String;
+
+    async fn check(&self) -> Result<(), String>;
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Fail,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckResult {
+    pub name: String,
+    pub status: Status,
+    pub latency_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub status: Status,
+    pub checked_at: DateTime<Utc>,
+    pub checks: Vec<CheckResult>,
+}
+
+// Readiness runs the checks at most once per ttl, so frequent probes from several replicas of
+// the orchestrator do not turn into a steady stream of calls to the dependencies
+pub struct Readiness {
+    checks: Vec<Arc<dyn Check>>,
+    ttl: Duration,
+    timeout: Duration,
+    cache: Mutex<Option<(Instant, Report)>>,
+    shutting_down: AtomicBool,
+}
+
+impl Readiness {
+    pub fn new(checks: Vec<Arc<dyn Check>>, ttl: Duration, timeout: Duration) -> Self {
+        Self {
+            checks,
+            ttl,
+            timeout,
+            cache: Mutex::new(None),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    // start_shutdown fails every readiness check from now on, so no new traffic is routed here
+    // while in-flight requests drain
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn report(&self) -> Report {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Report {
+                status: Status::Fail,
+                checked_at: Utc::now(),
+                checks: vec![CheckResult {
+                    name: "shutdown".to_string(),
+                    status: Status::Fail,
+                    latency_ms: 0,
+                    error: Some("server is shutting down".to_string()),
+                }],
+            };
+        }
+
+        // holding the lock while checking means concurrent probes wait for one set of results
+        // rather than each running the checks themselves
+        let mut cache = self.cache.lock().await;
+
+        if let Some((checked, report)) = cache.as_ref()
+            && checked.elapsed() < self.ttl
+        {
+            return report.clone();
+        }
+
+        let report = self.run_checks().await;
+        *cache = Some((Instant::now(), report.clone()));
+
+        report
+    }
+
+    async fn run_checks(&self) -> Report {
+        let mut tasks = JoinSet::new();
+
+        for (i, check) in self.checks.iter().enumerate() {
+            let check = check.clone();
+            let timeout = self.timeout;
+
+            tasks.spawn(
+                async move {
+                    let start = Instant::now();
+                    let result = match tokio::time::timeout(timeout, check.check()).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+                    };
+
+                    if let Err(e) = &result {
+                        tracing::warn!("Readiness check {} failed: {}", check.name(), e);
+                    }
+
+                    let check_result = CheckResult {
+                        name: check.name(),
+                        status: if result.is_ok() {
+                            Status::Ok
+                        } else {
+                            Status::Fail
+                        },
+                        latency_ms: start.elapsed().as_millis(),
+                        error: result.err(),
+                    };
+
+                    (i, check_result)
+                }
+                .in_current_span(),
+            );
+        }
+
+        let mut results = tasks.join_all().await;
+        // report the checks in the order they were configured
+        results.sort_by_key(|(i, _)| *i);
+        let checks: Vec<CheckResult> = results.into_iter().map(|(_, result)| result).collect();
+
+        let status = if checks.iter().all(|check| check.status == Status::Ok) {
+            Status::Ok
+        } else {
+            Status::Fail
+        };
+
+        Report {
+            status,
+            checked_at: Utc::now(),
+            checks,
+        }
+    }
+}
+
+// Info identifies the running service in liveness responses
+#[derive(Serialize, Clone)]
+pub struct Info {
+    pub name: String,
+    pub version: String,
+}
+
+// liveness only says the process is up and serving requests, it never looks at dependencies so
+// an outage elsewhere does not get every replica restarted
+#[get("/health/live")]
+async fn live(info: web::Data<Info>) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "status": Status::Ok,
+        "name": info.name,
+        "version": info.version,
+    }))
+}
+
+// readiness says whether this replica should be sent traffic
+#[get("/health/ready")]
+async fn ready(readiness: web::Data<Readiness>) -> impl Responder {
+    let report = readiness.report().await;
+
+    match report.status {
+        Status::Ok => HttpResponse::Ok().json(report),
+        Status::Fail => HttpResponse::ServiceUnavailable().json(report),
+    }
+}
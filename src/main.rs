@@ -1,57 +1,407 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::middleware::NormalizePath;
-use actix_web::web::{self, JsonConfig};
-use actix_web::{App, HttpResponse, HttpServer, Responder, get};
+use actix_web::middleware::{NormalizePath, from_fn};
+use actix_web::web::{self, JsonConfig, PathConfig, QueryConfig};
+use actix_web::{App, HttpServer};
+use clap::Parser;
+use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
+mod api_keys;
+mod auth;
+mod config;
+mod dynamodb;
+mod health;
+mod metrics;
+mod notifications;
+mod problem;
+mod request_id;
+mod sessions;
+mod shutdown;
+mod telemetry;
+mod tokens;
 mod users;
-
-// This struct represents shared application-wide state.
-struct AppState {
-    app_name: String,
-    version: String,
-}
-
-#[get("/health")]
-async fn health_check(state: web::Data<AppState>) -> impl Responder {
-    let response_str = format!("App Name: {}, Version: {}", state.app_name, state.version);
-    HttpResponse::Ok().body(response_str)
-}
+mod validation;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    tracing_subscriber::fmt()
-        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .json()
+    let args = config::core::Args::parse();
+
+    // commands print their output to stdout, so their logs must not end up there too
+    let log_writer = match args.command {
+        Some(_) => BoxMakeWriter::new(std::io::stderr),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+
+    // the config is needed to set up tracing, so errors loading it are logged once tracing is up
+    let config = config::core::Config::load(&args);
+
+    let tracer_provider = match &config {
+        // the stdout exporter prints spans with println!, where they would end up mixed into a
+        // command's output, so commands only export spans to a collector
+        Ok(config)
+            if args.command.is_some()
+                && config.telemetry.exporter == config::core::ExporterKind::Stdout =>
+        {
+            None
+        }
+        Ok(config) => {
+            telemetry::tracer_provider(&config.telemetry, &config.app.name, &config.app.version)
+                .map_err(std::io::Error::other)?
+        }
+        Err(_) => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+                .with_writer(log_writer),
+        )
+        .with(tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer().with_tracer(telemetry::tracer(provider))
+        }))
         .init();
 
     tracing::info!("booting application");
 
-    // initialise the user repo
-    let config = aws_config::load_from_env().await;
-    let client = aws_sdk_dynamodb::Client::new(&config);
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("failed to load config: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    let result = match args.command {
+        Some(config::core::Command::Repair { apply }) => repair(&config, apply).await,
+        None => serve(config).await,
+    };
+
+    // flush any spans still waiting to be exported, metrics are scraped so need no flushing
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::error!("failed to shut down tracer provider: {}", e);
+    }
+
+    tracing::info!("shutdown complete");
+
+    result
+}
+
+async fn serve(config: config::core::Config) -> std::io::Result<()> {
+    // the repos backed by DynamoDB share a client
+    let dynamodb = tokio::sync::OnceCell::new();
+
+    let mailer = match build_mailer(&config.notifications) {
+        Ok(mailer) => mailer,
+        Err(e) => {
+            tracing::error!("failed to set up mailer: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    // messages are delivered in the background, so requests never wait on the mail server
+    let (mailer, mail_worker) =
+        notifications::queue::Mailer::new(mailer, config.notifications.queue_capacity);
+    let mailer: Arc<dyn notifications::Mailer> = Arc::new(mailer);
+
+    let verify_email_ttl = chrono::TimeDelta::seconds(
+        config
+            .users
+            .verify_email_ttl_seconds
+            .try_into()
+            .unwrap_or(i64::MAX),
+    );
+    let reset_password_ttl = chrono::TimeDelta::seconds(
+        config
+            .users
+            .reset_password_ttl_seconds
+            .try_into()
+            .unwrap_or(i64::MAX),
+    );
+
+    // the users service, as each of the traits it implements
+    type UsersServices = (
+        Arc<dyn users::app::core::Service>,
+        Arc<dyn auth::Credentials>,
+        Arc<dyn auth::Recovery>,
+    );
+
+    // initialise the user repo, along with the checks for the dependencies it needs
+    let ((users_service, credentials, recovery), mut checks): (UsersServices, _) =
+        match config.users.repo {
+            config::core::RepoKind::Memory => {
+                tracing::info!("using in-memory user repo");
+
+                let repo = users::repo::memory::Repo::new();
+                let service = Arc::new(users::service::core::Service::new(
+                    users::repo::metered::Repo::new("memory", repo),
+                    mailer,
+                    verify_email_ttl,
+                    reset_password_ttl,
+                ));
+                ((service.clone(), service.clone(), service), vec![])
+            }
+            config::core::RepoKind::DynamoDb => {
+                let repo = users::repo::dynamodb::Repo::new(
+                    dynamodb_client(&dynamodb).await,
+                    config.users.dynamodb.table_name.clone(),
+                    config.users.dynamodb.email_lookup_table_name.clone(),
+                    config.users.dynamodb.credentials_table_name.clone(),
+                    config.users.dynamodb.tokens_table_name.clone(),
+                );
+                let checks = repo.health_checks();
+                let service = Arc::new(users::service::core::Service::new(
+                    users::repo::metered::Repo::new("dynamodb", repo),
+                    mailer,
+                    verify_email_ttl,
+                    reset_password_ttl,
+                ));
+                ((service.clone(), service.clone(), service), checks)
+            }
+        };
+
+    let (api_keys_service, api_keys_auth): (
+        Arc<dyn api_keys::app::core::Service>,
+        Arc<dyn auth::ApiKeys>,
+    ) = match config.api_keys.repo {
+        config::core::RepoKind::Memory => {
+            tracing::info!("using in-memory api key repo");
+
+            let repo = api_keys::repo::memory::Repo::new();
+            let service = Arc::new(api_keys::service::core::Service::new(repo));
+            (service.clone(), service)
+        }
+        config::core::RepoKind::DynamoDb => {
+            let repo = api_keys::repo::dynamodb::Repo::new(
+                dynamodb_client(&dynamodb).await,
+                config.api_keys.dynamodb.table_name.clone(),
+            );
+            checks.extend(repo.health_checks());
+            let service = Arc::new(api_keys::service::core::Service::new(repo));
+            (service.clone(), service)
+        }
+    };
+
+    let sessions_ttl = chrono::TimeDelta::seconds(
+        config
+            .auth
+            .refresh_token_ttl_seconds
+            .try_into()
+            .unwrap_or(i64::MAX),
+    );
+    let (user_sessions, auth_sessions): (
+        Arc<dyn users::app::core::Sessions>,
+        Arc<dyn auth::Sessions>,
+    ) = match config.sessions.repo {
+        config::core::RepoKind::Memory => {
+            tracing::info!("using in-memory session repo");
+
+            let repo = sessions::repo::memory::Repo::new();
+            let service = Arc::new(sessions::service::core::Service::new(repo, sessions_ttl));
+            (service.clone(), service)
+        }
+        config::core::RepoKind::DynamoDb => {
+            let repo = sessions::repo::dynamodb::Repo::new(
+                dynamodb_client(&dynamodb).await,
+                config.sessions.dynamodb.table_name.clone(),
+            );
+            checks.extend(repo.health_checks());
+            let service = Arc::new(sessions::service::core::Service::new(repo, sessions_ttl));
+            (service.clone(), service)
+        }
+    };
 
-    // TODO: this should be handled by config
-    let table_name = "users".to_string();
-    let email_lookup_table_name = "users_email_lookup".to_string();
+    // shared between workers, so that they share the cached readiness result too
+    let readiness = web::Data::new(health::Readiness::new(
+        checks,
+        Duration::from_secs(config.health.cache_ttl_seconds),
+        Duration::from_millis(config.health.check_timeout_ms),
+    ));
+    let info = web::Data::new(health::Info {
+        name: config.app.name.clone(),
+        version: config.app.version.clone(),
+    });
 
-    let repo = users::repo::dynamodb::Repo::new(client, table_name, email_lookup_table_name);
-    let users_service = users::service::core::Service::new(repo);
-    let users_app = users::app::core::App::new("/api/users".to_string(), Arc::new(users_service));
+    let authenticator = match auth::Authenticator::new(&config.auth, api_keys_auth) {
+        Ok(authenticator) => web::Data::new(authenticator),
+        Err(e) => {
+            tracing::error!("failed to load auth keys: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
 
-    HttpServer::new(move || {
+    let users_app = users::app::core::App::new(
+        config.users.scope.clone(),
+        users::app::state::State::new(
+            config.users.default_page_size,
+            config.users.max_page_size,
+            chrono::TimeDelta::days(config.users.purge_retention_days.into()),
+        ),
+        users_service,
+        user_sessions.clone(),
+    );
+
+    let api_keys_app =
+        api_keys::app::core::App::new(config.api_keys.scope.clone(), api_keys_service);
+
+    let issuer = auth::tokens::Issuer::new(&config.auth);
+    if issuer.is_none() {
+        tracing::warn!("auth.hs256_secret is not set, logins will be refused");
+    }
+    let login_app = auth::login::App::new(
+        config.auth.scope.clone(),
+        credentials,
+        auth_sessions,
+        issuer,
+        recovery,
+        user_sessions,
+    );
+
+    // actix's own signal handling is replaced, so readiness can fail before requests drain
+    let shutdown_readiness = readiness.clone();
+    let shutdown_timeout = Duration::from_secs(config.server.shutdown_timeout_seconds);
+
+    let server = HttpServer::new(move || {
         App::new()
+            // the last middleware wrapped runs first, so paths are normalised before they are
+            // matched against routes for spans and metrics
+            .wrap(from_fn(metrics::middleware))
+            .wrap(from_fn(request_id::middleware))
             .wrap(NormalizePath::trim())
-            .app_data(web::Data::new(AppState {
-                app_name: "My Actix Web App".to_string(),
-                version: "1.0.0".to_string(),
-            }))
-            .app_data(JsonConfig::default().error_handler(users::app::errors::json_error_handler))
+            .app_data(info.clone())
+            .app_data(readiness.clone())
+            .app_data(authenticator.clone())
+            .app_data(JsonConfig::default().error_handler(problem::json_error_handler))
+            .app_data(PathConfig::default().error_handler(problem::path_error_handler))
+            .app_data(QueryConfig::default().error_handler(problem::query_error_handler))
             .configure(users_app.configure())
-            .service(health_check)
+            .configure(api_keys_app.configure())
+            .configure(login_app.configure())
+            .service(health::live)
+            .service(health::ready)
+            .service(metrics::metrics)
+            .default_service(web::to(problem::not_found))
+    })
+    .shutdown_timeout(config.server.shutdown_timeout_seconds)
+    .disable_signals()
+    .bind((config.server.host.as_str(), config.server.port))?
+    .run();
+
+    let mut shutdown = tokio::spawn(shutdown::on_signal(
+        server.handle(),
+        shutdown_readiness,
+        Duration::from_secs(config.server.shutdown_delay_seconds),
+    ));
+
+    let result = server.await;
+
+    // the server stopping wakes the shutdown task too, unless it stopped without being signalled,
+    // in which case the task is still waiting for a signal and there is nothing to summarise
+    match tokio::time::timeout(Duration::from_secs(1), &mut shutdown).await {
+        Ok(Ok(shutdown)) => shutdown.log_summary(shutdown_timeout),
+        Ok(Err(e)) => tracing::error!("shutdown task failed: {}", e),
+        Err(_) => shutdown.abort(),
+    }
+
+    // requests have finished by now, so nothing more will be queued
+    mail_worker
+        .shutdown(Duration::from_secs(
+            config.notifications.drain_timeout_seconds,
+        ))
+        .await;
+
+    result
+}
+
+fn build_mailer(
+    config: &config::core::Notifications,
+) -> Result<Arc<dyn notifications::Mailer>, notifications::Error> {
+    match config.mailer {
+        config::core::MailerKind::Log => {
+            tracing::info!("using log mailer, no mail will be delivered");
+
+            Ok(Arc::new(notifications::log::Mailer::new(
+                config.from.clone(),
+                config.outbox_path.clone(),
+            )))
+        }
+        config::core::MailerKind::Smtp => {
+            tracing::info!(
+                host = config.smtp.host.as_str(),
+                port = config.smtp.port,
+                "using smtp mailer"
+            );
+
+            let from = notifications::parse_from(&config.from)?;
+            Ok(Arc::new(notifications::smtp::Mailer::new(
+                from,
+                &config.smtp,
+            )?))
+        }
+        config::core::MailerKind::Maildir => {
+            tracing::info!(
+                path = %config.maildir.path.display(),
+                "using maildir mailer, no mail will be delivered"
+            );
+
+            let from = notifications::parse_from(&config.from)?;
+            Ok(Arc::new(notifications::maildir::Mailer::new(
+                from,
+                config.maildir.path.clone(),
+            )?))
+        }
+    }
+}
+
+async fn dynamodb_client(
+    cell: &tokio::sync::OnceCell<aws_sdk_dynamodb::Client>,
+) -> aws_sdk_dynamodb::Client {
+    cell.get_or_init(|| async {
+        let aws_config = aws_config::load_from_env().await;
+        aws_sdk_dynamodb::Client::new(&aws_config)
     })
-    .bind(("127.0.0.1", 8080))?
-    .run()
     .await
+    .clone()
+}
+
+// repair checks the users tables for drift, printing the report to stdout
+async fn repair(config: &config::core::Config, apply: bool) -> std::io::Result<()> {
+    if config.users.repo != config::core::RepoKind::DynamoDb {
+        return Err(std::io::Error::other(
+            "repair requires the dynamodb users repo",
+        ));
+    }
+
+    let aws_config = aws_config::load_from_env().await;
+    let client = aws_sdk_dynamodb::Client::new(&aws_config);
+
+    let repo = users::repo::dynamodb::Repo::new(
+        client,
+        config.users.dynamodb.table_name.clone(),
+        config.users.dynamodb.email_lookup_table_name.clone(),
+        config.users.dynamodb.credentials_table_name.clone(),
+        config.users.dynamodb.tokens_table_name.clone(),
+    );
+
+    let report = match repo.repair(apply).await {
+        Ok(report) => report,
+        Err(e) => {
+            tracing::error!("repair failed: {}", e);
+            return Err(std::io::Error::other(e));
+        }
+    };
+
+    let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::other)?;
+    println!("{}", json);
+
+    Ok(())
 }
@@ -0,0 +1,155 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpResponse, Responder, get};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+// every metric is registered here rather than in the prometheus crate's default registry, so
+// nothing a dependency registers ends up exposed by accident
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| {
+    let registry = Registry::new();
+
+    #[cfg(target_os = "linux")]
+    if let Err(e) = registry.register(Box::new(
+        prometheus::process_collector::ProcessCollector::for_self(),
+    )) {
+        tracing::error!("Failed to register process metrics: {}", e);
+    }
+
+    registry
+});
+
+pub static HTTP_REQUESTS_IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new(
+        "http_requests_in_flight",
+        "HTTP requests currently being handled",
+    ))
+});
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("http_requests_total", "HTTP requests handled"),
+        &["method", "route", "status"],
+    ))
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "http_request_duration_seconds",
+            "Time taken to handle HTTP requests",
+        ),
+        &["method", "route", "status"],
+    ))
+});
+
+pub static REPO_OPERATION_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "repo_operation_duration_seconds",
+            "Time taken by user repo operations",
+        ),
+        &["repo", "operation"],
+    ))
+});
+
+pub static REPO_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("repo_errors_total", "User repo operations that failed"),
+        &["repo", "operation", "error"],
+    ))
+});
+
+pub static DYNAMODB_TRANSACTION_CANCELLATIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "dynamodb_transaction_cancellations_total",
+            "Reasons DynamoDB gave for cancelling transactions, by transaction item",
+        ),
+        &["item", "reason"],
+    ))
+});
+
+pub static MAIL_MESSAGES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "mail_messages_total",
+            "Messages handed to the mail queue, by whether they were sent, failed or dropped",
+        ),
+        &["outcome"],
+    ))
+});
+
+// the metric definitions above are fixed, so failing to create or register one is a bug
+fn register<M>(metric: Result<M, prometheus::Error>) -> M
+where
+    M: prometheus::core::Collector + Clone + 'static,
+{
+    let metric = metric.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+// middleware counts and times every request by route pattern, so requests for different users
+// share a series
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let _in_flight = InFlight::start();
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.call(req).await?;
+
+    let status = res.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    Ok(res)
+}
+
+// InFlight counts a request as in flight until it is dropped, which also covers requests
+// abandoned part way through, such as those still running when shutdown times out
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        HTTP_REQUESTS_IN_FLIGHT.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        HTTP_REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+#[get("/metrics")]
+async fn metrics() -> impl Responder {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
@@ -0,0 +1,85 @@
+pub(crate) mod log;
+pub(crate) mod maildir;
+pub(crate) mod queue;
+pub(crate) mod smtp;
+pub(crate) mod templates;
+
+use std::path::PathBuf;
+
+use lettre::message::{Mailbox, MultiPart, SinglePart};
+use serde::Serialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to encode message: {0}")]
+    Encode(serde_json::Error),
+
+    #[error("failed to write message to {0}: {1}")]
+    Write(PathBuf, std::io::Error),
+
+    #[error("invalid address {0}: {1}")]
+    Address(String, lettre::address::AddressError),
+
+    #[error("failed to build message: {0}")]
+    Build(lettre::error::Error),
+
+    #[error("failed to render template {0}: {1}")]
+    Render(&'static str, minijinja::Error),
+
+    #[error("smtp error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+
+    // the queue is full or no longer running, so the message was dropped
+    #[error("message queue is unavailable")]
+    QueueUnavailable,
+}
+
+// Message is an email ready to go, addressed to a single recipient. The sender is up to the
+// mailer, so every message goes out from the same address.
+#[derive(Serialize, Debug, Clone)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    // an HTML alternative to text, for clients that display it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+}
+
+impl Message {
+    // to_email builds the RFC 5322 message the SMTP and maildir mailers deliver
+    fn to_email(&self, from: &Mailbox) -> Result<lettre::Message, Error> {
+        let to = self
+            .to
+            .parse::<Mailbox>()
+            .map_err(|e| Error::Address(self.to.clone(), e))?;
+
+        let builder = lettre::Message::builder()
+            .from(from.clone())
+            .to(to)
+            .subject(self.subject.clone());
+
+        let email = match &self.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(
+                self.text.clone(),
+                html.clone(),
+            )),
+            None => builder.singlepart(SinglePart::plain(self.text.clone())),
+        };
+
+        email.map_err(Error::Build)
+    }
+}
+
+// Mailer delivers messages. Delivery failures are reported, but whether they matter is up to
+// the caller, as a message that was never sent can usually be asked for again.
+#[async_trait::async_trait]
+pub trait Mailer: Send + Sync + 'static {
+    async fn send(&self, message: Message) -> Result<(), Error>;
+}
+
+// parse_from checks the configured sender once, rather than on every message
+pub fn parse_from(from: &str) -> Result<Mailbox, Error> {
+    from.parse::<Mailbox>()
+        .map_err(|e| Error::Address(from.to_string(), e))
+}
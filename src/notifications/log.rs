@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+
+use super::{Error, Message};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+// Entry is a message as it is written to the outbox, one JSON object per line
+#[derive(Serialize)]
+struct Entry<'a> {
+    from: &'a str,
+    sent_at: DateTime<Utc>,
+    #[serde(flatten)]
+    message: &'a Message,
+}
+
+// Mailer never sends anything. Messages are logged, and appended to the outbox file when there
+// is one, so that local development and tests can read the tokens they carry without an SMTP
+// server. Their text ends up in the log only without an outbox, as it holds secrets.
+pub struct Mailer {
+    from: String,
+    outbox: Option<PathBuf>,
+    // appends from concurrent sends must not interleave
+    lock: Mutex<()>,
+}
+
+impl Mailer {
+    pub fn new(from: String, outbox: Option<PathBuf>) -> Self {
+        Self {
+            from,
+            outbox,
+            lock: Mutex::new(()),
+        }
+    }
+
+    async fn append(&self, path: &PathBuf, message: &Message) -> Result<(), Error> {
+        let entry = Entry {
+            from: &self.from,
+            sent_at: Utc::now(),
+            message,
+        };
+
+        let mut line = serde_json::to_vec(&entry).map_err(Error::Encode)?;
+        line.push(b'\n');
+
+        let _guard = self.lock.lock().await;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .map_err(|e| Error::Write(path.clone(), e))?;
+
+        file.write_all(&line)
+            .await
+            .map_err(|e| Error::Write(path.clone(), e))
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Mailer for Mailer {
+    #[tracing::instrument(name = "log_mailer.send", skip_all)]
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        match &self.outbox {
+            Some(path) => {
+                self.append(path, &message).await?;
+                tracing::info!(
+                    "Wrote message \"{}\" for {} to outbox {}",
+                    message.subject,
+                    message.to,
+                    path.display()
+                );
+            }
+            None => tracing::info!(
+                from = self.from.as_str(),
+                to = message.to.as_str(),
+                text = message.text.as_str(),
+                "Mail: {}",
+                message.subject
+            ),
+        }
+
+        Ok(())
+    }
+}
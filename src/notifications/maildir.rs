@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Error, Message};
+
+use chrono::Utc;
+use lettre::message::Mailbox;
+
+// Mailer delivers messages into a maildir, so that they can be read with any mail client
+// without running an SMTP server. Each message is written to tmp/ and then renamed into new/,
+// so a reader never sees one half written.
+pub struct Mailer {
+    from: Mailbox,
+    path: PathBuf,
+    host: String,
+    // keeps names unique between messages delivered within the same microsecond
+    sequence: AtomicU64,
+}
+
+impl Mailer {
+    // new creates the maildir's folders if they do not exist yet
+    pub fn new(from: Mailbox, path: PathBuf) -> Result<Self, Error> {
+        for dir in ["tmp", "new", "cur"] {
+            let dir = path.join(dir);
+            std::fs::create_dir_all(&dir).map_err(|e| Error::Write(dir, e))?;
+        }
+
+        Ok(Self {
+            from,
+            path,
+            host: hostname(),
+            sequence: AtomicU64::new(0),
+        })
+    }
+
+    // unique_name follows the maildir convention of time.unique.host
+    fn unique_name(&self) -> String {
+        let now = Utc::now();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.timestamp(),
+            now.timestamp_subsec_micros(),
+            std::process::id(),
+            sequence,
+            self.host
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Mailer for Mailer {
+    #[tracing::instrument(name = "maildir_mailer.send", skip_all)]
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        let email = message.to_email(&self.from)?;
+        let name = self.unique_name();
+        let tmp = self.path.join("tmp").join(&name);
+        let new = self.path.join("new").join(&name);
+
+        tokio::fs::write(&tmp, email.formatted())
+            .await
+            .map_err(|e| Error::Write(tmp.clone(), e))?;
+        tokio::fs::rename(&tmp, &new)
+            .await
+            .map_err(|e| Error::Write(new.clone(), e))?;
+
+        tracing::info!(
+            "Delivered message \"{}\" for {} to {}",
+            message.subject,
+            message.to,
+            new.display()
+        );
+
+        Ok(())
+    }
+}
+
+// hostname is only used in file names, where '/' and ':' are not allowed
+fn hostname() -> String {
+    let host = std::fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "localhost".to_string());
+
+    host.replace('/', "\\057").replace(':', "\\072")
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{Error, Message};
+use crate::metrics;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+
+// Mailer hands messages to a background worker that delivers them through the inner mailer, so
+// that a slow or unreachable mail server never holds up the request that sent them. Sending only
+// fails when the queue is full or shutting down, and the message is dropped; delivery failures
+// are logged by the worker, as there is no one left to report them to.
+pub struct Mailer {
+    sender: mpsc::Sender<(Message, tracing::Span)>,
+}
+
+// Worker delivers the queued messages, until it is shut down
+pub struct Worker {
+    close: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Mailer {
+    pub fn new(inner: Arc<dyn super::Mailer>, capacity: usize) -> (Self, Worker) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let (close, closed) = oneshot::channel();
+        let handle = tokio::spawn(run(inner, receiver, closed));
+
+        (Self { sender }, Worker { close, handle })
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Mailer for Mailer {
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        // delivery is traced on its own, as it usually outlives the request that sent it
+        let span = tracing::info_span!("mail_queue.deliver");
+        span.follows_from(tracing::Span::current());
+
+        match self.sender.try_send((message, span)) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                metrics::MAIL_MESSAGES.with_label_values(&["dropped"]).inc();
+                Err(Error::QueueUnavailable)
+            }
+        }
+    }
+}
+
+impl Worker {
+    // shutdown stops the queue taking new messages and waits for the ones already queued to be
+    // delivered, dropping whatever is left when the timeout expires
+    pub async fn shutdown(self, timeout: Duration) {
+        let _ = self.close.send(());
+
+        let mut handle = self.handle;
+        match tokio::time::timeout(timeout, &mut handle).await {
+            Ok(Ok(())) => tracing::info!("mail queue drained"),
+            Ok(Err(e)) => tracing::error!("mail queue worker failed: {}", e),
+            Err(_) => {
+                tracing::warn!("mail queue did not drain in time, dropping queued messages");
+                handle.abort();
+            }
+        }
+    }
+}
+
+async fn run(
+    inner: Arc<dyn super::Mailer>,
+    mut receiver: mpsc::Receiver<(Message, tracing::Span)>,
+    mut closed: oneshot::Receiver<()>,
+) {
+    loop {
+        tokio::select! {
+            queued = receiver.recv() => match queued {
+                Some((message, span)) => deliver(inner.as_ref(), message).instrument(span).await,
+                None => return,
+            },
+            _ = &mut closed => break,
+        }
+    }
+
+    // once closed, recv returns what is still queued and then None
+    receiver.close();
+    while let Some((message, span)) = receiver.recv().await {
+        deliver(inner.as_ref(), message).instrument(span).await;
+    }
+}
+
+async fn deliver(inner: &dyn super::Mailer, message: Message) {
+    let subject = message.subject.clone();
+
+    match inner.send(message).await {
+        Ok(_) => metrics::MAIL_MESSAGES.with_label_values(&["sent"]).inc(),
+        Err(e) => {
+            metrics::MAIL_MESSAGES.with_label_values(&["failed"]).inc();
+            tracing::error!("Failed to deliver message \"{}\": {}", subject, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notifications::Mailer as _;
+    use std::sync::Mutex;
+
+    // Recording delivers messages by keeping them, or never delivers them when stuck
+    #[derive(Default)]
+    struct Recording {
+        sent: Mutex<Vec<String>>,
+        stuck: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl super::super::Mailer for Recording {
+        async fn send(&self, message: Message) -> Result<(), Error> {
+            if self.stuck {
+                std::future::pending::<()>().await;
+            }
+
+            self.sent.lock().unwrap().push(message.subject);
+            Ok(())
+        }
+    }
+
+    fn message(subject: &str) -> Message {
+        Message {
+            to: "ada@example.com".to_string(),
+            subject: subject.to_string(),
+            text: "Hi".to_string(),
+            html: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn delivers_in_the_background() {
+        let inner = Arc::new(Recording::default());
+        let (mailer, worker) = Mailer::new(inner.clone(), 10);
+
+        mailer.send(message("first")).await.unwrap();
+        while inner.sent.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(*inner.sent.lock().unwrap(), ["first"]);
+        worker.shutdown(Duration::from_secs(1)).await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_the_queue() {
+        let inner = Arc::new(Recording::default());
+        let (mailer, worker) = Mailer::new(inner.clone(), 10);
+
+        // the test runtime is single threaded, so the worker cannot run before shutdown
+        for subject in ["first", "second", "third"] {
+            mailer.send(message(subject)).await.unwrap();
+        }
+        worker.shutdown(Duration::from_secs(1)).await;
+
+        assert_eq!(*inner.sent.lock().unwrap(), ["first", "second", "third"]);
+        assert!(matches!(
+            mailer.send(message("late")).await,
+            Err(Error::QueueUnavailable)
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_messages_when_full() {
+        let inner = Arc::new(Recording::default());
+        let (mailer, worker) = Mailer::new(inner.clone(), 2);
+
+        mailer.send(message("first")).await.unwrap();
+        mailer.send(message("second")).await.unwrap();
+        assert!(matches!(
+            mailer.send(message("third")).await,
+            Err(Error::QueueUnavailable)
+        ));
+        worker.shutdown(Duration::from_secs(1)).await;
+
+        assert_eq!(*inner.sent.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_gives_up_on_stuck_deliveries() {
+        let inner = Arc::new(Recording {
+            stuck: true,
+            ..Default::default()
+        });
+        let (mailer, worker) = Mailer::new(inner.clone(), 10);
+
+        mailer.send(message("first")).await.unwrap();
+        worker.shutdown(Duration::from_millis(10)).await;
+
+        assert!(inner.sent.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use super::{Error, Message};
+use crate::config::core::{NotificationsSmtp, SmtpTls};
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+
+// Mailer delivers messages to an SMTP relay, over a pool of connections shared between sends
+pub struct Mailer {
+    from: Mailbox,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl Mailer {
+    pub fn new(from: Mailbox, config: &NotificationsSmtp) -> Result<Self, Error> {
+        let tls = match config.tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls => Tls::Required(TlsParameters::new(config.host.clone())?),
+            SmtpTls::Tls => Tls::Wrapper(TlsParameters::new(config.host.clone())?),
+        };
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+            .port(config.port)
+            .tls(tls)
+            .timeout(Some(Duration::from_secs(config.timeout_seconds)));
+
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Mailer for Mailer {
+    #[tracing::instrument(name = "smtp_mailer.send", skip_all)]
+    async fn send(&self, message: Message) -> Result<(), Error> {
+        let email = message.to_email(&self.from)?;
+        let response = self.transport.send(email).await?;
+
+        tracing::info!(
+            "Relayed message \"{}\" for {}: {}",
+            message.subject,
+            message.to,
+            response.code()
+        );
+
+        Ok(())
+    }
+}
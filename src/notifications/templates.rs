@@ -0,0 +1,236 @@
+use super::{Error, Message};
+
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+
+// Template is a message users can be sent. Each has a text and an HTML version, rendered from
+// the files of the same name under templates/notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Template {
+    Welcome,
+    EmailChanged,
+    AccountDeleted,
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl Template {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Template::Welcome => "welcome",
+            Template::EmailChanged => "email_changed",
+            Template::AccountDeleted => "account_deleted",
+            Template::VerifyEmail => "verify_email",
+            Template::ResetPassword => "reset_password",
+        }
+    }
+
+    fn subject(&self) -> &'static str {
+        match self {
+            Template::Welcome => "Welcome",
+            Template::EmailChanged => "Your email address was changed",
+            Template::AccountDeleted => "Your account was deleted",
+            Template::VerifyEmail => "Verify your email address",
+            Template::ResetPassword => "Reset your password",
+        }
+    }
+}
+
+// the templates are built into the binary, so a message can never go missing at runtime
+const SOURCES: &[(&str, &str)] = &[
+    (
+        "layout.html",
+        include_str!("../../templates/notifications/layout.html"),
+    ),
+    (
+        "welcome.txt",
+        include_str!("../../templates/notifications/welcome.txt"),
+    ),
+    (
+        "welcome.html",
+        include_str!("../../templates/notifications/welcome.html"),
+    ),
+    (
+        "email_changed.txt",
+        include_str!("../../templates/notifications/email_changed.txt"),
+    ),
+    (
+        "email_changed.html",
+        include_str!("../../templates/notifications/email_changed.html"),
+    ),
+    (
+        "account_deleted.txt",
+        include_str!("../../templates/notifications/account_deleted.txt"),
+    ),
+    (
+        "account_deleted.html",
+        include_str!("../../templates/notifications/account_deleted.html"),
+    ),
+    (
+        "verify_email.txt",
+        include_str!("../../templates/notifications/verify_email.txt"),
+    ),
+    (
+        "verify_email.html",
+        include_str!("../../templates/notifications/verify_email.html"),
+    ),
+    (
+        "reset_password.txt",
+        include_str!("../../templates/notifications/reset_password.txt"),
+    ),
+    (
+        "reset_password.html",
+        include_str!("../../templates/notifications/reset_password.html"),
+    ),
+];
+
+// Templates renders messages. Values are escaped in the HTML versions, as they include whatever
+// users put in their names.
+#[derive(Clone)]
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        let mut env = Environment::new();
+        // a value missing from the context is a bug, rather than something to leave blank
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+
+        // the sources are fixed, so failing to parse one is a bug
+        for (name, source) in SOURCES {
+            env.add_template(name, source)
+                .unwrap_or_else(|e| panic!("invalid template {}: {}", name, e));
+        }
+
+        Self { env }
+    }
+}
+
+impl Templates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn render<C: Serialize>(
+        &self,
+        template: Template,
+        to: String,
+        context: C,
+    ) -> Result<Message, Error> {
+        let context = minijinja::Value::from_serialize(context);
+        let render = |extension: &str| {
+            self.env
+                .get_template(&format!("{}.{}", template.name(), extension))
+                .and_then(|t| t.render(&context))
+                .map_err(|e| Error::Render(template.name(), e))
+        };
+
+        Ok(Message {
+            to,
+            subject: template.subject().to_string(),
+            text: render("txt")?,
+            html: Some(render("html")?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TEMPLATES: [Template; 5] = [
+        Template::Welcome,
+        Template::EmailChanged,
+        Template::AccountDeleted,
+        Template::VerifyEmail,
+        Template::ResetPassword,
+    ];
+
+    // context holds every value any template uses, extra values are ignored
+    fn context(first_name: &str) -> serde_json::Value {
+        json!({
+            "first_name": first_name,
+            "email": "ada@example.com",
+            "old_email": "old@example.com",
+            "new_email": "new@example.com",
+            "token": "vt_0123_abc",
+            "expires_at": "2030-01-01T00:00:00Z",
+        })
+    }
+
+    #[test]
+    fn renders_every_template() {
+        let templates = Templates::new();
+
+        for template in TEMPLATES {
+            let message = templates
+                .render(template, "ada@example.com".to_string(), context("Ada"))
+                .unwrap();
+            let html = message.html.unwrap();
+
+            assert_eq!(message.to, "ada@example.com");
+            assert_eq!(message.subject, template.subject());
+            assert!(message.text.starts_with("Hi Ada,"), "{}", template.name());
+            assert!(html.contains("<p>Hi Ada,</p>"), "{}", template.name());
+            assert!(
+                html.contains(&format!("<title>{}</title>", template.subject())),
+                "{}",
+                template.name()
+            );
+        }
+    }
+
+    #[test]
+    fn renders_values_into_both_versions() {
+        let templates = Templates::new();
+
+        let message = templates
+            .render(
+                Template::EmailChanged,
+                "new@example.com".to_string(),
+                context("Ada"),
+            )
+            .unwrap();
+
+        for body in [message.text, message.html.unwrap()] {
+            assert!(body.contains("old@example.com"));
+            assert!(body.contains("new@example.com"));
+        }
+    }
+
+    #[test]
+    fn escapes_values_in_html_only() {
+        let templates = Templates::new();
+        let name = "<script>alert(\"hi\")</script> & co";
+
+        for template in TEMPLATES {
+            let message = templates
+                .render(template, "ada@example.com".to_string(), context(name))
+                .unwrap();
+            let html = message.html.unwrap();
+
+            assert!(message.text.contains(name), "{}", template.name());
+            assert!(!html.contains("<script>"), "{}", template.name());
+            assert!(
+                html.contains("&lt;script&gt;alert(&quot;hi&quot;)&lt;&#x2f;script&gt; &amp; co"),
+                "{}",
+                template.name()
+            );
+        }
+    }
+
+    #[test]
+    fn missing_values_fail_to_render() {
+        let templates = Templates::new();
+
+        let result = templates.render(
+            Template::Welcome,
+            "ada@example.com".to_string(),
+            json!({ "first_name": "Ada" }),
+        );
+
+        assert!(matches!(result, Err(Error::Render("welcome", _))));
+    }
+}
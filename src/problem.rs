@@ -0,0 +1,171 @@
+use crate::request_id;
+use crate::validation::FieldError;
+
+use actix_web::error::{InternalError, JsonPayloadError, PathError, QueryPayloadError};
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+// ProblemType is an entry in the catalogue of errors the API can return. Its uri is stable, so
+// clients can rely on it to tell errors apart, while title is a human readable summary of it.
+pub struct ProblemType {
+    pub uri: &'static str,
+    pub title: &'static str,
+    pub status: StatusCode,
+}
+
+pub const MALFORMED_REQUEST: ProblemType = ProblemType {
+    uri: "/problems/malformed-request",
+    title: "Malformed request",
+    status: StatusCode::BAD_REQUEST,
+};
+
+// the request was well formed, but asked for something that cannot be done
+pub const INVALID_REQUEST: ProblemType = ProblemType {
+    uri: "/problems/invalid-request",
+    title: "Invalid request",
+    status: StatusCode::BAD_REQUEST,
+};
+
+// the request was well formed, but some of its fields broke the rules, each of which is listed
+// in the errors member
+pub const VALIDATION_FAILED: ProblemType = ProblemType {
+    uri: "/problems/validation-failed",
+    title: "Request failed validation",
+    status: StatusCode::UNPROCESSABLE_ENTITY,
+};
+
+pub const UNSUPPORTED_MEDIA_TYPE: ProblemType = ProblemType {
+    uri: "/problems/unsupported-media-type",
+    title: "Unsupported media type",
+    status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+};
+
+pub const UNAUTHORIZED: ProblemType = ProblemType {
+    uri: "/problems/unauthorized",
+    title: "Unauthorized",
+    status: StatusCode::UNAUTHORIZED,
+};
+
+// credentials could not be checked, typically because the store of API keys is unreachable
+pub const AUTHENTICATION_UNAVAILABLE: ProblemType = ProblemType {
+    uri: "/problems/authentication-unavailable",
+    title: "Authentication unavailable",
+    status: StatusCode::SERVICE_UNAVAILABLE,
+};
+
+pub const FORBIDDEN: ProblemType = ProblemType {
+    uri: "/problems/forbidden",
+    title: "Forbidden",
+    status: StatusCode::FORBIDDEN,
+};
+
+pub const INTERNAL_ERROR: ProblemType = ProblemType {
+    uri: "/problems/internal-error",
+    title: "Internal error",
+    status: StatusCode::INTERNAL_SERVER_ERROR,
+};
+
+// about:blank means the problem has no semantics beyond its status code (RFC 7807 section 4.2)
+pub const NOT_FOUND: ProblemType = ProblemType {
+    uri: "about:blank",
+    title: "Not Found",
+    status: StatusCode::NOT_FOUND,
+};
+
+// Problem is an RFC 7807 problem details document
+#[derive(Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    type_uri: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    instance: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // extension members specific to the problem type
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    pub fn new(req: &HttpRequest, problem_type: &ProblemType, detail: Option<String>) -> Self {
+        Self {
+            type_uri: problem_type.uri,
+            title: problem_type.title,
+            status: problem_type.status.as_u16(),
+            detail,
+            instance: req.path().to_string(),
+            request_id: request_id::get(req),
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn with_extension(mut self, name: &str, value: impl Serialize) -> Self {
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.extensions.insert(name.to_string(), value);
+            }
+            Err(e) => tracing::error!("Failed to serialise problem extension {}: {}", name, e),
+        }
+        self
+    }
+
+    pub fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        HttpResponse::build(status)
+            .content_type(CONTENT_TYPE)
+            .body(serde_json::to_string(&self).unwrap_or_else(|_| "{}".to_string()))
+    }
+}
+
+// not_found is the default service, answering requests that match no route
+pub async fn not_found(req: HttpRequest) -> HttpResponse {
+    Problem::new(
+        &req,
+        &NOT_FOUND,
+        Some(format!("no route matches {} {}", req.method(), req.path())),
+    )
+    .into_response()
+}
+
+// validation_failed lists every field that broke the rules, so clients can fix them all at once
+pub fn validation_failed(req: &HttpRequest, field_errors: Vec<FieldError>) -> HttpResponse {
+    Problem::new(
+        req,
+        &VALIDATION_FAILED,
+        Some(format!("{} field(s) failed validation", field_errors.len())),
+    )
+    .with_extension("errors", field_errors)
+    .into_response()
+}
+
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let (problem_type, detail) = match &err {
+        JsonPayloadError::ContentType => (
+            &UNSUPPORTED_MEDIA_TYPE,
+            "request body must be application/json".to_string(),
+        ),
+        JsonPayloadError::Deserialize(json_err) => (&MALFORMED_REQUEST, json_err.to_string()),
+        _ => (&MALFORMED_REQUEST, "invalid JSON payload".to_string()),
+    };
+
+    let response = Problem::new(req, problem_type, Some(detail)).into_response();
+    InternalError::from_response(err, response).into()
+}
+
+pub fn path_error_handler(err: PathError, req: &HttpRequest) -> actix_web::Error {
+    let response = Problem::new(req, &MALFORMED_REQUEST, Some(err.to_string())).into_response();
+    InternalError::from_response(err, response).into()
+}
+
+pub fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> actix_web::Error {
+    let response = Problem::new(req, &MALFORMED_REQUEST, Some(err.to_string())).into_response();
+    InternalError::from_response(err, response).into()
+}
@@ -0,0 +1,83 @@
+use crate::telemetry;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpMessage, HttpRequest};
+use tracing::Instrument;
+use tracing::field::Empty;
+use uuid::Uuid;
+
+pub const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// ids supplied by clients are logged and echoed back, so anything long or unprintable is replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+// RequestId identifies a single request, so that an error reported by a client can be matched
+// up with the server's logs
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// middleware takes the request's id from its X-Request-Id header, or generates one, and runs the
+// rest of the request inside a span carrying it. Handlers add the user id to the span once they
+// know it, authentication adds the token's subject, and the id is echoed in the response's
+// X-Request-Id header. The span is exported as the request's server span, continuing the
+// caller's trace when it sent a traceparent.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| is_valid(value))
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    // route patterns rather than paths keep span names low cardinality
+    let route = req.match_pattern().unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        otel.name = %format!("{} {}", req.method(), route).trim_end(),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.path(),
+        user_id = Empty,
+        subject = Empty,
+        status = Empty,
+    );
+    telemetry::set_remote_parent(&span, req.headers());
+
+    let mut res = next.call(req).instrument(span.clone()).await?;
+
+    span.record("status", res.status().as_u16());
+    if res.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(X_REQUEST_ID, value);
+    }
+
+    Ok(res)
+}
+
+pub fn get(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+// record_user_id adds the id of the user a request is about to the current request span
+pub fn record_user_id(id: &Uuid) {
+    tracing::Span::current().record("user_id", tracing::field::display(id));
+}
+
+fn is_valid(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.chars().all(|c| c.is_ascii_graphic())
+}
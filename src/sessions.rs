@@ -0,0 +1,2 @@
+pub(crate) mod repo;
+pub(crate) mod service;
@@ -0,0 +1,3 @@
+pub(crate) mod dynamodb;
+pub(crate) mod errors;
+pub(crate) mod memory;
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::errors::Error;
+use crate::dynamodb::attrs::{
+    get_datetime, get_number, get_optional_datetime, get_string, get_uuid,
+};
+use crate::dynamodb::health::TableCheck;
+use crate::dynamodb::instrument;
+use crate::health::Check;
+use crate::sessions::service;
+use crate::sessions::service::idos;
+
+use aws_sdk_dynamodb::{
+    Client,
+    types::{AttributeValue, ReturnConsumedCapacity},
+};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// Repo keeps one item per session, keyed by user_id (partition) and family_id (sort) so that a
+// user's sessions can be queried. The ttl attribute holds the expiry as epoch seconds, for the
+// table's TTL to remove expired sessions by.
+#[derive(Clone)]
+pub struct Repo {
+    client: Client,
+    table_name: String,
+}
+
+impl Repo {
+    pub fn new(client: Client, table_name: String) -> Self {
+        Self { client, table_name }
+    }
+
+    pub fn health_checks(&self) -> Vec<Arc<dyn Check>> {
+        vec![Arc::new(TableCheck::new(
+            self.client.clone(),
+            self.table_name.clone(),
+        ))]
+    }
+}
+
+#[async_trait::async_trait]
+impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "dynamodb.create_session", skip_all, fields(session_id = %session.family_id))]
+    async fn create_session(&self, session: &idos::Session) -> Result<(), Error> {
+        let request = self
+            .client
+            .put_item()
+            .table_name(self.table_name.clone())
+            .set_item(Some(session_to_attrs(session)))
+            .condition_expression("attribute_not_exists(family_id)")
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("PutItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::AlreadyExists)
+            }
+            Err(e) => {
+                tracing::error!("Failed to put session: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.get_session", skip_all, fields(session_id = %family_id))]
+    async fn get_session(&self, user_id: Uuid, family_id: Uuid) -> Result<idos::Session, Error> {
+        // reads are consistent, so a token that was just rotated is never accepted again
+        let request = self
+            .client
+            .get_item()
+            .table_name(self.table_name.clone())
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .key("family_id", AttributeValue::S(family_id.to_string()))
+            .consistent_read(true)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("GetItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(output) => match output.item() {
+                Some(attrs) => session_from_attrs(attrs),
+                None => Err(Error::NotFound),
+            },
+            Err(e) => {
+                tracing::error!("Failed to get session: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.list_sessions", skip_all, fields(user_id = %user_id))]
+    async fn list_sessions(&self, user_id: Uuid) -> Result<Vec<idos::Session>, Error> {
+        let mut sessions = vec![];
+        let mut start_key = None;
+
+        loop {
+            let request = self
+                .client
+                .query()
+                .table_name(self.table_name.clone())
+                .key_condition_expression("user_id = :user_id")
+                .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+                .consistent_read(true)
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+            let output = match instrument::call("Query", &[&self.table_name], request.send()).await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    tracing::error!("Failed to query sessions: {:?}", e);
+                    return Err(Error::Internal);
+                }
+            };
+
+            for attrs in output.items() {
+                sessions.push(session_from_attrs(attrs)?);
+            }
+
+            match output.last_evaluated_key {
+                Some(key) => start_key = Some(key),
+                None => break,
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    #[tracing::instrument(name = "dynamodb.rotate_session", skip_all, fields(session_id = %session.family_id))]
+    async fn rotate_session(
+        &self,
+        session: &idos::Session,
+        expected_hash: &str,
+    ) -> Result<(), Error> {
+        // ttl is a reserved word, so it has to be named through a placeholder
+        let request = self
+            .client
+            .update_item()
+            .table_name(self.table_name.clone())
+            .key("user_id", AttributeValue::S(session.user_id.to_string()))
+            .key(
+                "family_id",
+                AttributeValue::S(session.family_id.to_string()),
+            )
+            .update_expression(
+                "SET token_hash = :token_hash, generation = :generation, \
+                 refreshed_at = :refreshed_at, expires_at = :expires_at, #ttl = :ttl",
+            )
+            .condition_expression(
+                "attribute_exists(family_id) AND token_hash = :expected_hash \
+                 AND attribute_not_exists(revoked_at)",
+            )
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_values(
+                ":token_hash",
+                AttributeValue::S(session.token_hash.clone()),
+            )
+            .expression_attribute_values(
+                ":generation",
+                AttributeValue::N(session.generation.to_string()),
+            )
+            .expression_attribute_values(
+                ":refreshed_at",
+                AttributeValue::S(session.refreshed_at.to_rfc3339()),
+            )
+            .expression_attribute_values(
+                ":expires_at",
+                AttributeValue::S(session.expires_at.to_rfc3339()),
+            )
+            .expression_attribute_values(
+                ":ttl",
+                AttributeValue::N(session.expires_at.timestamp().to_string()),
+            )
+            .expression_attribute_values(
+                ":expected_hash",
+                AttributeValue::S(expected_hash.to_string()),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("UpdateItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::Changed)
+            }
+            Err(e) => {
+                tracing::error!("Failed to rotate session: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.revoke_session", skip_all, fields(session_id = %family_id))]
+    async fn revoke_session(
+        &self,
+        user_id: Uuid,
+        family_id: Uuid,
+        revoked_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let request = self
+            .client
+            .update_item()
+            .table_name(self.table_name.clone())
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .key("family_id", AttributeValue::S(family_id.to_string()))
+            .update_expression("SET revoked_at = if_not_exists(revoked_at, :at)")
+            .condition_expression("attribute_exists(family_id)")
+            .expression_attribute_values(":at", AttributeValue::S(revoked_at.to_rfc3339()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("UpdateItem", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(_) => Ok(()),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::NotFound)
+            }
+            Err(e) => {
+                tracing::error!("Failed to revoke session: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+}
+
+fn session_to_attrs(session: &idos::Session) -> HashMap<String, AttributeValue> {
+    let mut attrs = HashMap::from([
+        (
+            "user_id".to_string(),
+            AttributeValue::S(session.user_id.to_string()),
+        ),
+        (
+            "family_id".to_string(),
+            AttributeValue::S(session.family_id.to_string()),
+        ),
+        (
+            "token_hash".to_string(),
+            AttributeValue::S(session.token_hash.clone()),
+        ),
+        (
+            "generation".to_string(),
+            AttributeValue::N(session.generation.to_string()),
+        ),
+        (
+            "created_at".to_string(),
+            AttributeValue::S(session.created_at.to_rfc3339()),
+        ),
+        (
+            "refreshed_at".to_string(),
+            AttributeValue::S(session.refreshed_at.to_rfc3339()),
+        ),
+        (
+            "expires_at".to_string(),
+            AttributeValue::S(session.expires_at.to_rfc3339()),
+        ),
+        (
+            "ttl".to_string(),
+            AttributeValue::N(session.expires_at.timestamp().to_string()),
+        ),
+    ]);
+
+    if let Some(revoked_at) = session.revoked_at {
+        attrs.insert(
+            "revoked_at".to_string(),
+            AttributeValue::S(revoked_at.to_rfc3339()),
+        );
+    }
+
+    attrs
+}
+
+fn session_from_attrs(attrs: &HashMap<String, AttributeValue>) -> Result<idos::Session, Error> {
+    Ok(idos::Session {
+        user_id: get_uuid(attrs, "user_id")?,
+        family_id: get_uuid(attrs, "family_id")?,
+        token_hash: get_string(attrs, "token_hash")?,
+        generation: get_number(attrs, "generation")?,
+        created_at: get_datetime(attrs, "created_at")?,
+        refreshed_at: get_datetime(attrs, "refreshed_at")?,
+        expires_at: get_datetime(attrs, "expires_at")?,
+        revoked_at: get_optional_datetime(attrs, "revoked_at")?,
+    })
+}
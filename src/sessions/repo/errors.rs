@@ -0,0 +1,26 @@
+use crate::dynamodb::attrs::Malformed;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("session not found")]
+    NotFound,
+
+    #[error("session already exists")]
+    AlreadyExists,
+
+    // the session was refreshed or revoked since it was read
+    #[error("session has changed")]
+    Changed,
+
+    #[error("malformed response: {0}")]
+    MalformedResponse(String),
+
+    #[error("internal error")]
+    Internal,
+}
+
+impl From<Malformed> for Error {
+    fn from(malformed: Malformed) -> Self {
+        Error::MalformedResponse(malformed.0)
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::errors::Error;
+use crate::sessions::service;
+use crate::sessions::service::idos;
+
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+// sessions are keyed by user then family, the same as the DynamoDB table, so a user's sessions
+// are a range of the map. Expired sessions are never removed, unlike with the table's TTL.
+#[derive(Clone, Default)]
+pub struct Repo {
+    sessions: Arc<RwLock<BTreeMap<(Uuid, Uuid), idos::Session>>>,
+}
+
+impl Repo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "memory.create_session", skip_all, fields(session_id = %session.family_id))]
+    async fn create_session(&self, session: &idos::Session) -> Result<(), Error> {
+        let mut sessions = self.sessions.write().await;
+
+        let key = (session.user_id, session.family_id);
+        if sessions.contains_key(&key) {
+            return Err(Error::AlreadyExists);
+        }
+
+        sessions.insert(key, session.clone());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.get_session", skip_all, fields(session_id = %family_id))]
+    async fn get_session(&self, user_id: Uuid, family_id: Uuid) -> Result<idos::Session, Error> {
+        let sessions = self.sessions.read().await;
+
+        sessions
+            .get(&(user_id, family_id))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    #[tracing::instrument(name = "memory.list_sessions", skip_all, fields(user_id = %user_id))]
+    async fn list_sessions(&self, user_id: Uuid) -> Result<Vec<idos::Session>, Error> {
+        let sessions = self.sessions.read().await;
+
+        Ok(sessions
+            .range((user_id, Uuid::nil())..=(user_id, Uuid::max()))
+            .map(|(_, session)| session.clone())
+            .collect())
+    }
+
+    #[tracing::instrument(name = "memory.rotate_session", skip_all, fields(session_id = %session.family_id))]
+    async fn rotate_session(
+        &self,
+        session: &idos::Session,
+        expected_hash: &str,
+    ) -> Result<(), Error> {
+        let mut sessions = self.sessions.write().await;
+
+        let stored = sessions
+            .get_mut(&(session.user_id, session.family_id))
+            .ok_or(Error::NotFound)?;
+        if stored.token_hash != expected_hash || stored.is_revoked() {
+            return Err(Error::Changed);
+        }
+
+        *stored = session.clone();
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.revoke_session", skip_all, fields(session_id = %family_id))]
+    async fn revoke_session(
+        &self,
+        user_id: Uuid,
+        family_id: Uuid,
+        revoked_at: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let mut sessions = self.sessions.write().await;
+
+        let session = sessions
+            .get_mut(&(user_id, family_id))
+            .ok_or(Error::NotFound)?;
+        session.revoked_at.get_or_insert(revoked_at);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,346 @@
+use super::idos::{RefreshToken, Session};
+use crate::auth;
+use crate::sessions::repo::errors::Error as RepoError;
+use crate::users::app::core::Sessions as UserSessions;
+use crate::users::service::errors::Error as UserServiceError;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use uuid::Uuid;
+
+#[async_trait::async_trait]
+pub trait Repo: Send + Sync + Clone + 'static {
+    async fn create_session(&self, session: &Session) -> Result<(), RepoError>;
+
+    async fn get_session(&self, user_id: Uuid, family_id: Uuid) -> Result<Session, RepoError>;
+
+    async fn list_sessions(&self, user_id: Uuid) -> Result<Vec<Session>, RepoError>;
+
+    // rotate_session stores the session's new token, failing with Changed unless the stored
+    // session still has expected_hash and has not been revoked
+    async fn rotate_session(&self, session: &Session, expected_hash: &str)
+    -> Result<(), RepoError>;
+
+    // revoke_session keeps the time the session was first revoked, so revoking twice changes
+    // nothing
+    async fn revoke_session(
+        &self,
+        user_id: Uuid,
+        family_id: Uuid,
+        revoked_at: DateTime<Utc>,
+    ) -> Result<(), RepoError>;
+}
+
+#[derive(Clone)]
+pub struct Service<R: Repo> {
+    repo: R,
+    // a session expires once its refresh token has gone unused for this long
+    ttl: TimeDelta,
+}
+
+impl<R: Repo> Service<R> {
+    pub fn new(repo: R, ttl: TimeDelta) -> Self {
+        Self { repo, ttl }
+    }
+
+    // revoke_family ends the session a replayed token belongs to. Only one of the client and
+    // whoever replayed the token holds its successor, and there is no telling which, so neither
+    // is allowed to carry on.
+    async fn revoke_family(&self, user_id: Uuid, family_id: Uuid) -> Result<(), auth::Error> {
+        tracing::warn!(
+            "Refresh token reuse detected, revoking session {} of user {}",
+            family_id,
+            user_id
+        );
+
+        match self
+            .repo
+            .revoke_session(user_id, family_id, Utc::now())
+            .await
+        {
+            Ok(_) | Err(RepoError::NotFound) => Ok(()),
+            Err(e) => {
+                tracing::error!("Failed to revoke session {}: {}", family_id, e);
+                Err(auth::Error::Unavailable)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Repo> auth::Sessions for Service<R> {
+    #[tracing::instrument(name = "service.start_session", skip_all)]
+    async fn start(&self, subject: &str) -> Result<String, auth::Error> {
+        // sessions are only started for users, whose subject is their id
+        let user_id = subject.parse::<Uuid>().map_err(|_| {
+            tracing::error!("Cannot start a session for non-user subject {}", subject);
+            auth::Error::Unavailable
+        })?;
+
+        let now = Utc::now();
+        let family_id = Uuid::new_v4();
+        let token = RefreshToken::generate(user_id, family_id);
+
+        let session = Session {
+            user_id,
+            family_id,
+            token_hash: token.hash(),
+            generation: 0,
+            created_at: now,
+            refreshed_at: now,
+            expires_at: now + self.ttl,
+            revoked_at: None,
+        };
+
+        if let Err(e) = self.repo.create_session(&session).await {
+            tracing::error!("Failed to create session for user {}: {}", user_id, e);
+            return Err(auth::Error::Unavailable);
+        }
+
+        tracing::info!("Started session {} for user {}", family_id, user_id);
+
+        Ok(token.as_str().to_string())
+    }
+
+    #[tracing::instrument(name = "service.refresh_session", skip_all)]
+    async fn refresh(&self, refresh_token: &str) -> Result<(String, String), auth::Error> {
+        let token = RefreshToken::parse(refresh_token).ok_or(auth::Error::InvalidRefreshToken)?;
+
+        let mut session = match self
+            .repo
+            .get_session(token.user_id(), token.family_id())
+            .await
+        {
+            Ok(session) => session,
+            Err(RepoError::NotFound) => return Err(auth::Error::InvalidRefreshToken),
+            Err(e) => {
+                tracing::error!("Failed to get session {}: {}", token.family_id(), e);
+                return Err(auth::Error::Unavailable);
+            }
+        };
+
+        let now = Utc::now();
+        if session.is_revoked() || session.is_expired(now) {
+            return Err(auth::Error::InvalidRefreshToken);
+        }
+
+        // a token from a live session that is not its latest has already been swapped for
+        // another, so it is being used a second time
+        if !session.matches(&token) {
+            self.revoke_family(session.user_id, session.family_id)
+                .await?;
+            return Err(auth::Error::InvalidRefreshToken);
+        }
+
+        let next = RefreshToken::generate(session.user_id, session.family_id);
+        let expected_hash = std::mem::replace(&mut session.token_hash, next.hash());
+        session.generation += 1;
+        session.refreshed_at = now;
+        session.expires_at = now + self.ttl;
+
+        match self.repo.rotate_session(&session, &expected_hash).await {
+            Ok(_) => Ok((session.user_id.to_string(), next.as_str().to_string())),
+            // the same token was used concurrently, which is reuse all the same
+            Err(RepoError::Changed) => {
+                self.revoke_family(session.user_id, session.family_id)
+                    .await?;
+                Err(auth::Error::InvalidRefreshToken)
+            }
+            Err(e) => {
+                tracing::error!("Failed to rotate session {}: {}", session.family_id, e);
+                Err(auth::Error::Unavailable)
+            }
+        }
+    }
+
+    // ending a session with a token that is not its latest does nothing, so a stale token
+    // cannot be used to log someone else out
+    #[tracing::instrument(name = "service.end_session", skip_all)]
+    async fn end(&self, refresh_token: &str) -> Result<(), auth::Error> {
+        let Some(token) = RefreshToken::parse(refresh_token) else {
+            return Ok(());
+        };
+
+        let session = match self
+            .repo
+            .get_session(token.user_id(), token.family_id())
+            .await
+        {
+            Ok(session) => session,
+            Err(RepoError::NotFound) => return Ok(()),
+            Err(e) => {
+                tracing::error!("Failed to get session {}: {}", token.family_id(), e);
+                return Err(auth::Error::Unavailable);
+            }
+        };
+
+        if session.is_revoked() || !session.matches(&token) {
+            return Ok(());
+        }
+
+        match self
+            .repo
+            .revoke_session(session.user_id, session.family_id, Utc::now())
+            .await
+        {
+            Ok(_) | Err(RepoError::NotFound) => {
+                tracing::info!("Ended session {}", session.family_id);
+                Ok(())
+            }
+            Err(e) => {
+                tracing::error!("Failed to revoke session {}: {}", session.family_id, e);
+                Err(auth::Error::Unavailable)
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Repo> UserSessions for Service<R> {
+    #[tracing::instrument(name = "service.revoke_user_sessions", skip_all, fields(user_id = %id))]
+    async fn revoke_user_sessions(&self, id: Uuid) -> Result<(), UserServiceError> {
+        let sessions = self.repo.list_sessions(id).await.map_err(|e| {
+            tracing::error!("Failed to list sessions of user {}: {}", id, e);
+            UserServiceError::Internal
+        })?;
+
+        let now = Utc::now();
+        let mut revoked = 0;
+        for session in sessions {
+            if session.is_revoked() || session.is_expired(now) {
+                continue;
+            }
+
+            match self.repo.revoke_session(id, session.family_id, now).await {
+                Ok(_) => revoked += 1,
+                // expired sessions may be removed by the table's TTL at any time
+                Err(RepoError::NotFound) => {}
+                Err(e) => {
+                    tracing::error!("Failed to revoke session {}: {}", session.family_id, e);
+                    return Err(UserServiceError::Internal);
+                }
+            }
+        }
+
+        tracing::info!("Revoked {} session(s) of user {}", revoked, id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Sessions as _;
+    use crate::sessions::repo::memory;
+
+    const TTL: TimeDelta = TimeDelta::days(1);
+
+    #[tokio::test]
+    async fn refresh_swaps_the_token_for_the_next_one() {
+        let service = Service::new(memory::Repo::new(), TTL);
+        let user_id = Uuid::new_v4();
+
+        let first = service.start(&user_id.to_string()).await.unwrap();
+        let (subject, second) = service.refresh(&first).await.unwrap();
+        let (_, third) = service.refresh(&second).await.unwrap();
+
+        assert_eq!(subject, user_id.to_string());
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+    }
+
+    #[tokio::test]
+    async fn replaying_a_rotated_token_revokes_the_session() {
+        let repo = memory::Repo::new();
+        let service = Service::new(repo.clone(), TTL);
+        let user_id = Uuid::new_v4();
+
+        let first = service.start(&user_id.to_string()).await.unwrap();
+        let (_, second) = service.refresh(&first).await.unwrap();
+
+        assert!(matches!(
+            service.refresh(&first).await,
+            Err(auth::Error::InvalidRefreshToken)
+        ));
+
+        // whoever holds the latest token is logged out too, as it may not be the client
+        assert!(matches!(
+            service.refresh(&second).await,
+            Err(auth::Error::InvalidRefreshToken)
+        ));
+
+        let token = RefreshToken::parse(&second).unwrap();
+        let session = repo
+            .get_session(token.user_id(), token.family_id())
+            .await
+            .unwrap();
+        assert!(session.is_revoked());
+    }
+
+    #[tokio::test]
+    async fn replay_leaves_other_sessions_alone() {
+        let service = Service::new(memory::Repo::new(), TTL);
+        let subject = Uuid::new_v4().to_string();
+
+        let replayed = service.start(&subject).await.unwrap();
+        let other = service.start(&subject).await.unwrap();
+        service.refresh(&replayed).await.unwrap();
+        assert!(service.refresh(&replayed).await.is_err());
+
+        assert!(service.refresh(&other).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_sessions_cannot_be_refreshed() {
+        let repo = memory::Repo::new();
+        let service = Service::new(repo.clone(), TimeDelta::zero());
+
+        let token = service.start(&Uuid::new_v4().to_string()).await.unwrap();
+
+        assert!(matches!(
+            service.refresh(&token).await,
+            Err(auth::Error::InvalidRefreshToken)
+        ));
+
+        // an expired token is not a replayed one, so nothing is revoked
+        let token = RefreshToken::parse(&token).unwrap();
+        let session = repo
+            .get_session(token.user_id(), token.family_id())
+            .await
+            .unwrap();
+        assert!(!session.is_revoked());
+    }
+
+    #[tokio::test]
+    async fn revoking_a_users_sessions_ends_every_one_of_them() {
+        let service = Service::new(memory::Repo::new(), TTL);
+        let user_id = Uuid::new_v4();
+        let other_user = Uuid::new_v4().to_string();
+
+        let first = service.start(&user_id.to_string()).await.unwrap();
+        let second = service.start(&user_id.to_string()).await.unwrap();
+        let others = service.start(&other_user).await.unwrap();
+
+        service.revoke_user_sessions(user_id).await.unwrap();
+
+        for token in [first, second] {
+            assert!(matches!(
+                service.refresh(&token).await,
+                Err(auth::Error::InvalidRefreshToken)
+            ));
+        }
+        assert!(service.refresh(&others).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ending_a_session_with_a_stale_token_does_nothing() {
+        let service = Service::new(memory::Repo::new(), TTL);
+
+        let first = service.start(&Uuid::new_v4().to_string()).await.unwrap();
+        let (_, second) = service.refresh(&first).await.unwrap();
+
+        service.end(&first).await.unwrap();
+
+        assert!(service.refresh(&second).await.is_ok());
+    }
+}
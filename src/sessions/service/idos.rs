@@ -0,0 +1,67 @@
+use crate::tokens;
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+const TOKEN_PREFIX: &str = "rt_";
+
+// Session is the family of refresh tokens descending from a single login. Each refresh swaps the
+// current token for the next one, so only the latest is valid and only its hash is kept.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub user_id: Uuid,
+    pub family_id: Uuid,
+    pub token_hash: String,
+    // how many times the session has been refreshed
+    pub generation: u64,
+    pub created_at: DateTime<Utc>,
+    pub refreshed_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    pub fn is_revoked(&self) -> bool {
+        self.revoked_at.is_some()
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+
+    pub fn matches(&self, token: &RefreshToken) -> bool {
+        token.0.matches(&self.token_hash)
+    }
+}
+
+// RefreshToken is a token as handed to the client, rt_<user id>_<family id>_<random>. Carrying
+// both ids lets the session be fetched directly, and lets a stale token name the family to
+// revoke when it is replayed.
+#[derive(Debug)]
+pub struct RefreshToken(tokens::Secret<2>);
+
+impl RefreshToken {
+    pub fn generate(user_id: Uuid, family_id: Uuid) -> Self {
+        Self(tokens::Secret::generate(TOKEN_PREFIX, [user_id, family_id]))
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        tokens::Secret::parse(TOKEN_PREFIX, value).map(Self)
+    }
+
+    pub fn user_id(&self) -> Uuid {
+        self.0.ids()[0]
+    }
+
+    pub fn family_id(&self) -> Uuid {
+        self.0.ids()[1]
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn hash(&self) -> String {
+        self.0.hash()
+    }
+}
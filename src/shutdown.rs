@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+
+use crate::health::Readiness;
+use crate::metrics;
+
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+
+// Shutdown records what was going on when the server was asked to stop, for the summary logged
+// once it has
+pub struct Shutdown {
+    signal: &'static str,
+    // when the server was told to stop, after the readiness delay, so the drain is timed on its
+    // own just as actix times it against the shutdown timeout
+    drain_started_at: Instant,
+    in_flight: i64,
+    forced: bool,
+}
+
+// on_signal waits for SIGTERM or SIGINT, then fails readiness, waits out the delay so load
+// balancers stop sending requests, and stops the server once in-flight requests have finished.
+// A second signal stops the server at once, dropping whatever is still in flight.
+pub async fn on_signal(
+    server: ServerHandle,
+    readiness: web::Data<Readiness>,
+    delay: Duration,
+) -> Shutdown {
+    let signal = wait_for_signal().await;
+    let in_flight = metrics::HTTP_REQUESTS_IN_FLIGHT.get();
+
+    tracing::info!(
+        signal,
+        in_flight,
+        delay_seconds = delay.as_secs(),
+        "shutdown requested, failing readiness and draining requests"
+    );
+    readiness.start_shutdown();
+
+    let mut drain_started_at = None;
+    let graceful = async {
+        tokio::time::sleep(delay).await;
+        drain_started_at = Some(Instant::now());
+        server.stop(true).await;
+    };
+
+    let forced = tokio::select! {
+        _ = graceful => false,
+        signal = wait_for_signal() => {
+            tracing::warn!(signal, "second signal received, stopping immediately");
+            server.stop(false).await;
+            true
+        }
+    };
+
+    Shutdown {
+        signal,
+        // a second signal during the delay stops the server without draining at all
+        drain_started_at: drain_started_at.unwrap_or_else(Instant::now),
+        in_flight,
+        forced,
+    }
+}
+
+impl Shutdown {
+    // log_summary is called once the server has stopped
+    pub fn log_summary(&self, timeout: Duration) {
+        let elapsed = self.drain_started_at.elapsed();
+
+        tracing::info!(
+            signal = self.signal,
+            in_flight_at_signal = self.in_flight,
+            drain_ms = elapsed.as_millis() as u64,
+            // actix drops whatever is still running when the timeout expires
+            timed_out = elapsed >= timeout,
+            forced = self.forced,
+            "server stopped"
+        );
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "SIGINT"
+}
@@ -0,0 +1,80 @@
+use crate::config::core::{ExporterKind, Telemetry};
+
+use actix_web::http::header::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::{KeyValue, global};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider, Tracer};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+// tracer_provider builds the provider spans are exported through, or None when export is
+// disabled, in which case spans are only logged
+pub fn tracer_provider(
+    config: &Telemetry,
+    service_name: &str,
+    service_version: &str,
+) -> Result<Option<SdkTracerProvider>, opentelemetry_otlp::ExporterBuildError> {
+    let builder = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sampling_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .with_attribute(KeyValue::new(
+                    "service.version",
+                    service_version.to_string(),
+                ))
+                .build(),
+        );
+
+    let provider = match config.exporter {
+        ExporterKind::None => return Ok(None),
+        ExporterKind::Stdout => builder
+            .with_simple_exporter(opentelemetry_stdout::SpanExporter::default())
+            .build(),
+        ExporterKind::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(config.endpoint.clone())
+                .build()?;
+
+            builder.with_batch_exporter(exporter).build()
+        }
+    };
+
+    // W3C trace context is what lets a request join its caller's trace
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+
+    Ok(Some(provider))
+}
+
+pub fn tracer(provider: &SdkTracerProvider) -> Tracer {
+    provider.tracer(env!("CARGO_PKG_NAME"))
+}
+
+// set_remote_parent makes span a child of the span described by the request's traceparent
+// header, if there is one
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+
+    // fails only when no OpenTelemetry layer is installed, when there is nothing to link to
+    let _ = span.set_parent(parent);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
@@ -0,0 +1,143 @@
+use std::fmt;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+// bytes of randomness in every secret
+const RANDOM_LENGTH: usize = 32;
+
+// Secret is a credential as handed to its holder, <prefix><id>_..._<random>. The prefix makes
+// leaked secrets easy to recognise in logs and secret scanners, and keeps one kind from being
+// mistaken for another. The ids let whatever the secret unlocks be fetched directly, rather than
+// by searching for its hash.
+pub struct Secret<const IDS: usize> {
+    ids: [Uuid; IDS],
+    value: String,
+}
+
+impl<const IDS: usize> Secret<IDS> {
+    pub fn generate(prefix: &str, ids: [Uuid; IDS]) -> Self {
+        let mut random = [0u8; RANDOM_LENGTH];
+        rand::rng().fill_bytes(&mut random);
+
+        let mut value = prefix.to_string();
+        for id in &ids {
+            value.push_str(&id.simple().to_string());
+            value.push('_');
+        }
+        value.push_str(&URL_SAFE_NO_PAD.encode(random));
+
+        Self { ids, value }
+    }
+
+    // parse only checks the secret is well formed, whether it is valid is up to what is stored
+    // for it
+    pub fn parse(prefix: &str, value: &str) -> Option<Self> {
+        // the simple form of a uuid is hex, so each underscore ends an id and the random part,
+        // which can contain underscores of its own, is whatever is left after the last one
+        let mut parts = value.strip_prefix(prefix)?.splitn(IDS + 1, '_');
+
+        let mut ids = [Uuid::nil(); IDS];
+        for id in &mut ids {
+            *id = Uuid::try_parse(parts.next()?).ok()?;
+        }
+
+        if parts.next().is_none_or(|random| random.is_empty()) {
+            return None;
+        }
+
+        Some(Self {
+            ids,
+            value: value.to_string(),
+        })
+    }
+
+    pub fn ids(&self) -> [Uuid; IDS] {
+        self.ids
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+
+    // the secret has enough entropy that a fast hash is as good as a slow one
+    pub fn hash(&self) -> String {
+        format!("{:x}", Sha256::digest(self.value.as_bytes()))
+    }
+
+    // matches compares hashes in constant time, so response times say nothing about how close
+    // a guess was
+    pub fn matches(&self, hash: &str) -> bool {
+        hash.as_bytes().ct_eq(self.hash().as_bytes()).into()
+    }
+}
+
+// the value is never printed, so a secret that ends up in a log line gives nothing away
+impl<const IDS: usize> fmt::Debug for Secret<IDS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret").field("ids", &self.ids).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_what_it_generates() {
+        let ids = [Uuid::new_v4(), Uuid::new_v4()];
+        let secret = Secret::generate("xx_", ids);
+
+        let parsed = Secret::<2>::parse("xx_", secret.as_str()).unwrap();
+
+        assert_eq!(parsed.ids(), ids);
+        assert_eq!(parsed.hash(), secret.hash());
+        assert!(parsed.matches(&secret.hash()));
+    }
+
+    #[test]
+    fn does_not_match_another_secret() {
+        let id = Uuid::new_v4();
+        let secret = Secret::generate("xx_", [id]);
+        let other = Secret::generate("xx_", [id]);
+
+        assert!(!secret.matches(&other.hash()));
+    }
+
+    #[test]
+    fn rejects_malformed_secrets() {
+        let id = Uuid::new_v4().simple().to_string();
+
+        for value in [
+            String::new(),
+            format!("yy_{}_random", id),
+            format!("xx_{}", id),
+            format!("xx_{}_", id),
+            "xx_not-a-uuid_random".to_string(),
+        ] {
+            assert!(Secret::<1>::parse("xx_", &value).is_none(), "{}", value);
+        }
+
+        // the number of ids is part of the format, so a secret carrying one is not one carrying two
+        assert!(Secret::<2>::parse("xx_", &format!("xx_{}_random", id)).is_none());
+    }
+
+    #[test]
+    fn random_part_may_contain_underscores() {
+        let id = Uuid::new_v4();
+        let value = format!("xx_{}_a_b", id.simple());
+
+        assert_eq!(Secret::<1>::parse("xx_", &value).unwrap().ids(), [id]);
+    }
+
+    #[test]
+    fn debug_does_not_print_the_value() {
+        let secret = Secret::generate("xx_", [Uuid::new_v4()]);
+
+        assert!(!format!("{:?}", secret).contains(secret.as_str()));
+    }
+}
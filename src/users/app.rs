@@ -1,4 +1,6 @@
 pub(crate) mod core;
 pub(crate) mod dtos;
 pub(crate) mod errors;
+pub(crate) mod policy;
 pub(crate) mod state;
+pub(crate) mod validation;
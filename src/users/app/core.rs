@@ -1,35 +1,94 @@
-use super::dtos::{QueryUser, ReqUserCreation, ReqUserUpdate};
-use super::errors::from_service_error;
+use super::dtos::{QueryUser, ReqPasswordChange, ReqUserCreation, ReqUserUpdate};
+use super::errors::{forbidden, from_service_error, unsupported_media_type};
+use super::policy::{self, Action};
 use super::state::State;
+use crate::auth;
+use crate::request_id;
 use crate::users::service;
 use crate::users::service::errors::Error as ServiceError;
 
 use std::sync::Arc;
 
-use actix_web::{HttpResponse, Responder, delete, get, post, put, web};
-use chrono::NaiveDate;
+use actix_web::http::header::{self, EntityTag, Header, HeaderName, HeaderValue};
+use actix_web::middleware::from_fn;
+use actix_web::{
+    HttpMessage, HttpRequest, HttpResponse, Responder, delete, get, patch, post, put, web,
+};
+use chrono::{DateTime, Utc};
+use serde_json::json;
 use uuid::Uuid;
 
+const MERGE_PATCH: &str = "application/merge-patch+json";
+const JSON_PATCH: &str = "application/json-patch+json";
+
+// the members of a user that a JSON Patch may address
+const PATCHABLE_FIELDS: [&str; 4] = ["first_name", "last_name", "email", "dob"];
+
 // TODO: currently we have a God trait that encapsulates all service methods - forces clients to violate Interface Segregation Principle
 // maybe should figure out an approach that breaks this trait up
 #[async_trait::async_trait]
 pub trait Service: Send + Sync + 'static {
+    // create_user stores a hash of the password, which is never returned with the user
     async fn create_user(
         &self,
         user: service::idos::User,
+        password: Option<service::idos::Password>,
     ) -> Result<service::idos::User, ServiceError>;
 
     async fn get_user(&self, id: Uuid) -> Result<service::idos::User, ServiceError>;
 
-    async fn get_user_by_email(&self, email: &str) -> Result<service::idos::User, ServiceError>;
+    async fn get_user_by_email(
+        &self,
+        email: &service::idos::Email,
+    ) -> Result<service::idos::User, ServiceError>;
 
+    async fn list_users(
+        &self,
+        query: service::idos::ListUsers,
+    ) -> Result<service::idos::UserPage, ServiceError>;
+
+    // expected_version is the version the caller last saw, None places no constraint on it
     async fn update_user(
         &self,
         id: Uuid,
         update: service::idos::UserUpdate,
+        expected_version: Option<u64>,
     ) -> Result<service::idos::User, ServiceError>;
 
-    async fn delete_user(&self, id: Uuid) -> Result<(), ServiceError>;
+    // delete_user soft deletes the user, which can be brought back with restore_user until it is
+    // purged
+    async fn delete_user(
+        &self,
+        id: Uuid,
+        expected_version: Option<u64>,
+    ) -> Result<(), ServiceError>;
+
+    async fn restore_user(&self, id: Uuid) -> Result<service::idos::User, ServiceError>;
+
+    // send_email_verification mails the user a new token to verify their email with, for when
+    // the one they were sent has expired or gone missing
+    async fn send_email_verification(&self, id: Uuid) -> Result<(), ServiceError>;
+
+    // change_password checks current against the stored password first, unless it is None
+    async fn change_password(
+        &self,
+        id: Uuid,
+        current: Option<service::idos::Password>,
+        new: service::idos::Password,
+    ) -> Result<(), ServiceError>;
+
+    // purge_deleted_users permanently removes users soft deleted at or before deleted_before,
+    // returning the ids of the purged users
+    async fn purge_deleted_users(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<Vec<Uuid>, ServiceError>;
+}
+
+// Sessions ends the sessions users were given on login, so they have to log in again
+#[async_trait::async_trait]
+pub trait Sessions: Send + Sync + 'static {
+    async fn revoke_user_sessions(&self, id: Uuid) -> Result<(), ServiceError>;
 }
 
 #[derive(Clone)]
@@ -37,14 +96,21 @@ pub struct App {
     scope: String,
     state: State,
     service: Arc<dyn Service>,
+    sessions: Arc<dyn Sessions>,
 }
 
 impl App {
-    pub fn new(scope: String, service: Arc<dyn Service>) -> Self {
+    pub fn new(
+        scope: String,
+        state: State,
+        service: Arc<dyn Service>,
+        sessions: Arc<dyn Sessions>,
+    ) -> Self {
         Self {
             scope,
-            state: State::new(),
-            service: service,
+            state,
+            service,
+            sessions,
         }
     }
 
@@ -52,17 +118,26 @@ impl App {
         let scope = self.scope.clone();
         let state = self.state.clone();
         let service: Arc<dyn Service + 'static> = self.service.clone();
+        let sessions: Arc<dyn Sessions + 'static> = self.sessions.clone();
 
         move |cfg: &mut web::ServiceConfig| {
             cfg.service(
                 web::scope(&scope)
+                    .wrap(from_fn(auth::middleware))
                     .app_data(web::Data::new(state))
                     .app_data::<actix_web::web::Data<Arc<dyn Service>>>(web::Data::new(service))
+                    .app_data::<web::Data<Arc<dyn Sessions>>>(web::Data::new(sessions))
                     .service(create_user)
+                    .service(purge_deleted_users)
                     .service(get_user_by_id)
-                    .service(get_user_by_email)
-                    .service(update_user)
-                    .service(delete_user),
+                    .service(get_users)
+                    .service(replace_user)
+                    .service(patch_user)
+                    .service(delete_user)
+                    .service(restore_user)
+                    .service(change_password)
+                    .service(send_email_verification)
+                    .service(revoke_sessions),
             );
         }
     }
@@ -70,129 +145,989 @@ impl App {
 
 #[post("")]
 async fn create_user(
+    req: HttpRequest,
+    principal: auth::Principal,
     service: web::Data<Arc<dyn Service>>,
     user: web::Json<ReqUserCreation>,
 ) -> impl Responder {
-    let new_user_req: ReqUserCreation = user.into_inner();
-
-    let parsed_dob = NaiveDate::parse_from_str(&new_user_req.dob, "%Y-%m-%d");
-    match parsed_dob {
-        Err(e) => {
-            println!("Failed to parse date: {}", e);
-            // send back 400
-            return HttpResponse::BadRequest().body("could not parse dob");
-        }
-        Ok(_) => println!("good date"),
+    if let Err(denied) = policy::authorize(&principal, Action::Create) {
+        return forbidden(&req, denied);
     }
 
-    let new_user = service::idos::User::new(
-        new_user_req.first_name,
-        new_user_req.last_name,
-        new_user_req.email,
-        parsed_dob.unwrap(),
-    );
+    let (new_user, password) = match user.into_inner().into_user() {
+        Ok(new_user) => new_user,
+        Err(errors) => return from_service_error(&req, ServiceError::InvalidFields(errors)),
+    };
 
     // pass new user to service.create_user
-    let created_user = match service.create_user(new_user.clone()).await {
+    let created_user = match service.create_user(new_user, password).await {
         Ok(user) => user,
         Err(e) => {
-            println!("Failed to create user: {}", e);
-            return from_service_error(e);
+            // from_service_error logs internal errors, anything else is the client's to fix
+            if !matches!(e, ServiceError::Internal) {
+                tracing::info!("Rejected user creation: {}", e);
+            }
+            return from_service_error(&req, e);
         }
     };
 
+    request_id::record_user_id(&created_user.id);
+
     HttpResponse::Created()
         .content_type("application/json")
+        .insert_header(header::ETag(etag(&created_user)))
         .body(serde_json::to_string(&created_user).unwrap_or_else(|_| "{}".to_string()))
 }
 
 #[get("/{id}")]
 async fn get_user_by_id(
+    req: HttpRequest,
+    principal: auth::Principal,
     service: web::Data<Arc<dyn Service>>,
-    user_id: web::Path<String>,
+    user_id: web::Path<Uuid>,
 ) -> impl Responder {
-    // this next step can fail if the string provided is not a valid uuid
-    let user_uuid_str: &String = &user_id.into_inner();
-    let user_uuid = match uuid::Uuid::parse_str(user_uuid_str) {
-        Ok(uuid) => uuid,
-        Err(_) => {
-            return HttpResponse::BadRequest()
-                .content_type("application/json")
-                .body({
-                    serde_json::to_string(&serde_json::json!({
-                        "message": "Invalid UUID format"
-                    }))
-                    .unwrap_or_else(|_| "{}".to_string())
-                });
-        }
-    };
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::Read(user_uuid)) {
+        return forbidden(&req, denied);
+    }
 
     match service.get_user(user_uuid).await {
         Ok(user) => HttpResponse::Ok()
             .content_type("application/json")
+            .insert_header(header::ETag(etag(&user)))
             .body(serde_json::to_string(&user).unwrap_or_else(|_| "{}".to_string())),
-        Err(e) => from_service_error(e),
+        Err(e) => from_service_error(&req, e),
     }
 }
 
 #[get("")]
-async fn get_user_by_email(
+async fn get_users(
+    req: HttpRequest,
+    principal: auth::Principal,
     service: web::Data<Arc<dyn Service>>,
-    email: web::Query<QueryUser>,
+    state: web::Data<State>,
+    query: web::Query<QueryUser>,
 ) -> impl Responder {
-    let email_str_opt = email.into_inner().email;
+    let query = query.into_inner();
 
-    let email_str = match email_str_opt {
-        Some(email) => email,
-        None => return HttpResponse::BadRequest().body("Email query parameter is required"),
+    let action = match query.email {
+        Some(_) => Action::ReadByEmail,
+        None => Action::List,
     };
+    if let Err(denied) = policy::authorize(&principal, action) {
+        return forbidden(&req, denied);
+    }
 
-    match service.get_user_by_email(&email_str).await {
+    // an email query is a point lookup, otherwise we list a page of users
+    if let Some(email) = query.email {
+        return get_user_by_email(&req, service, email).await;
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(state.default_page_size)
+        .min(state.max_page_size);
+
+    let list_users = match query.into_list_users(limit) {
+        Ok(list_users) => list_users,
+        Err(e) => return from_service_error(&req, ServiceError::Validation(e)),
+    };
+
+    match service.list_users(list_users).await {
+        Ok(page) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(serde_json::to_string(&page).unwrap_or_else(|_| "{}".to_string())),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+async fn get_user_by_email(
+    req: &HttpRequest,
+    service: web::Data<Arc<dyn Service>>,
+    email: service::idos::Email,
+) -> HttpResponse {
+    match service.get_user_by_email(&email).await {
         Ok(user) => HttpResponse::Ok()
             .content_type("application/json")
+            .insert_header(header::ETag(etag(&user)))
             .body(serde_json::to_string(&user).unwrap_or_else(|_| "{}".to_string())),
-        Err(e) => from_service_error(e),
+        Err(e) => from_service_error(req, e),
     }
 }
 
+// PUT replaces every field of the user, partial updates go through PATCH
 #[put("/{id}")]
-async fn update_user(
+async fn replace_user(
+    req: HttpRequest,
+    principal: auth::Principal,
     service: web::Data<Arc<dyn Service>>,
-    user_id: web::Path<String>,
-    user: web::Json<ReqUserUpdate>,
+    user_id: web::Path<Uuid>,
+    user: web::Json<ReqUserCreation>,
 ) -> impl Responder {
-    let user_uuid_str = &user_id.into_inner();
-    let user_uuid = match uuid::Uuid::parse_str(user_uuid_str) {
-        Ok(uuid) => uuid,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid UUID format"),
-    };
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::Update(user_uuid)) {
+        return forbidden(&req, denied);
+    }
 
-    let user_update = match user.into_inner().into_update() {
+    let user_update = match user.into_inner().into_replacement() {
         Ok(update) => update,
-        Err(e) => return HttpResponse::BadRequest().body(e),
+        Err(errors) => return from_service_error(&req, ServiceError::InvalidFields(errors)),
+    };
+
+    let expected_version = match expected_version(&req) {
+        Ok(expected_version) => expected_version,
+        Err(e) => return update_error(&req, &service, user_uuid, e).await,
     };
 
-    match service.update_user(user_uuid, user_update).await {
+    match service
+        .update_user(user_uuid, user_update, expected_version)
+        .await
+    {
         Ok(updated_user) => HttpResponse::Ok()
             .content_type("application/json")
+            .insert_header(header::ETag(etag(&updated_user)))
             .body(serde_json::to_string(&updated_user).unwrap_or_else(|_| "{}".to_string())),
-        Err(e) => from_service_error(e),
+        Err(e) => update_error(&req, &service, user_uuid, e).await,
     }
 }
 
+#[patch("/{id}")]
+async fn patch_user(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    user_id: web::Path<Uuid>,
+    body: web::Bytes,
+) -> impl Responder {
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::Update(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    let expected_version = match expected_version(&req) {
+        Ok(expected_version) => expected_version,
+        Err(e) => return update_error(&req, &service, user_uuid, e).await,
+    };
+
+    let content_type = req.mime_type().ok().flatten();
+    let patched = match content_type.as_ref().map(|mime| mime.essence_str()) {
+        Some(MERGE_PATCH) => merge_patch_update(&body).map(|update| (update, expected_version)),
+        Some(JSON_PATCH) => json_patch_update(&service, user_uuid, &body, expected_version).await,
+        _ => {
+            let mut resp = unsupported_media_type(
+                &req,
+                format!(
+                    "PATCH requires a Content-Type of {} or {}",
+                    MERGE_PATCH, JSON_PATCH
+                ),
+            );
+            resp.headers_mut().insert(
+                HeaderName::from_static("accept-patch"),
+                HeaderValue::from_static(
+                    "application/merge-patch+json, application/json-patch+json",
+                ),
+            );
+            return resp;
+        }
+    };
+
+    let (user_update, expected_version) = match patched {
+        Ok(patched) => patched,
+        Err(e) => return update_error(&req, &service, user_uuid, e).await,
+    };
+
+    match service
+        .update_user(user_uuid, user_update, expected_version)
+        .await
+    {
+        Ok(updated_user) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(header::ETag(etag(&updated_user)))
+            .body(serde_json::to_string(&updated_user).unwrap_or_else(|_| "{}".to_string())),
+        Err(e) => update_error(&req, &service, user_uuid, e).await,
+    }
+}
+
+// a deleted user is logged out everywhere, so their sessions cannot outlive them
 #[delete("/{id}")]
 async fn delete_user(
+    req: HttpRequest,
+    principal: auth::Principal,
     service: web::Data<Arc<dyn Service>>,
-    user_id: web::Path<String>,
+    sessions: web::Data<Arc<dyn Sessions>>,
+    user_id: web::Path<Uuid>,
 ) -> impl Responder {
-    let user_uuid_str = &user_id.into_inner();
-    let user_uuid = match uuid::Uuid::parse_str(user_uuid_str) {
-        Ok(uuid) => uuid,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid UUID format"),
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::Delete(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    let expected_version = match expected_version(&req) {
+        Ok(expected_version) => expected_version,
+        Err(e) => return update_error(&req, &service, user_uuid, e).await,
     };
 
-    match service.delete_user(user_uuid).await {
+    if let Err(e) = service.delete_user(user_uuid, expected_version).await {
+        return update_error(&req, &service, user_uuid, e).await;
+    }
+
+    // the user is deleted whether or not this works, and failing the request would only have
+    // the client retry a delete that has already happened
+    if let Err(e) = sessions.revoke_user_sessions(user_uuid).await {
+        tracing::error!("Failed to revoke sessions after deleting user: {}", e);
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+// revoking sessions stops refresh tokens working, access tokens already issued stay valid until
+// they expire
+#[delete("/{id}/sessions")]
+async fn revoke_sessions(
+    req: HttpRequest,
+    principal: auth::Principal,
+    sessions: web::Data<Arc<dyn Sessions>>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::RevokeSessions(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    match sessions.revoke_user_sessions(user_uuid).await {
         Ok(_) => HttpResponse::NoContent().finish(),
-        Err(e) => from_service_error(e),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+#[post("/{id}/restore")]
+async fn restore_user(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::Restore(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    match service.restore_user(user_uuid).await {
+        Ok(restored_user) => HttpResponse::Ok()
+            .content_type("application/json")
+            .insert_header(header::ETag(etag(&restored_user)))
+            .body(serde_json::to_string(&restored_user).unwrap_or_else(|_| "{}".to_string())),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+// the token is mailed rather than returned, as using it proves the user receives mail at the
+// address
+#[post("/{id}/email-verification")]
+async fn send_email_verification(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    user_id: web::Path<Uuid>,
+) -> impl Responder {
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::SendEmailVerification(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    match service.send_email_verification(user_uuid).await {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+// users must give their current password to change it, admins may set one without it
+#[put("/{id}/password")]
+async fn change_password(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    sessions: web::Data<Arc<dyn Sessions>>,
+    user_id: web::Path<Uuid>,
+    body: web::Json<ReqPasswordChange>,
+) -> impl Responder {
+    let user_uuid = user_id.into_inner();
+    request_id::record_user_id(&user_uuid);
+
+    if let Err(denied) = policy::authorize(&principal, Action::ChangePassword(user_uuid)) {
+        return forbidden(&req, denied);
+    }
+
+    let (current, new) = match body.into_inner().into_passwords() {
+        Ok(passwords) => passwords,
+        Err(errors) => return from_service_error(&req, ServiceError::InvalidFields(errors)),
+    };
+
+    if current.is_none() && !policy::is_admin(&principal) {
+        return from_service_error(
+            &req,
+            ServiceError::MissingParameters("current_password must be populated".to_string()),
+        );
+    }
+
+    if let Err(e) = service.change_password(user_uuid, current, new).await {
+        return from_service_error(&req, e);
+    }
+
+    // whoever knew the old password may have logged in with it, so every session is ended. The
+    // password has been changed either way, and the user can revoke their sessions themselves.
+    if let Err(e) = sessions.revoke_user_sessions(user_uuid).await {
+        tracing::error!("Failed to revoke sessions after password change: {}", e);
+    }
+
+    HttpResponse::NoContent().finish()
+}
+
+// purges every user that has been soft deleted for longer than the retention period
+#[post("/purge")]
+async fn purge_deleted_users(
+    req: HttpRequest,
+    principal: auth::Principal,
+    service: web::Data<Arc<dyn Service>>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = policy::authorize(&principal, Action::Purge) {
+        return forbidden(&req, denied);
+    }
+
+    let deleted_before = Utc::now() - state.purge_retention;
+
+    match service.purge_deleted_users(deleted_before).await {
+        Ok(purged) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(json!({ "purged": purged }).to_string()),
+        Err(e) => from_service_error(&req, e),
+    }
+}
+
+// the user's version is its entity tag, so an If-Match can be checked against the stored version
+fn etag(user: &service::idos::User) -> EntityTag {
+    EntityTag::new_strong(user.version.to_string())
+}
+
+// update_error adds the user's current entity tag to a 412, so the client can tell which version
+// it has fallen behind without reading the user again
+async fn update_error(
+    req: &HttpRequest,
+    service: &web::Data<Arc<dyn Service>>,
+    id: Uuid,
+    err: ServiceError,
+) -> HttpResponse {
+    let precondition_failed = matches!(err, ServiceError::PreconditionFailed(_));
+    let mut resp = from_service_error(req, err);
+
+    if precondition_failed
+        && let Ok(user) = service.get_user(id).await
+        && let Ok(value) = HeaderValue::from_str(&etag(&user).to_string())
+    {
+        resp.headers_mut().insert(header::ETAG, value);
+    }
+
+    resp
+}
+
+// expected_version reads the If-Match header, where both "*" and no header at all place no
+// constraint on the version being modified
+fn expected_version(req: &HttpRequest) -> Result<Option<u64>, ServiceError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+
+    match header::IfMatch::parse(req) {
+        Ok(header::IfMatch::Any) => Ok(None),
+        Ok(header::IfMatch::Items(tags)) => match tags.as_slice() {
+            // If-Match uses the strong comparison, so weak tags and tags we never issued can
+            // never match
+            [tag] => match tag.tag().parse::<u64>() {
+                Ok(version) if !tag.weak => Ok(Some(version)),
+                _ => Err(ServiceError::PreconditionFailed(format!(
+                    "entity tag {} does not match",
+                    tag
+                ))),
+            },
+            _ => Err(ServiceError::Validation(
+                "If-Match must contain a single entity tag".to_string(),
+            )),
+        },
+        Err(_) => Err(ServiceError::Validation(
+            "malformed If-Match header".to_string(),
+        )),
+    }
+}
+
+fn merge_patch_update(body: &[u8]) -> Result<service::idos::UserUpdate, ServiceError> {
+    let patch: ReqUserUpdate = serde_json::from_slice(body)
+        .map_err(|e| ServiceError::Validation(format!("invalid merge patch: {}", e)))?;
+
+    patch.into_update().map_err(ServiceError::InvalidFields)
+}
+
+// json_patch_update applies a JSON Patch to the user's current state. Operations such as test
+// depend on that state, so the update is made conditional on the version that was patched
+async fn json_patch_update(
+    service: &web::Data<Arc<dyn Service>>,
+    id: Uuid,
+    body: &[u8],
+    expected_version: Option<u64>,
+) -> Result<(service::idos::UserUpdate, Option<u64>), ServiceError> {
+    let patch: json_patch::Patch = serde_json::from_slice(body)
+        .map_err(|e| ServiceError::Validation(format!("invalid json patch: {}", e)))?;
+
+    let user = service.get_user(id).await?;
+    if let Some(expected) = expected_version
+        && user.version != expected
+    {
+        return Err(ServiceError::PreconditionFailed(format!(
+            "user is at version {}, expected {}",
+            user.version, expected
+        )));
+    }
+
+    let mut document = json!({
+        "first_name": user.first_name,
+        "last_name": user.last_name,
+        "email": user.email.as_str(),
+        "dob": user.dob.format("%Y-%m-%d").to_string(),
+    });
+
+    json_patch::patch(&mut document, &patch)
+        .map_err(|e| ServiceError::Validation(format!("patch could not be applied: {}", e)))?;
+
+    if let Some(field) = document.as_object().and_then(|fields| {
+        fields
+            .keys()
+            .find(|field| !PATCHABLE_FIELDS.contains(&field.as_str()))
+    }) {
+        return Err(ServiceError::Validation(format!(
+            "{} is not a field of user",
+            field
+        )));
+    }
+
+    let replacement: ReqUserCreation = serde_json::from_value(document)
+        .map_err(|e| ServiceError::Validation(format!("patched user is invalid: {}", e)))?;
+
+    let update = replacement
+        .into_replacement()
+        .map_err(ServiceError::InvalidFields)?;
+
+    Ok((update, Some(user.version)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_keys;
+    use crate::config;
+    use crate::notifications;
+    use crate::problem;
+    use crate::sessions;
+    use crate::users::repo::memory;
+
+    use actix_web::body::MessageBody;
+    use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web::JsonConfig;
+    use chrono::TimeDelta;
+    use jsonwebtoken::EncodingKey;
+    use serde_json::Value;
+
+    const SECRET: &str = "a secret of at least thirty-two bytes";
+
+    // Fixture runs the users app over the memory repos, as main does when configured without
+    // DynamoDB
+    struct Fixture {
+        service: Arc<service::core::Service<memory::Repo>>,
+        sessions: Arc<sessions::service::core::Service<sessions::repo::memory::Repo>>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let mailer = notifications::log::Mailer::new("users@example.com".to_string(), None);
+
+            Self {
+                service: Arc::new(service::core::Service::new(
+                    memory::Repo::new(),
+                    Arc::new(mailer),
+                    TimeDelta::days(1),
+                    TimeDelta::hours(1),
+                )),
+                sessions: Arc::new(sessions::service::core::Service::new(
+                    sessions::repo::memory::Repo::new(),
+                    TimeDelta::days(1),
+                )),
+            }
+        }
+
+        fn app(
+            &self,
+        ) -> actix_web::App<
+            impl ServiceFactory<
+                ServiceRequest,
+                Config = (),
+                Response = ServiceResponse<impl MessageBody + use<>>,
+                Error = actix_web::Error,
+                InitError = (),
+            > + use<>,
+        > {
+            let auth = config::core::Auth {
+                hs256_secret: Some(SECRET.to_string()),
+                ..Default::default()
+            };
+            let api_keys = Arc::new(api_keys::service::core::Service::new(
+                api_keys::repo::memory::Repo::new(),
+            ));
+            let authenticator = auth::Authenticator::new(&auth, api_keys).unwrap();
+
+            let users = App::new(
+                "/api/users".to_string(),
+                State::new(10, 100, TimeDelta::days(30)),
+                self.service.clone(),
+                self.sessions.clone(),
+            );
+
+            actix_web::App::new()
+                .app_data(web::Data::new(authenticator))
+                .app_data(JsonConfig::default().error_handler(problem::json_error_handler))
+                .configure(users.configure())
+        }
+    }
+
+    fn token(subject: &str, scope: &str) -> String {
+        let claims = json!({
+            "sub": subject,
+            "scope": scope,
+            "exp": Utc::now().timestamp() + 600,
+        });
+
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn as_admin(req: TestRequest) -> TestRequest {
+        req.insert_header((
+            header::AUTHORIZATION,
+            format!("Bearer {}", token("admin", "users:admin")),
+        ))
+    }
+
+    fn as_user(req: TestRequest, id: &str) -> TestRequest {
+        req.insert_header((header::AUTHORIZATION, format!("Bearer {}", token(id, ""))))
+    }
+
+    fn new_user(email: &str) -> Value {
+        json!({
+            "first_name": "Ada",
+            "last_name": "Lovelace",
+            "email": email,
+            "dob": "1990-12-10",
+        })
+    }
+
+    // problem_type reads the type of an RFC 7807 response, which says which error it was
+    async fn problem_type(resp: ServiceResponse<impl MessageBody>) -> String {
+        let body: Value = test::read_body_json(resp).await;
+        body["type"].as_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn creates_and_gets_a_user() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"1\"");
+        let created: Value = test::read_body_json(resp).await;
+
+        let id = created["id"].as_str().unwrap();
+        let req = as_user(TestRequest::get().uri(&format!("/api/users/{}", id)), id).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let fetched: Value = test::read_body_json(resp).await;
+
+        assert_eq!(fetched, created);
+        assert_eq!(fetched["email"], "ada@example.com");
+    }
+
+    #[actix_web::test]
+    async fn rejects_an_email_already_in_use() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CREATED
+        );
+
+        // uniqueness is decided on the canonical form, so a change of case is the same address
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ADA@Example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(problem_type(resp).await, "/problems/user-conflict");
+    }
+
+    #[actix_web::test]
+    async fn updating_a_missing_user_is_not_found() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::put().uri(&format!("/api/users/{}", Uuid::new_v4())))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(problem_type(resp).await, "/problems/user-not-found");
+    }
+
+    #[actix_web::test]
+    async fn deleting_a_missing_user_is_not_found() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::delete().uri(&format!("/api/users/{}", Uuid::new_v4())))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(problem_type(resp).await, "/problems/user-not-found");
+    }
+
+    #[actix_web::test]
+    async fn lists_users_a_page_at_a_time() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        for email in ["a@example.com", "b@example.com", "c@example.com"] {
+            let req = as_admin(TestRequest::post().uri("/api/users"))
+                .set_json(new_user(email))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                StatusCode::CREATED
+            );
+        }
+
+        let req = as_admin(TestRequest::get().uri("/api/users?limit=2")).to_request();
+        let first: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(first["users"].as_array().unwrap().len(), 2);
+        let cursor = first["next_cursor"].as_str().unwrap();
+
+        let req =
+            as_admin(TestRequest::get().uri(&format!("/api/users?limit=2&cursor={}", cursor)))
+                .to_request();
+        let second: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(second["users"].as_array().unwrap().len(), 1);
+        assert!(second["next_cursor"].is_null());
+
+        let mut emails: Vec<&str> = first["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .chain(second["users"].as_array().unwrap())
+            .map(|user| user["email"].as_str().unwrap())
+            .collect();
+        emails.sort();
+        assert_eq!(emails, ["a@example.com", "b@example.com", "c@example.com"]);
+    }
+
+    #[actix_web::test]
+    async fn changing_a_password_ends_the_users_sessions() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        let refresh_token = auth::Sessions::start(fixture.sessions.as_ref(), id)
+            .await
+            .unwrap();
+
+        let req = as_admin(TestRequest::put().uri(&format!("/api/users/{}/password", id)))
+            .set_json(json!({ "new_password": "a brand new password" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        assert!(matches!(
+            auth::Sessions::refresh(fixture.sessions.as_ref(), &refresh_token).await,
+            Err(auth::Error::InvalidRefreshToken)
+        ));
+    }
+
+    // replace builds an admin's PUT of the user, with If-Match set to if_match when given
+    fn replace(id: &str, if_match: Option<&str>) -> TestRequest {
+        let req = as_admin(TestRequest::put().uri(&format!("/api/users/{}", id)))
+            .set_json(new_user("ada@example.com"));
+
+        match if_match {
+            Some(if_match) => req.insert_header((header::IF_MATCH, if_match)),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn if_match_is_checked_against_the_version() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        // without If-Match, or with *, the update places no constraint on the version
+        for (if_match, etag) in [
+            (None, "\"2\""),
+            (Some("*"), "\"3\""),
+            (Some("\"3\""), "\"4\""),
+        ] {
+            let resp = test::call_service(&app, replace(id, if_match).to_request()).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{:?}", if_match);
+            assert_eq!(resp.headers().get(header::ETAG).unwrap(), etag);
+        }
+    }
+
+    #[actix_web::test]
+    async fn stale_if_match_fails_with_the_current_etag() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        let resp = test::call_service(&app, replace(id, Some("\"1\"")).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // If-Match uses the strong comparison, so a weak tag fails even for the current version
+        for if_match in ["\"1\"", "W/\"2\""] {
+            let resp = test::call_service(&app, replace(id, Some(if_match)).to_request()).await;
+            assert_eq!(
+                resp.status(),
+                StatusCode::PRECONDITION_FAILED,
+                "{}",
+                if_match
+            );
+            assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"2\"");
+            assert_eq!(problem_type(resp).await, "/problems/precondition-failed");
+        }
+
+        let req = as_admin(TestRequest::delete().uri(&format!("/api/users/{}", id)))
+            .insert_header((header::IF_MATCH, "\"1\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+        assert_eq!(resp.headers().get(header::ETAG).unwrap(), "\"2\"");
+    }
+
+    #[actix_web::test]
+    async fn if_match_must_carry_a_single_tag() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        let resp = test::call_service(&app, replace(id, Some("\"1\", \"2\"")).to_request()).await;
+
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(problem_type(resp).await, "/problems/invalid-request");
+    }
+
+    fn patch(id: &str, content_type: &str, body: Value) -> TestRequest {
+        as_admin(TestRequest::patch().uri(&format!("/api/users/{}", id)))
+            .insert_header((header::CONTENT_TYPE, content_type))
+            .set_payload(body.to_string())
+    }
+
+    #[test]
+    fn merge_patch_only_sets_the_fields_it_names() {
+        let update = merge_patch_update(br#"{"last_name": "King"}"#).unwrap();
+
+        assert_eq!(update.last_name.as_deref(), Some("King"));
+        assert!(update.first_name.is_none() && update.email.is_none() && update.dob.is_none());
+    }
+
+    #[test]
+    fn merge_patch_cannot_remove_fields() {
+        match merge_patch_update(br#"{"email": null}"#) {
+            Err(ServiceError::InvalidFields(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(
+                    (errors[0].field.as_str(), errors[0].code),
+                    ("email", "cannot_be_removed")
+                );
+            }
+            other => panic!("expected invalid fields, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn merge_patch_cannot_set_members_that_are_not_fields() {
+        for body in [
+            &br#"{"id": "00000000-0000-0000-0000-000000000000"}"#[..],
+            br#"{"version": 9}"#,
+        ] {
+            assert!(matches!(
+                merge_patch_update(body),
+                Err(ServiceError::Validation(_))
+            ));
+        }
+    }
+
+    #[actix_web::test]
+    async fn json_patch_applies_to_the_current_user() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        let ops = json!([
+            { "op": "test", "path": "/first_name", "value": "Ada" },
+            { "op": "replace", "path": "/first_name", "value": "Augusta" },
+        ]);
+        let resp = test::call_service(&app, patch(id, JSON_PATCH, ops).to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let patched: Value = test::read_body_json(resp).await;
+
+        assert_eq!(patched["first_name"], "Augusta");
+        assert_eq!(patched["last_name"], "Lovelace");
+        assert_eq!(patched["version"], 2);
+    }
+
+    #[actix_web::test]
+    async fn json_patch_can_only_touch_the_fields_of_a_user() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(new_user("ada@example.com"))
+            .to_request();
+        let created: Value = test::call_and_read_body_json(&app, req).await;
+        let id = created["id"].as_str().unwrap();
+
+        for ops in [
+            json!([{ "op": "add", "path": "/id", "value": Uuid::new_v4() }]),
+            json!([{ "op": "replace", "path": "/id", "value": Uuid::new_v4() }]),
+            json!([{ "op": "replace", "path": "/version", "value": 9 }]),
+            json!([{ "op": "add", "path": "/version", "value": 9 }]),
+            json!([{ "op": "remove", "path": "/email" }]),
+            json!([{ "op": "test", "path": "/first_name", "value": "Augusta" }]),
+        ] {
+            let resp =
+                test::call_service(&app, patch(id, JSON_PATCH, ops.clone()).to_request()).await;
+
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", ops);
+            assert_eq!(problem_type(resp).await, "/problems/invalid-request");
+        }
+
+        let req = as_admin(TestRequest::get().uri(&format!("/api/users/{}", id))).to_request();
+        let user: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(user, created);
+    }
+
+    #[actix_web::test]
+    async fn patch_requires_a_patch_content_type() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let id = Uuid::new_v4().to_string();
+        let req = patch(&id, "application/json", json!({ "first_name": "Augusta" }));
+        let resp = test::call_service(&app, req.to_request()).await;
+
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(resp.headers().contains_key("accept-patch"));
+    }
+
+    #[actix_web::test]
+    async fn reports_every_invalid_field_at_once() {
+        let fixture = Fixture::new();
+        let app = test::init_service(fixture.app()).await;
+
+        let req = as_admin(TestRequest::post().uri("/api/users"))
+            .set_json(json!({
+                "first_name": " ",
+                "last_name": "L0velace",
+                "email": "ada.example.com",
+                "dob": "2999-01-01",
+                "password": "short",
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let body: Value = test::read_body_json(resp).await;
+        assert_eq!(body["type"], "/problems/validation-failed");
+
+        let mut errors: Vec<(&str, &str)> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|error| {
+                (
+                    error["field"].as_str().unwrap(),
+                    error["code"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        errors.sort();
+        assert_eq!(
+            errors,
+            [
+                ("dob", "in_future"),
+                ("email", "invalid_email"),
+                ("first_name", "required"),
+                ("last_name", "invalid_characters"),
+                ("password", "too_short"),
+            ]
+        );
     }
 }
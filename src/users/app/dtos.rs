@@ -1,45 +1,321 @@
 use crate::users::service::idos;
-use chrono::NaiveDate;
-use serde::Deserialize;
+use crate::validation::{FieldError, Validator};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer};
 
 #[derive(Deserialize)]
 pub struct QueryUser {
-    pub email: Option<String>,
+    pub email: Option<idos::Email>,
+    pub limit: Option<usize>,
+    pub cursor: Option<String>,
+    pub last_name_prefix: Option<String>,
+    pub dob_from: Option<String>,
+    pub dob_to: Option<String>,
+    pub created_from: Option<String>,
+    pub created_to: Option<String>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
 }
 
+impl QueryUser {
+    pub fn into_list_users(self, limit: usize) -> Result<idos::ListUsers, String> {
+        let filter = idos::UserFilter {
+            last_name_prefix: self.last_name_prefix,
+            dob_from: parse_date("dob_from", self.dob_from)?,
+            dob_to: parse_date("dob_to", self.dob_to)?,
+            created_from: parse_datetime("created_from", self.created_from)?,
+            created_to: parse_datetime("created_to", self.created_to)?,
+            deleted: idos::DeletedFilter::Exclude,
+        };
+
+        let order = match self.order.as_deref() {
+            None | Some("asc") => idos::SortOrder::Asc,
+            Some("desc") => idos::SortOrder::Desc,
+            Some(_) => return Err("Invalid order, expected one of: asc, desc".to_string()),
+        };
+
+        let sort = match self.sort.as_deref() {
+            Some("created_at") => Some(idos::Sort {
+                field: idos::SortField::CreatedAt,
+                order,
+            }),
+            Some("last_name") => Some(idos::Sort {
+                field: idos::SortField::LastName,
+                order,
+            }),
+            Some(_) => {
+                return Err("Invalid sort, expected one of: created_at, last_name".to_string());
+            }
+            None if self.order.is_some() => {
+                return Err("order can only be used together with sort".to_string());
+            }
+            None => None,
+        };
+
+        Ok(idos::ListUsers {
+            limit,
+            cursor: self.cursor,
+            filter,
+            sort,
+        })
+    }
+}
+
+// the password is optional, users created without one cannot log in until it is set
 #[derive(Deserialize)]
 pub struct ReqUserCreation {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
     pub dob: String,
+    #[serde(default)]
+    pub password: Option<String>,
 }
 
+impl ReqUserCreation {
+    pub fn into_user(mut self) -> Result<(idos::User, Option<idos::Password>), Vec<FieldError>> {
+        let mut v = Validator::new();
+
+        let password = self
+            .password
+            .take()
+            .map(|password| v.password("password", password));
+        let fields = self.validate(&mut v);
+
+        match (fields, password) {
+            (Some((first_name, last_name, email, dob)), password) if v.is_valid() => Ok((
+                idos::User::new(first_name, last_name, email, dob),
+                password.flatten().map(idos::Password::new),
+            )),
+            _ => Err(v.into_errors()),
+        }
+    }
+
+    // into_replacement sets every field, for full replacement of an existing user
+    pub fn into_replacement(self) -> Result<idos::UserUpdate, Vec<FieldError>> {
+        let mut v = Validator::new();
+
+        v.not_replaceable("password", &self.password, "PUT /{id}/password");
+        let fields = self.validate(&mut v);
+
+        match fields {
+            Some((first_name, last_name, email, dob)) if v.is_valid() => Ok(idos::UserUpdate {
+                first_name: Some(first_name),
+                last_name: Some(last_name),
+                email: Some(email),
+                dob: Some(dob),
+            }),
+            _ => Err(v.into_errors()),
+        }
+    }
+
+    fn validate(self, v: &mut Validator) -> Option<(String, String, idos::Email, NaiveDate)> {
+        let first_name = v.name("first_name", self.first_name);
+        let last_name = v.name("last_name", self.last_name);
+        let email = v.email("email", &self.email);
+        let dob = v.dob("dob", &self.dob);
+
+        // a rule only returns None after recording an error
+        match (first_name, last_name, email, dob) {
+            (Some(first_name), Some(last_name), Some(email), Some(dob)) => {
+                Some((first_name, last_name, email, dob))
+            }
+            _ => None,
+        }
+    }
+}
+
+// ReqPasswordChange must carry the current password unless the caller is an admin, who may set
+// a password for users that have none or have forgotten theirs
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReqPasswordChange {
+    #[serde(default)]
+    pub current_password: Option<String>,
+    pub new_password: String,
+}
+
+impl ReqPasswordChange {
+    pub fn into_passwords(
+        self,
+    ) -> Result<(Option<idos::Password>, idos::Password), Vec<FieldError>> {
+        let mut v = Validator::new();
+
+        // the current password is only compared with the stored hash, so it is not held to the
+        // rules a new one must meet
+        let current = self.current_password.map(idos::Password::new);
+
+        match v.password("new_password", self.new_password) {
+            Some(new) => Ok((current, idos::Password::new(new))),
+            None => Err(v.into_errors()),
+        }
+    }
+}
+
+// ReqUserUpdate is a JSON Merge Patch (RFC 7396) document: an absent field is left unchanged
+// while a null asks for the field to be removed, which none of the user's fields allow
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ReqUserUpdate {
-    pub first_name: Option<String>,
-    pub last_name: Option<String>,
-    pub email: Option<String>,
-    pub dob: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub first_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub last_name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub email: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub dob: Option<Option<String>>,
 }
 
 impl ReqUserUpdate {
-    pub fn into_update(self) -> Result<idos::UserUpdate, String> {
-        let dob = match self.dob {
-            Some(date_str) => match NaiveDate::parse_from_str(&date_str, "%Y-%m-%d") {
-                Ok(date) => Some(date),
-                Err(_) => {
-                    return Err("Invalid date format for dob, expected YYYY-MM-DD".to_string());
-                }
-            },
-            None => None,
+    pub fn into_update(self) -> Result<idos::UserUpdate, Vec<FieldError>> {
+        let mut v = Validator::new();
+
+        let first_name = v.present("first_name", self.first_name);
+        let last_name = v.present("last_name", self.last_name);
+        let email = v.present("email", self.email);
+        let dob = v.present("dob", self.dob);
+
+        let update = idos::UserUpdate {
+            first_name: first_name.and_then(|value| v.name("first_name", value)),
+            last_name: last_name.and_then(|value| v.name("last_name", value)),
+            email: email.and_then(|value| v.email("email", &value)),
+            dob: dob.and_then(|value| v.dob("dob", &value)),
         };
 
-        Ok(idos::UserUpdate {
-            first_name: self.first_name,
-            last_name: self.last_name,
-            email: self.email,
-            dob,
-        })
+        if !v.is_valid() {
+            return Err(v.into_errors());
+        }
+
+        Ok(update)
+    }
+}
+
+// present is only called for fields in the document, so wrapping the value in Some lets an
+// explicit null be told apart from a missing field
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn parse_date(name: &str, value: Option<String>) -> Result<Option<NaiveDate>, String> {
+    match value {
+        Some(date_str) => match NaiveDate::parse_from_str(&date_str, "%Y-%m-%d") {
+            Ok(date) => Ok(Some(date)),
+            Err(_) => Err(format!(
+                "Invalid date format for {}, expected YYYY-MM-DD",
+                name
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_datetime(name: &str, value: Option<String>) -> Result<Option<DateTime<Utc>>, String> {
+    match value {
+        Some(datetime_str) => match DateTime::parse_from_rfc3339(&datetime_str) {
+            Ok(datetime) => Ok(Some(datetime.with_timezone(&Utc))),
+            Err(_) => Err(format!(
+                "Invalid timestamp format for {}, expected RFC 3339",
+                name
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(errors: &[FieldError]) -> Vec<(&str, &str)> {
+        errors
+            .iter()
+            .map(|error| (error.field.as_str(), error.code))
+            .collect()
+    }
+
+    #[test]
+    fn merge_patch_leaves_absent_fields_alone() {
+        let patch: ReqUserUpdate = serde_json::from_str(r#"{"first_name": "Augusta"}"#).unwrap();
+
+        assert_eq!(patch.first_name, Some(Some("Augusta".to_string())));
+        assert_eq!(patch.last_name, None);
+
+        let update = patch.into_update().unwrap();
+        assert_eq!(update.first_name.as_deref(), Some("Augusta"));
+        assert!(update.last_name.is_none() && update.email.is_none() && update.dob.is_none());
+    }
+
+    #[test]
+    fn merge_patch_null_asks_to_remove_the_field() {
+        let patch: ReqUserUpdate =
+            serde_json::from_str(r#"{"last_name": null, "dob": null}"#).unwrap();
+
+        assert_eq!(patch.last_name, Some(None));
+
+        let errors = patch.into_update().unwrap_err();
+        assert_eq!(
+            codes(&errors),
+            [
+                ("last_name", "cannot_be_removed"),
+                ("dob", "cannot_be_removed")
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_patch_rejects_members_that_are_not_fields() {
+        for patch in [
+            r#"{"id": "00000000-0000-0000-0000-000000000000"}"#,
+            r#"{"version": 7}"#,
+            r#"{"first_name": "Augusta", "password": "a new password"}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<ReqUserUpdate>(patch).is_err(),
+                "{}",
+                patch
+            );
+        }
+    }
+
+    #[test]
+    fn replacement_sets_every_field() {
+        let user: ReqUserCreation = serde_json::from_str(
+            r#"{"first_name": "Ada", "last_name": "Lovelace", "email": "ada@example.com", "dob": "1990-12-10"}"#,
+        )
+        .unwrap();
+
+        let update = user.into_replacement().unwrap();
+
+        assert_eq!(update.first_name.as_deref(), Some("Ada"));
+        assert_eq!(update.last_name.as_deref(), Some("Lovelace"));
+        assert_eq!(update.email.unwrap().as_str(), "ada@example.com");
+        assert_eq!(update.dob, NaiveDate::from_ymd_opt(1990, 12, 10));
+    }
+
+    #[test]
+    fn replacement_requires_every_field() {
+        let user = serde_json::from_str::<ReqUserCreation>(
+            r#"{"first_name": "Ada", "email": "ada@example.com", "dob": "1990-12-10"}"#,
+        );
+
+        assert!(user.is_err());
+    }
+
+    #[test]
+    fn replacement_refuses_a_password() {
+        let user: ReqUserCreation = serde_json::from_str(
+            r#"{"first_name": "Ada", "last_name": "Lovelace", "email": "ada@example.com", "dob": "1990-12-10", "password": "a new password"}"#,
+        )
+        .unwrap();
+
+        let errors = user.into_replacement().unwrap_err();
+
+        assert_eq!(codes(&errors), [("password", "not_replaceable")]);
+        assert!(errors[0].message.contains("PUT /{id}/password"));
     }
 }
@@ -1,70 +1,93 @@
+use super::policy::Denied;
+use crate::problem::{self, Problem, ProblemType};
 use crate::users::service::errors;
-use actix_web::{HttpResponse, error::JsonPayloadError};
-use serde::Serialize;
-use serde_json::json;
 
-#[derive(Serialize)]
-struct Error {
-    message: String,
-}
+use actix_web::http::StatusCode;
+use actix_web::{HttpRequest, HttpResponse};
+
+// the catalogue of problem types for service errors, one per variant of errors::Error, along
+// with the shared entries in problem
+
+pub const USER_NOT_FOUND: ProblemType = ProblemType {
+    uri: "/problems/user-not-found",
+    title: "User not found",
+    status: StatusCode::NOT_FOUND,
+};
+
+pub const USER_DELETED: ProblemType = ProblemType {
+    uri: "/problems/user-deleted",
+    title: "User has been deleted",
+    status: StatusCode::GONE,
+};
+
+pub const MISSING_PARAMETERS: ProblemType = ProblemType {
+    uri: "/problems/missing-parameters",
+    title: "Missing parameters",
+    status: StatusCode::BAD_REQUEST,
+};
 
-pub fn from_service_error(svc_err: errors::Error) -> HttpResponse {
+pub const USER_CONFLICT: ProblemType = ProblemType {
+    uri: "/problems/user-conflict",
+    title: "Conflicting user",
+    status: StatusCode::CONFLICT,
+};
+
+pub const PRECONDITION_FAILED: ProblemType = ProblemType {
+    uri: "/problems/precondition-failed",
+    title: "Precondition failed",
+    status: StatusCode::PRECONDITION_FAILED,
+};
+
+pub const INCORRECT_PASSWORD: ProblemType = ProblemType {
+    uri: "/problems/incorrect-password",
+    title: "Incorrect password",
+    status: StatusCode::FORBIDDEN,
+};
+
+pub fn problem_type(svc_err: &errors::Error) -> &'static ProblemType {
     match svc_err {
-        errors::Error::NotFound => {
-            let err = Error {
-                message: svc_err.to_string(),
-            };
-
-            HttpResponse::NotFound()
-                .content_type("application/json")
-                .body(serde_json::to_string(&err).unwrap_or_else(|_| "{}".to_string()))
-        }
-        errors::Error::Validation(e) | errors::Error::MissingParameters(e) => {
-            let err = Error { message: e };
+        errors::Error::NotFound => &USER_NOT_FOUND,
+        errors::Error::Deleted => &USER_DELETED,
+        errors::Error::Validation(_) => &problem::INVALID_REQUEST,
+        errors::Error::InvalidFields(_) => &problem::VALIDATION_FAILED,
+        errors::Error::MissingParameters(_) => &MISSING_PARAMETERS,
+        errors::Error::ConflictingUser(_) => &USER_CONFLICT,
+        errors::Error::PreconditionFailed(_) => &PRECONDITION_FAILED,
+        errors::Error::IncorrectPassword => &INCORRECT_PASSWORD,
+        errors::Error::Internal => &problem::INTERNAL_ERROR,
+    }
+}
+
+pub fn from_service_error(req: &HttpRequest, svc_err: errors::Error) -> HttpResponse {
+    let problem_type = problem_type(&svc_err);
 
-            HttpResponse::BadRequest()
-                .content_type("application/json")
-                .body(serde_json::to_string(&err).unwrap_or_else(|_| "{}".to_string()))
+    let problem = match svc_err {
+        errors::Error::NotFound | errors::Error::Deleted | errors::Error::IncorrectPassword => {
+            Problem::new(req, problem_type, Some(svc_err.to_string()))
         }
-        errors::Error::ConflictingUser(_) => {
-            let err = Error {
-                message: svc_err.to_string(),
-            };
-
-            HttpResponse::Conflict()
-                .content_type("application/json")
-                .body(serde_json::to_string(&err).unwrap_or_else(|_| "{}".to_string()))
+        errors::Error::Validation(e)
+        | errors::Error::MissingParameters(e)
+        | errors::Error::ConflictingUser(e)
+        | errors::Error::PreconditionFailed(e) => Problem::new(req, problem_type, Some(e)),
+        errors::Error::InvalidFields(field_errors) => {
+            return problem::validation_failed(req, field_errors);
         }
-        _ => {
+        errors::Error::Internal => {
             tracing::error!("Unhandled service error: {:?}", svc_err);
 
-            let err = Error {
-                message: format!("Internal error"),
-            };
-
-            HttpResponse::InternalServerError()
-                .content_type("application/json")
-                .body(serde_json::to_string(&err).unwrap_or_else(|_| "{}".to_string()))
+            Problem::new(req, problem_type, None)
         }
-    }
+    };
+
+    problem.into_response()
 }
 
-pub fn json_error_handler(
-    err: JsonPayloadError,
-    _req: &actix_web::HttpRequest,
-) -> actix_web::Error {
-    let message = match &err {
-        JsonPayloadError::ContentType => "Invalid content type".to_string(),
-        JsonPayloadError::Deserialize(json_err) => {
-            // You can parse the specific field error here if desired
-            json_err.to_string()
-        }
-        _ => "Invalid JSON payload".to_string(),
-    };
+pub fn unsupported_media_type(req: &HttpRequest, message: String) -> HttpResponse {
+    Problem::new(req, &problem::UNSUPPORTED_MEDIA_TYPE, Some(message)).into_response()
+}
 
-    let error_response = HttpResponse::BadRequest()
-        .content_type("application/json")
-        .body(json!({ "message": message }).to_string());
+pub fn forbidden(req: &HttpRequest, denied: Denied) -> HttpResponse {
+    tracing::info!("Denied request: {}", denied);
 
-    actix_web::error::InternalError::from_response(err, error_response).into()
+    Problem::new(req, &problem::FORBIDDEN, Some(denied.0)).into_response()
 }
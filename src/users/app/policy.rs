@@ -0,0 +1,201 @@
+use crate::auth::Principal;
+
+use uuid::Uuid;
+
+// ADMIN_SCOPE lets a principal act on any user, rather than only the one it is
+pub const ADMIN_SCOPE: &str = "users:admin";
+
+// Action is something a principal asks to do through the users API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Read(Uuid),
+    ReadByEmail,
+    List,
+    Update(Uuid),
+    Delete(Uuid),
+    Restore(Uuid),
+    Purge,
+    ChangePassword(Uuid),
+    SendEmailVerification(Uuid),
+    RevokeSessions(Uuid),
+}
+
+// Denied carries the reason an action was refused, which is returned to the caller
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{0}")]
+pub struct Denied(pub String);
+
+// authorize decides whether principal may take action. Principals with the admin scope may do
+// anything, while everyone else may only read, update and delete the user whose id is their
+// token's subject, change its password, have its email verified and log it out. It knows
+// nothing of HTTP, so the rules can be checked on their own.
+pub fn authorize(principal: &Principal, action: Action) -> Result<(), Denied> {
+    if is_admin(principal) {
+        return Ok(());
+    }
+
+    match action {
+        Action::Read(id)
+        | Action::Update(id)
+        | Action::Delete(id)
+        | Action::ChangePassword(id)
+        | Action::SendEmailVerification(id)
+        | Action::RevokeSessions(id) => {
+            if is_subject(principal, id) {
+                Ok(())
+            } else {
+                Err(Denied(format!(
+                    "only the user themselves or a token with the {} scope may access user {}",
+                    ADMIN_SCOPE, id
+                )))
+            }
+        }
+        // a lookup by email could reveal any user, and creating, restoring and purging users are
+        // administrative
+        Action::Create
+        | Action::ReadByEmail
+        | Action::List
+        | Action::Restore(_)
+        | Action::Purge => Err(Denied(format!(
+            "{} requires the {} scope",
+            describe(action),
+            ADMIN_SCOPE
+        ))),
+    }
+}
+
+pub fn is_admin(principal: &Principal) -> bool {
+    principal.scopes.iter().any(|scope| scope == ADMIN_SCOPE)
+}
+
+// subjects are compared as uuids, so differences in case or formatting don't matter
+fn is_subject(principal: &Principal, id: Uuid) -> bool {
+    principal
+        .subject
+        .parse::<Uuid>()
+        .is_ok_and(|subject| subject == id)
+}
+
+fn describe(action: Action) -> &'static str {
+    match action {
+        Action::Create => "creating users",
+        Action::Read(_) => "reading a user",
+        Action::ReadByEmail => "looking users up by email",
+        Action::List => "listing users",
+        Action::Update(_) => "updating a user",
+        Action::Delete(_) => "deleting a user",
+        Action::Restore(_) => "restoring a user",
+        Action::Purge => "purging deleted users",
+        Action::ChangePassword(_) => "changing a password",
+        Action::SendEmailVerification(_) => "sending an email verification",
+        Action::RevokeSessions(_) => "revoking sessions",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(subject: &str, scopes: &[&str]) -> Principal {
+        Principal {
+            subject: subject.to_string(),
+            scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+        }
+    }
+
+    fn own_actions(id: Uuid) -> [Action; 6] {
+        [
+            Action::Read(id),
+            Action::Update(id),
+            Action::Delete(id),
+            Action::ChangePassword(id),
+            Action::SendEmailVerification(id),
+            Action::RevokeSessions(id),
+        ]
+    }
+
+    fn admin_actions(id: Uuid) -> [Action; 5] {
+        [
+            Action::Create,
+            Action::List,
+            Action::ReadByEmail,
+            Action::Restore(id),
+            Action::Purge,
+        ]
+    }
+
+    #[test]
+    fn subject_may_act_on_themselves() {
+        let id = Uuid::new_v4();
+        // subjects match regardless of case
+        let principal = principal(&id.to_string().to_uppercase(), &[]);
+
+        for action in own_actions(id) {
+            assert_eq!(authorize(&principal, action), Ok(()), "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn subject_may_not_act_on_another_user() {
+        let principal = principal(&Uuid::new_v4().to_string(), &["users:read"]);
+        let other = Uuid::new_v4();
+
+        for action in own_actions(other) {
+            assert_eq!(
+                authorize(&principal, action),
+                Err(Denied(format!(
+                    "only the user themselves or a token with the users:admin scope may access \
+                     user {}",
+                    other
+                ))),
+                "{:?}",
+                action
+            );
+        }
+    }
+
+    #[test]
+    fn non_uuid_subject_is_never_a_user() {
+        let principal = principal("service-account", &[]);
+
+        assert!(authorize(&principal, Action::Read(Uuid::nil())).is_err());
+    }
+
+    #[test]
+    fn admin_may_do_anything() {
+        let principal = principal("admin", &[ADMIN_SCOPE]);
+        let other = Uuid::new_v4();
+
+        for action in own_actions(other).into_iter().chain(admin_actions(other)) {
+            assert_eq!(authorize(&principal, action), Ok(()), "{:?}", action);
+        }
+    }
+
+    #[test]
+    fn administrative_actions_are_denied_to_non_admins() {
+        let id = Uuid::new_v4();
+        // being the user in question does not help
+        let principal = principal(&id.to_string(), &["users:read", "users:write"]);
+
+        let cases = [
+            (Action::Create, "creating users"),
+            (Action::List, "listing users"),
+            (Action::ReadByEmail, "looking users up by email"),
+            (Action::Restore(id), "restoring a user"),
+            (Action::Purge, "purging deleted users"),
+        ];
+
+        for (action, description) in cases {
+            assert_eq!(
+                authorize(&principal, action),
+                Err(Denied(format!(
+                    "{} requires the users:admin scope",
+                    description
+                ))),
+                "{:?}",
+                action
+            );
+        }
+    }
+}
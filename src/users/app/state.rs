@@ -1,8 +1,21 @@
+use chrono::TimeDelta;
+
 #[derive(Clone)]
-pub struct State {}
+pub struct State {
+    // page size used when a listing request does not specify one
+    pub default_page_size: usize,
+    // upper bound on the page size a client may request
+    pub max_page_size: usize,
+    // how long soft deleted users are kept before they may be purged
+    pub purge_retention: TimeDelta,
+}
 
 impl State {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(default_page_size: usize, max_page_size: usize, purge_retention: TimeDelta) -> Self {
+        Self {
+            default_page_size,
+            max_page_size,
+            purge_retention,
+        }
     }
 }
@@ -0,0 +1,271 @@
+use crate::users::service::idos;
+use crate::validation::Validator;
+
+use chrono::{Months, NaiveDate, Utc};
+
+const NAME_MAX_LENGTH: usize = 100;
+
+// users must be at least this old to register
+const MIN_AGE_YEARS: u32 = 13;
+
+// anything older than this is almost certainly a typo
+const MAX_AGE_YEARS: u32 = 130;
+
+// characters allowed in names besides letters, to cover names such as "O'Neil", "Smith-Jones"
+// and "St. John"
+const NAME_PUNCTUATION: &str = " '-.";
+
+// the rules for the fields of a user, alongside the shared ones in crate::validation
+impl Validator {
+    // present unwraps a merge patch field, where null asks for the field to be removed, which
+    // none of the user's fields allow
+    pub fn present<T>(&mut self, field: &str, value: Option<Option<T>>) -> Option<T> {
+        match value {
+            Some(None) => {
+                self.fail(
+                    field,
+                    "cannot_be_removed",
+                    format!("{} cannot be removed", field),
+                );
+                None
+            }
+            Some(value) => value,
+            None => None,
+        }
+    }
+
+    // name trims surrounding whitespace and checks what is left
+    pub fn name(&mut self, field: &str, value: String) -> Option<String> {
+        let value = value.trim();
+
+        if value.is_empty() {
+            self.fail(field, "required", format!("{} must not be empty", field));
+            return None;
+        }
+
+        if value.chars().count() > NAME_MAX_LENGTH {
+            self.fail(
+                field,
+                "too_long",
+                format!("{} must be at most {} characters", field, NAME_MAX_LENGTH),
+            );
+            return None;
+        }
+
+        if !value
+            .chars()
+            .all(|c| c.is_alphabetic() || NAME_PUNCTUATION.contains(c))
+        {
+            self.fail(
+                field,
+                "invalid_characters",
+                format!(
+                    "{} may only contain letters, spaces, apostrophes, hyphens and periods",
+                    field
+                ),
+            );
+            return None;
+        }
+
+        Some(value.to_string())
+    }
+
+    pub fn email(&mut self, field: &str, value: &str) -> Option<idos::Email> {
+        match idos::Email::parse(value) {
+            Ok(email) => Some(email),
+            Err(e) => {
+                self.fail(field, "invalid_email", e);
+                None
+            }
+        }
+    }
+
+    // dob must be a YYYY-MM-DD date giving an age between MIN_AGE_YEARS and MAX_AGE_YEARS
+    pub fn dob(&mut self, field: &str, value: &str) -> Option<NaiveDate> {
+        let dob = match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            Ok(dob) => dob,
+            Err(_) => {
+                self.fail(
+                    field,
+                    "invalid_format",
+                    format!("{} must be a date in the format YYYY-MM-DD", field),
+                );
+                return None;
+            }
+        };
+
+        let today = Utc::now().date_naive();
+        if dob > today {
+            self.fail(
+                field,
+                "in_future",
+                format!("{} must not be in the future", field),
+            );
+            return None;
+        }
+
+        if years_before(today, MIN_AGE_YEARS).is_some_and(|latest| dob > latest) {
+            self.fail(
+                field,
+                "too_young",
+                format!("users must be at least {} years old", MIN_AGE_YEARS),
+            );
+            return None;
+        }
+
+        if years_before(today, MAX_AGE_YEARS).is_some_and(|earliest| dob < earliest) {
+            self.fail(
+                field,
+                "too_old",
+                format!("{} must be within the last {} years", field, MAX_AGE_YEARS),
+            );
+            return None;
+        }
+
+        Some(dob)
+    }
+
+    // not_replaceable rejects a field that can only be changed through its own endpoint
+    pub fn not_replaceable<T>(&mut self, field: &str, value: &Option<T>, endpoint: &str) {
+        if value.is_some() {
+            self.fail(
+                field,
+                "not_replaceable",
+                format!("{} can only be changed through {}", field, endpoint),
+            );
+        }
+    }
+}
+
+fn years_before(date: NaiveDate, years: u32) -> Option<NaiveDate> {
+    date.checked_sub_months(Months::new(years * 12))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // codes returns the code of each error v recorded, in order
+    fn codes(v: Validator) -> Vec<&'static str> {
+        v.into_errors()
+            .into_iter()
+            .map(|error| error.code)
+            .collect()
+    }
+
+    fn years_ago(years: u32) -> NaiveDate {
+        years_before(Utc::now().date_naive(), years).unwrap()
+    }
+
+    #[test]
+    fn names_are_trimmed() {
+        let mut v = Validator::new();
+
+        for (name, expected) in [
+            ("Ada", "Ada"),
+            ("  Ada ", "Ada"),
+            ("O'Neil", "O'Neil"),
+            ("Smith-Jones", "Smith-Jones"),
+            ("St. John", "St. John"),
+            ("Zoë", "Zoë"),
+            ("Ólafur", "Ólafur"),
+            ("李", "李"),
+        ] {
+            assert_eq!(
+                v.name("first_name", name.to_string()).as_deref(),
+                Some(expected)
+            );
+        }
+
+        assert!(v.is_valid());
+    }
+
+    #[test]
+    fn names_are_rejected() {
+        for (name, code) in [
+            ("", "required"),
+            ("   ", "required"),
+            (&"a".repeat(101), "too_long"),
+            ("Ada1", "invalid_characters"),
+            ("Ada_King", "invalid_characters"),
+            ("<script>", "invalid_characters"),
+        ] {
+            let mut v = Validator::new();
+            assert_eq!(v.name("first_name", name.to_string()), None, "{}", name);
+            assert_eq!(codes(v), [code], "{}", name);
+        }
+
+        let mut v = Validator::new();
+        assert!(v.name("first_name", "a".repeat(100)).is_some());
+    }
+
+    #[test]
+    fn dob_must_be_a_plausible_age() {
+        let mut v = Validator::new();
+
+        assert_eq!(
+            v.dob("dob", "1990-12-10"),
+            NaiveDate::from_ymd_opt(1990, 12, 10)
+        );
+        // the limits are inclusive
+        let youngest = years_ago(MIN_AGE_YEARS);
+        let oldest = years_ago(MAX_AGE_YEARS);
+        assert_eq!(v.dob("dob", &youngest.to_string()), Some(youngest));
+        assert_eq!(v.dob("dob", &oldest.to_string()), Some(oldest));
+        assert!(v.is_valid());
+
+        let tomorrow = Utc::now().date_naive().succ_opt().unwrap();
+        for (dob, code) in [
+            ("10/12/1990".to_string(), "invalid_format"),
+            ("1990-13-01".to_string(), "invalid_format"),
+            ("".to_string(), "invalid_format"),
+            (tomorrow.to_string(), "in_future"),
+            (youngest.succ_opt().unwrap().to_string(), "too_young"),
+            (oldest.pred_opt().unwrap().to_string(), "too_old"),
+        ] {
+            let mut v = Validator::new();
+            assert_eq!(v.dob("dob", &dob), None, "{}", dob);
+            assert_eq!(codes(v), [code], "{}", dob);
+        }
+    }
+
+    #[test]
+    fn email_errors_say_what_is_wrong() {
+        let mut v = Validator::new();
+
+        assert!(v.email("email", "Ada@Example.com").is_some());
+        assert!(v.email("email", "ada.example.com").is_none());
+
+        let errors = v.into_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            (errors[0].field.as_str(), errors[0].code),
+            ("email", "invalid_email")
+        );
+        assert_eq!(errors[0].message, "email must contain an @");
+    }
+
+    #[test]
+    fn every_failed_rule_is_recorded() {
+        let mut v = Validator::new();
+
+        v.name("first_name", "".to_string());
+        v.name("last_name", "K1ng".to_string());
+        v.email("email", "nope");
+        v.dob("dob", "yesterday");
+        v.present::<String>("first_name", Some(None));
+        v.not_replaceable("password", &Some("secret"), "PUT /{id}/password");
+
+        assert_eq!(
+            codes(v),
+            [
+                "required",
+                "invalid_characters",
+                "invalid_email",
+                "invalid_format",
+                "cannot_be_removed",
+                "not_replaceable",
+            ]
+        );
+    }
+}
@@ -1,2 +1,5 @@
 pub(crate) mod dynamodb;
 pub(crate) mod errors;
+pub(crate) mod memory;
+pub(crate) mod metered;
+pub(crate) mod pagination;
@@ -1,70 +1,186 @@
+pub(crate) mod repair;
+
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use super::errors::Error;
+use super::pagination::{self, Cursor};
+use crate::dynamodb::attrs::{
+    Malformed, get_datetime, get_optional_bool, get_optional_datetime, get_optional_number,
+    get_optional_string, get_string, get_uuid,
+};
+use crate::dynamodb::health::TableCheck;
+use crate::dynamodb::instrument;
+use crate::health::Check;
+use crate::metrics;
 use crate::users::service;
 use crate::users::service::idos;
 
-use aws_sdk_dynamodb::operation::{delete_item::DeleteItemError, put_item::PutItemError};
-
 use aws_sdk_dynamodb::{
     Client,
     error::SdkError,
-    operation::transact_write_items::TransactWriteItemsError::TransactionCanceledException,
-    types::{AttributeValue, Delete, Put, TransactWriteItem},
+    operation::transact_write_items::TransactWriteItemsError,
+    types::{
+        AttributeValue, ConditionCheck, Delete, Put, ReturnConsumedCapacity, ReturnValue,
+        ReturnValuesOnConditionCheckFailure, TransactWriteItem,
+    },
 };
-use chrono::{DateTime, NaiveDate, Utc};
+use chrono::{NaiveDate, Utc};
 use uuid::{self, Uuid};
 
-const OPERATION_LABELS: [&str; 3] = ["delete_old_email_with_check", "put_new_email", "put_user"];
+// labels for the items of the create transaction
+const PUT_EMAIL: &str = "put_email";
+const CREATE_USER: &str = "create_user";
+const PUT_CREDENTIALS: &str = "put_credentials";
+
+// labels for the items of the update transaction
+const DELETE_OLD_EMAIL: &str = "delete_old_email_with_check";
+const PUT_NEW_EMAIL: &str = "put_new_email";
+const PUT_USER: &str = "put_user";
+
+// labels for the items of the delete transaction
+const DELETE_USER: &str = "delete_user";
+const DELETE_EMAIL: &str = "delete_email";
+const DELETE_CREDENTIALS: &str = "delete_credentials";
+const DELETE_VERIFY_EMAIL_TOKEN: &str = "delete_verify_email_token";
+const DELETE_RESET_PASSWORD_TOKEN: &str = "delete_reset_password_token";
+
+// labels for the items of the set password transaction
+const CHECK_USER: &str = "check_user";
 
 #[derive(Clone)]
 pub struct Repo {
     client: Client,
     table_name: String,
     email_lookup_table_name: String,
+    // password hashes are kept in their own table, so nothing that reads a user can leak them
+    credentials_table_name: String,
+    // one-time tokens, keyed by user_id (partition) and purpose (sort). The ttl attribute holds
+    // the expiry as epoch seconds, for the table's TTL to remove expired tokens by.
+    tokens_table_name: String,
 }
 
 impl Repo {
-    pub fn new(client: Client, table_name: String, email_lookup_table_name: String) -> Self {
+    pub fn new(
+        client: Client,
+        table_name: String,
+        email_lookup_table_name: String,
+        credentials_table_name: String,
+        tokens_table_name: String,
+    ) -> Self {
         Self {
             client,
             table_name,
             email_lookup_table_name,
+            credentials_table_name,
+            tokens_table_name,
+        }
+    }
+
+    // health_checks returns a readiness check for each of the repo's tables
+    pub fn health_checks(&self) -> Vec<Arc<dyn Check>> {
+        [
+            &self.table_name,
+            &self.email_lookup_table_name,
+            &self.credentials_table_name,
+            &self.tokens_table_name,
+        ]
+        .into_iter()
+        .map(|table_name| {
+            Arc::new(TableCheck::new(self.client.clone(), table_name.clone())) as Arc<dyn Check>
+        })
+        .collect()
+    }
+
+    fn put_credentials(&self, id: Uuid, password_hash: &str) -> TransactWriteItem {
+        TransactWriteItem::builder()
+            .put(
+                Put::builder()
+                    .table_name(self.credentials_table_name.clone())
+                    .set_item(Some(credentials_to_attrs(id, password_hash)))
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+    }
+
+    // scan_users reads a single scan page of users matching the filter
+    async fn scan_users(
+        &self,
+        filter: &idos::UserFilter,
+        limit: Option<i32>,
+        start_key: Option<HashMap<String, AttributeValue>>,
+    ) -> Result<(Vec<idos::User>, Option<HashMap<String, AttributeValue>>), Error> {
+        let (filter_expression, expression_attribute_values) = filter_expression(filter);
+
+        let request = self
+            .client
+            .scan()
+            .table_name(self.table_name.clone())
+            .set_limit(limit)
+            .set_exclusive_start_key(start_key)
+            .filter_expression(filter_expression)
+            .set_expression_attribute_values(expression_attribute_values)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("Scan", &[&self.table_name], request.send()).await;
+        match resp {
+            Ok(output) => {
+                let users = output
+                    .items()
+                    .iter()
+                    .map(user_from_attrs)
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok((users, output.last_evaluated_key))
+            }
+            Err(e) => {
+                tracing::error!("Failed to scan users: {:?}", e);
+                Err(Error::Internal)
+            }
         }
     }
 }
 
 #[async_trait::async_trait]
 impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "dynamodb.get_user", skip_all, fields(user_id = %id))]
     async fn get_user(&self, id: Uuid) -> Result<idos::User, Error> {
         let request = self
             .client
             .get_item()
             .table_name(self.table_name.clone())
-            .key("id".to_string(), AttributeValue::S(id.to_string()));
+            .key("id".to_string(), AttributeValue::S(id.to_string()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
 
-        let resp = request.send().await;
+        let resp = instrument::call("GetItem", &[&self.table_name], request.send()).await;
         match resp {
             Ok(output) => match output.item() {
                 Some(attrs) => user_from_attrs(attrs),
                 None => Err(Error::NotFound),
             },
             Err(e) => {
-                println!("{:?}", e);
-                return Err(Error::Internal);
+                tracing::error!("Failed to get user: {:?}", e);
+                Err(Error::Internal)
             }
         }
     }
 
-    async fn get_user_by_email(&self, email: &str) -> Result<idos::User, Error> {
+    #[tracing::instrument(name = "dynamodb.get_user_by_email", skip_all)]
+    async fn get_user_by_email(&self, email: &idos::Email) -> Result<idos::User, Error> {
         // email is a unique identifier in our system, so we can use it to look up the user
         let request = self
             .client
             .get_item()
             .table_name(self.email_lookup_table_name.clone())
-            .key("email".to_string(), AttributeValue::S(email.to_string()));
+            .key(
+                "email".to_string(),
+                AttributeValue::S(email.canonical().to_string()),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
 
-        let resp = request.send().await;
+        let resp =
+            instrument::call("GetItem", &[&self.email_lookup_table_name], request.send()).await;
         match resp {
             Ok(output) => match output.item() {
                 Some(attrs) => {
@@ -103,132 +219,169 @@ impl service::core::Repo for Repo {
                 None => Err(Error::NotFound),
             },
             Err(e) => {
-                println!("{:?}", e);
-                return Err(Error::Internal);
+                tracing::error!("Failed to get email lookup record: {:?}", e);
+                Err(Error::Internal)
             }
         }
     }
 
-    async fn create_user(&self, user: &idos::User) -> Result<(), Error> {
-        // first we attempt to create an entry in the lookup table under the condition that the given email does not already exist
-        // this serves as both a check to ensure that we do not have duplicate emails in the system and as a way to create a lookup entry for the user
-        // if the email already exists, we will get a ConditionalCheckFailedException
-        let lookup_request = self
-            .client
-            .put_item()
-            .table_name(self.email_lookup_table_name.clone())
-            .item("id".to_string(), AttributeValue::S(user.id.to_string()))
-            .item("email".to_string(), AttributeValue::S(user.email.clone()))
-            .condition_expression("attribute_not_exists(email)");
+    #[tracing::instrument(name = "dynamodb.list_users", skip_all, fields(limit = query.limit))]
+    async fn list_users(&self, query: &idos::ListUsers) -> Result<idos::UserPage, Error> {
+        if let Some(sort) = &query.sort {
+            // a scan has no order, and a GSI could only order every user under a single constant
+            // partition key, so sorted listings read every match and sort them instead. The scan
+            // stops as soon as there are too many to sort, rather than reading the whole table.
+            let mut users = vec![];
+            let mut start_key = None;
+            loop {
+                let (mut page, last_key) = self.scan_users(&query.filter, None, start_key).await?;
+                users.append(&mut page);
+                if users.len() > pagination::MAX_SORTED_USERS {
+                    return Err(pagination::too_many_to_sort());
+                }
 
-        let lookup_resp = lookup_request.send().await;
-        match lookup_resp {
-            Ok(_) => {
-                tracing::info!(
-                    "Lookup entry created successfully for email: {}",
-                    user.email
-                );
-            }
-            Err(ref e) => match e {
-                SdkError::ServiceError(service_err) => match service_err.err() {
-                    PutItemError::ConditionalCheckFailedException(_) => {
-                        tracing::info!(
-                            "creation of email lookup record failed as a user with the given address already exists"
-                        );
-                        return Err(Error::EmailAddressAlreadyInUse(
-                            "a user with the given email already exists".to_string(),
-                        ));
-                    }
-                    _ => {
-                        tracing::error!("Unexpected error occurred: {:?}", e);
-                        return Err(Error::Internal);
-                    }
-                },
-                _ => {
-                    tracing::error!("Unexpected error occurred: {:?}", e);
-                    return Err(Error::Internal);
+                match last_key {
+                    Some(key) => start_key = Some(key),
+                    None => break,
                 }
-            },
+            }
+
+            return pagination::sorted_page(users, sort, query);
         }
 
-        let request = self
-            .client
-            .put_item()
-            .table_name(self.table_name.clone())
-            .item("id".to_string(), AttributeValue::S(user.id.to_string()))
-            .item(
-                "first_name".to_string(),
-                AttributeValue::S(user.first_name.clone()),
-            )
-            .item(
-                "last_name".to_string(),
-                AttributeValue::S(user.last_name.clone()),
-            )
-            .item("email".to_string(), AttributeValue::S(user.email.clone()))
-            .item("dob".to_string(), AttributeValue::S(user.dob.to_string()))
-            .item(
-                "created_at".to_string(),
-                AttributeValue::S(user.created_at.to_rfc3339()),
-            )
-            .item(
-                "updated_at".to_string(),
-                AttributeValue::S(user.updated_at.to_rfc3339()),
-            );
+        let mut start_key = match &query.cursor {
+            Some(cursor) => Some(HashMap::from([(
+                "id".to_string(),
+                AttributeValue::S(Cursor::decode(cursor, query)?.id.to_string()),
+            )])),
+            None => None,
+        };
 
-        println!("Executing request [{request:?}] to add item...");
+        // the scan limit is applied before the filter expression, so a page can come back short
+        // and we keep scanning until it is full or the table is exhausted
+        let limit = i32::try_from(query.limit).unwrap_or(i32::MAX);
+        let mut users = vec![];
+        loop {
+            let (mut page, last_key) = self
+                .scan_users(&query.filter, Some(limit), start_key)
+                .await?;
+            users.append(&mut page);
+            start_key = last_key;
 
-        let resp = request.send().await;
-        match resp {
-            Ok(_) => {
-                println!("User created successfully");
-                Ok(())
+            if users.len() >= query.limit || start_key.is_none() {
+                break;
             }
-            Err(e) => {
-                println!("Failed to create user: {:?}", e);
+        }
+
+        let has_more = users.len() > query.limit || start_key.is_some();
+        users.truncate(query.limit);
+
+        // resuming from the last user we return also picks up anything truncated from the scan
+        let next_cursor = match users.last() {
+            Some(last) if has_more => Some(Cursor::after(last, query).encode()),
+            _ => None,
+        };
 
-                // rollback the lookup entry if the user creation fails
-                let rollback_request = self
-                    .client
-                    .delete_item()
+        Ok(idos::UserPage { users, next_cursor })
+    }
+
+    #[tracing::instrument(name = "dynamodb.create_user", skip_all, fields(user_id = %user.id))]
+    async fn create_user(
+        &self,
+        user: &idos::User,
+        password_hash: Option<&str>,
+    ) -> Result<(), Error> {
+        // the lookup row and the user are written together so that neither can exist without
+        // the other. The lookup row's condition guarantees that emails are unique, the user's
+        // that we never overwrite an existing user
+        let put_email = TransactWriteItem::builder()
+            .put(
+                Put::builder()
                     .table_name(self.email_lookup_table_name.clone())
-                    .key("id".to_string(), AttributeValue::S(user.id.to_string()));
+                    .item(
+                        "email",
+                        AttributeValue::S(user.email.canonical().to_string()),
+                    )
+                    .item("id", AttributeValue::S(user.id.to_string()))
+                    .condition_expression("attribute_not_exists(email)")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
 
-                let rollback_resp = rollback_request.send().await;
-                if let Err(rollback_err) = rollback_resp {
-                    tracing::error!(
-                        "Failed to rollback lookup entry for user {}: {:?}",
-                        user.id,
-                        rollback_err
-                    );
-                } else {
-                    tracing::info!(
-                        "Rolled back lookup entry for user {} after failed creation",
-                        user.id
-                    );
-                }
+        let create_user = TransactWriteItem::builder()
+            .put(
+                Put::builder()
+                    .table_name(self.table_name.clone())
+                    .set_item(Some(user_to_attrs(user)))
+                    .condition_expression("attribute_not_exists(id)")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
 
-                return Err(Error::Internal);
+        let mut tx_write_items = vec![put_email, create_user];
+        let mut labels = vec![PUT_EMAIL, CREATE_USER];
+        let mut table_names = vec![
+            self.email_lookup_table_name.as_str(),
+            self.table_name.as_str(),
+        ];
+
+        // the password is set in the same transaction, so a user never exists without the
+        // password it was created with
+        if let Some(password_hash) = password_hash {
+            tx_write_items.push(self.put_credentials(user.id, password_hash));
+            labels.push(PUT_CREDENTIALS);
+            table_names.push(&self.credentials_table_name);
+        }
+
+        let request = self
+            .client
+            .transact_write_items()
+            .set_transact_items(Some(tx_write_items))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let result = instrument::call("TransactWriteItems", &table_names, request.send()).await;
+
+        match result {
+            Ok(_) => {
+                tracing::info!("User {} created successfully", user.id);
+                Ok(())
             }
+            Err(e) => Err(transaction_error(&labels, e)),
         }
     }
 
+    #[tracing::instrument(
+        name = "dynamodb.update_user",
+        skip_all,
+        fields(user_id = %user.id, expected_version = expected_version)
+    )]
     async fn update_user(
         &self,
         user: &idos::User,
-        old_user_email: Option<String>,
+        expected_version: u64,
+        old_user_email: Option<idos::Email>,
     ) -> Result<(), Error> {
         let mut tx_write_items: Vec<TransactWriteItem> = vec![];
+        // labels name each item of the transaction, in order, to attribute cancellation reasons
+        let mut labels: Vec<&str> = vec![];
 
         // old_user_email is None if the email has not changed
         if let Some(old_email) = old_user_email.clone() {
             // outside the tx we perform a pre-write lookup
-            let old_email_lookup_record = self
+            let request = self
                 .client
                 .get_item()
                 .table_name(self.email_lookup_table_name.clone())
-                .key("email", AttributeValue::S(old_email.to_string()))
-                .send()
-                .await;
+                .key(
+                    "email",
+                    AttributeValue::S(old_email.canonical().to_string()),
+                )
+                .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+            let old_email_lookup_record =
+                instrument::call("GetItem", &[&self.email_lookup_table_name], request.send()).await;
 
             match old_email_lookup_record {
                 Ok(get_item_output) => {
@@ -266,7 +419,11 @@ impl service::core::Repo for Repo {
                 .delete(
                     Delete::builder()
                         .table_name(self.email_lookup_table_name.clone())
-                        .key("email", AttributeValue::S(old_email.to_string()))
+                        .key(
+                            "email",
+                            AttributeValue::S(old_email.canonical().to_string()),
+                        )
+                        .condition_expression("id = :id")
                         .expression_attribute_values(":id", AttributeValue::S(user.id.to_string()))
                         .build()
                         .unwrap(),
@@ -274,12 +431,16 @@ impl service::core::Repo for Repo {
                 .build();
 
             tx_write_items.push(delete_old_email_with_check);
+            labels.push(DELETE_OLD_EMAIL);
 
             let put_new_email = TransactWriteItem::builder()
                 .put(
                     Put::builder()
                         .table_name(self.email_lookup_table_name.clone())
-                        .item("email", AttributeValue::S(user.email.to_string()))
+                        .item(
+                            "email",
+                            AttributeValue::S(user.email.canonical().to_string()),
+                        )
                         .item("id", AttributeValue::S(user.id.to_string()))
                         .condition_expression("attribute_not_exists(email)")
                         .build()
@@ -288,188 +449,606 @@ impl service::core::Repo for Repo {
                 .build();
 
             tx_write_items.push(put_new_email);
+            labels.push(PUT_NEW_EMAIL);
         }
 
+        let (version_condition, version_values) = version_condition(expected_version);
+
         let put_user = TransactWriteItem::builder()
             .put(
                 Put::builder()
                     .table_name(self.table_name.clone())
-                    .item("id", AttributeValue::S(user.id.to_string()))
-                    .item("email", AttributeValue::S(user.email.to_string()))
-                    .item("first_name", AttributeValue::S(user.first_name.clone()))
-                    .item("last_name", AttributeValue::S(user.last_name.clone()))
-                    .item("dob", AttributeValue::S(user.dob.to_string()))
-                    .item(
-                        "updated_at",
-                        AttributeValue::S(user.updated_at.to_rfc3339()),
+                    .set_item(Some(user_to_attrs(user)))
+                    // the item must exist to prevent silent creation, and must not have been
+                    // written since it was read
+                    .condition_expression(format!("attribute_exists(id) AND {}", version_condition))
+                    .expression_attribute_names("#version", "version")
+                    .set_expression_attribute_values(version_values)
+                    // the old item tells a version mismatch apart from a missing user
+                    .return_values_on_condition_check_failure(
+                        ReturnValuesOnConditionCheckFailure::AllOld,
                     )
-                    .condition_expression("attribute_exists(id)") // Ensure the item exists before updating to prevent silent creation
                     .build()
                     .unwrap(),
             )
             .build();
 
         tx_write_items.push(put_user);
+        labels.push(PUT_USER);
+
+        // the lookup table is only written when the email changes
+        let table_names: &[&str] = if old_user_email.is_some() {
+            &[&self.email_lookup_table_name, &self.table_name]
+        } else {
+            &[&self.table_name]
+        };
 
-        let result = self
+        let request = self
             .client
             .transact_write_items()
             .set_transact_items(Some(tx_write_items))
-            .send()
-            .await;
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let result = instrument::call("TransactWriteItems", table_names, request.send()).await;
 
         match result {
             Ok(_) => Ok(()),
-            Err(SdkError::ServiceError(svc_err)) => {
-                tracing::error!("Blud Transaction failed");
-                match svc_err.err() {
-                    TransactionCanceledException(e) => {
-                        tracing::error!("Transaction was cancelled: {:?}", svc_err);
-
-                        for (i, maybe_reason) in e.cancellation_reasons().iter().enumerate() {
-                            if let Some(reason) = maybe_reason.code.clone() {
-                                let op = OPERATION_LABELS.get(i).unwrap_or(&"unknown op");
-                                let msg = maybe_reason.message().unwrap_or("no message");
-                                tracing::error!(
-                                    "Step {} - op: {} failed: [{}] {}",
-                                    i + 1,
-                                    op,
-                                    reason,
-                                    msg
-                                );
-
-                                let _cond_check_failed = "ConditionalCheckFailed";
-                                match (*op, reason.as_str()) {
-                                    ("put_new_email", _cond_check_failed) => {
-                                        return Err(Error::EmailAddressAlreadyInUse(
-                                            "a user with the provided email already exists"
-                                                .to_string(),
-                                        ));
-                                    }
-                                    ("put_user", _cond_check_failed) => {
-                                        return Err(Error::NotFound);
-                                    }
-                                    (op, reason) => {
-                                        tracing::error!(
-                                            "Unhandled operation {} with reason: {}",
-                                            op,
-                                            reason
-                                        );
-                                        return Err(Error::Internal);
-                                    }
-                                }
-                            }
-                        }
+            Err(e) => Err(transaction_error(&labels, e)),
+        }
+    }
 
-                        return Err(Error::Internal);
-                    }
-                    _ => {
-                        tracing::error!("Unexpected error occurred: {:?}", svc_err);
-                        return Err(Error::Internal);
-                    }
-                }
-            }
+    #[tracing::instrument(
+        name = "dynamodb.delete_user",
+        skip_all,
+        fields(user_id = %id, expected_version = ?expected_version)
+    )]
+    async fn delete_user(&self, id: Uuid, expected_version: Option<u64>) -> Result<(), Error> {
+        // the user's email is needed to find its lookup row, so read the user ahead of the tx
+        let request = self
+            .client
+            .get_item()
+            .table_name(self.table_name.clone())
+            .key("id", AttributeValue::S(id.to_string()))
+            .consistent_read(true)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("GetItem", &[&self.table_name], request.send()).await;
+
+        let user = match resp {
+            Ok(output) => match output.item() {
+                Some(attrs) => user_from_attrs(attrs)?,
+                None => return Err(Error::NotFound),
+            },
             Err(e) => {
-                tracing::error!("Non-service error: {:?}", e);
+                tracing::error!("Failed to read user {} ahead of deletion: {:?}", id, e);
                 return Err(Error::Internal);
             }
+        };
+
+        if expected_version.is_some_and(|expected| expected != user.version) {
+            return Err(Error::VersionMismatch);
+        }
+
+        // conditioning on the version we read guarantees the email has not changed since
+        let (version_condition, version_values) = version_condition(user.version);
+
+        let delete_user = TransactWriteItem::builder()
+            .delete(
+                Delete::builder()
+                    .table_name(self.table_name.clone())
+                    .key("id", AttributeValue::S(id.to_string()))
+                    .condition_expression(format!("attribute_exists(id) AND {}", version_condition))
+                    .expression_attribute_names("#version", "version")
+                    .set_expression_attribute_values(version_values)
+                    .return_values_on_condition_check_failure(
+                        ReturnValuesOnConditionCheckFailure::AllOld,
+                    )
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+
+        // a missing lookup row should not block deletion, but one owned by another user must
+        // not be released
+        let delete_email = TransactWriteItem::builder()
+            .delete(
+                Delete::builder()
+                    .table_name(self.email_lookup_table_name.clone())
+                    .key(
+                        "email",
+                        AttributeValue::S(user.email.canonical().to_string()),
+                    )
+                    .condition_expression("attribute_not_exists(email) OR id = :id")
+                    .expression_attribute_values(":id", AttributeValue::S(id.to_string()))
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+
+        // users without a password have no credentials row, which a delete ignores
+        let delete_credentials = TransactWriteItem::builder()
+            .delete(
+                Delete::builder()
+                    .table_name(self.credentials_table_name.clone())
+                    .key("id", AttributeValue::S(id.to_string()))
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+
+        // a user has at most one token per purpose, so every key is known without a query, and
+        // deleting one that was never issued is ignored like the credentials
+        let delete_token = |purpose: idos::TokenPurpose| {
+            TransactWriteItem::builder()
+                .delete(
+                    Delete::builder()
+                        .table_name(self.tokens_table_name.clone())
+                        .key("user_id", AttributeValue::S(id.to_string()))
+                        .key("purpose", AttributeValue::S(purpose.as_str().to_string()))
+                        .build()
+                        .unwrap(),
+                )
+                .build()
+        };
+
+        let request = self
+            .client
+            .transact_write_items()
+            .transact_items(delete_user)
+            .transact_items(delete_email)
+            .transact_items(delete_credentials)
+            .transact_items(delete_token(idos::TokenPurpose::VerifyEmail))
+            .transact_items(delete_token(idos::TokenPurpose::ResetPassword))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let result = instrument::call(
+            "TransactWriteItems",
+            &[
+                &self.table_name,
+                &self.email_lookup_table_name,
+                &self.credentials_table_name,
+                &self.tokens_table_name,
+            ],
+            request.send(),
+        )
+        .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(transaction_error(
+                &[
+                    DELETE_USER,
+                    DELETE_EMAIL,
+                    DELETE_CREDENTIALS,
+                    DELETE_VERIFY_EMAIL_TOKEN,
+                    DELETE_RESET_PASSWORD_TOKEN,
+                ],
+                e,
+            )),
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.get_password_hash", skip_all, fields(user_id = %id))]
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, Error> {
+        let request = self
+            .client
+            .get_item()
+            .table_name(self.credentials_table_name.clone())
+            .key("id", AttributeValue::S(id.to_string()))
+            .consistent_read(true)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp =
+            instrument::call("GetItem", &[&self.credentials_table_name], request.send()).await;
+        match resp {
+            Ok(output) => match output.item() {
+                Some(attrs) => get_string(attrs, "password_hash")
+                    .map(Some)
+                    .map_err(Error::from),
+                None => Ok(None),
+            },
+            Err(e) => {
+                tracing::error!("Failed to get credentials: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.set_password_hash", skip_all, fields(user_id = %id))]
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), Error> {
+        // credentials must not outlive a user that was purged while the password was changing
+        let check_user = TransactWriteItem::builder()
+            .condition_check(
+                ConditionCheck::builder()
+                    .table_name(self.table_name.clone())
+                    .key("id", AttributeValue::S(id.to_string()))
+                    .condition_expression("attribute_exists(id)")
+                    .build()
+                    .unwrap(),
+            )
+            .build();
+
+        let request = self
+            .client
+            .transact_write_items()
+            .transact_items(check_user)
+            .transact_items(self.put_credentials(id, password_hash))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let result = instrument::call(
+            "TransactWriteItems",
+            &[&self.table_name, &self.credentials_table_name],
+            request.send(),
+        )
+        .await;
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(transaction_error(&[CHECK_USER, PUT_CREDENTIALS], e)),
         }
     }
 
-    async fn delete_user(&self, id: Uuid) -> Result<(), Error> {
+    #[tracing::instrument(name = "dynamodb.put_token", skip_all, fields(user_id = %token.user_id))]
+    async fn put_token(&self, token: &idos::Token) -> Result<(), Error> {
         let request = self
             .client
-            .delete_item()
-            .table_name(self.table_name.clone())
-            .key("id".to_string(), AttributeValue::S(id.to_string()));
+            .put_item()
+            .table_name(self.tokens_table_name.clone())
+            .set_item(Some(token_to_attrs(token)))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
 
-        let resp = request.send().await;
+        let resp = instrument::call("PutItem", &[&self.tokens_table_name], request.send()).await;
         match resp {
             Ok(_) => Ok(()),
             Err(e) => {
-                println!("Failed to delete user: {:?}", e);
-                match e {
-                    SdkError::ServiceError(service_err) => match service_err.err() {
-                        DeleteItemError::ConditionalCheckFailedException(_) => Err(Error::NotFound),
-                        _ => Err(Error::Internal),
-                    },
-                    SdkError::TimeoutError(_) => {
-                        tracing::error!("Timeout error while updating user");
-                        Err(Error::Internal)
-                    }
-                    _ => Err(Error::Internal),
-                }
+                tracing::error!("Failed to put token: {:?}", e);
+                Err(Error::Internal)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "dynamodb.take_token", skip_all, fields(user_id = %user_id))]
+    async fn take_token(
+        &self,
+        user_id: Uuid,
+        purpose: idos::TokenPurpose,
+        hash: &str,
+    ) -> Result<idos::Token, Error> {
+        // the conditional delete is what makes a token single use, of two concurrent attempts to
+        // use it only one gets the deleted item back
+        let request = self
+            .client
+            .delete_item()
+            .table_name(self.tokens_table_name.clone())
+            .key("user_id", AttributeValue::S(user_id.to_string()))
+            .key("purpose", AttributeValue::S(purpose.as_str().to_string()))
+            .condition_expression("token_hash = :token_hash")
+            .expression_attribute_values(":token_hash", AttributeValue::S(hash.to_string()))
+            .return_values(ReturnValue::AllOld)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        let resp = instrument::call("DeleteItem", &[&self.tokens_table_name], request.send()).await;
+        match resp {
+            Ok(output) => match output.attributes() {
+                Some(attrs) => token_from_attrs(attrs),
+                None => Err(Error::MalformedResponse(
+                    "deleted token missing from response".to_string(),
+                )),
+            },
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+            {
+                Err(Error::NotFound)
+            }
+            Err(e) => {
+                tracing::error!("Failed to delete token: {:?}", e);
+                Err(Error::Internal)
             }
         }
     }
 }
 
-fn user_from_attrs(attrs: &HashMap<String, AttributeValue>) -> Result<idos::User, Error> {
-    let id = get_string(attrs, "id").and_then(|val| {
-        Uuid::parse_str(&val).map_err(|_| Error::Validation("invalid uuid".to_string()))
+fn token_to_attrs(token: &idos::Token) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        (
+            "user_id".to_string(),
+            AttributeValue::S(token.user_id.to_string()),
+        ),
+        (
+            "purpose".to_string(),
+            AttributeValue::S(token.purpose.as_str().to_string()),
+        ),
+        (
+            "token_hash".to_string(),
+            AttributeValue::S(token.hash.clone()),
+        ),
+        (
+            "email".to_string(),
+            AttributeValue::S(token.email.to_string()),
+        ),
+        (
+            "created_at".to_string(),
+            AttributeValue::S(token.created_at.to_rfc3339()),
+        ),
+        (
+            "expires_at".to_string(),
+            AttributeValue::S(token.expires_at.to_rfc3339()),
+        ),
+        (
+            "ttl".to_string(),
+            AttributeValue::N(token.expires_at.timestamp().to_string()),
+        ),
+    ])
+}
+
+fn token_from_attrs(attrs: &HashMap<String, AttributeValue>) -> Result<idos::Token, Error> {
+    let user_id = get_uuid(attrs, "user_id")?;
+    let purpose = get_string(attrs, "purpose").and_then(|val| {
+        idos::TokenPurpose::parse(&val)
+            .ok_or_else(|| Malformed(format!("invalid purpose: {}", val)))
+    })?;
+    let email = get_string(attrs, "email").and_then(|val| {
+        idos::Email::parse(&val).map_err(|e| Malformed(format!("invalid email: {}", e)))
     })?;
 
+    Ok(idos::Token {
+        user_id,
+        purpose,
+        hash: get_string(attrs, "token_hash")?,
+        email,
+        created_at: get_datetime(attrs, "created_at")?,
+        expires_at: get_datetime(attrs, "expires_at")?,
+    })
+}
+
+fn credentials_to_attrs(id: Uuid, password_hash: &str) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("id".to_string(), AttributeValue::S(id.to_string())),
+        (
+            "password_hash".to_string(),
+            AttributeValue::S(password_hash.to_string()),
+        ),
+        (
+            "updated_at".to_string(),
+            AttributeValue::S(Utc::now().to_rfc3339()),
+        ),
+    ])
+}
+
+fn user_from_attrs(attrs: &HashMap<String, AttributeValue>) -> Result<idos::User, Error> {
+    let id = get_uuid(attrs, "id")?;
+
     let first_name = get_optional_string(attrs, "first_name")?;
     let last_name = get_optional_string(attrs, "last_name")?;
-    let email = get_string(attrs, "email")?;
+    let email = get_string(attrs, "email").and_then(|val| {
+        idos::Email::parse(&val).map_err(|e| Malformed(format!("invalid email: {}", e)))
+    })?;
     let dob = get_string(attrs, "dob").and_then(|val| {
         NaiveDate::parse_from_str(&val, "%Y-%m-%d")
-            .map_err(|_| Error::MalformedResponse("invalid dob format".to_string()))
+            .map_err(|_| Malformed("invalid dob format".to_string()))
     })?;
 
-    let created_at = get_datetime(attrs, "updated_at")?;
+    // users written before verification was introduced have no email_verified attribute
+    let email_verified = get_optional_bool(attrs, "email_verified")?.unwrap_or(false);
+
+    let created_at = get_datetime(attrs, "created_at")?;
     let updated_at = get_datetime(attrs, "updated_at")?;
+    // users written before versioning was introduced have no version attribute
+    let version = get_optional_number(attrs, "version")?.unwrap_or(0);
+    let deleted_at = get_optional_datetime(attrs, "deleted_at")?;
 
     Ok(idos::User {
         id,
         first_name,
         last_name,
         email,
+        email_verified,
         dob,
         created_at,
         updated_at,
+        version,
+        deleted_at,
     })
 }
 
-fn get_datetime(
-    attrs: &HashMap<String, AttributeValue>,
-    key: &str,
-) -> Result<DateTime<Utc>, Error> {
-    match attrs.get(key) {
-        Some(AttributeValue::S(val)) => DateTime::parse_from_rfc3339(val)
-            .map(|dt| dt.with_timezone(&Utc))
-            .map_err(|_| Error::MalformedResponse(format!("invalid {} format", key))),
-        Some(_) => Err(Error::MalformedResponse(format!(
-            "incorrect type for {}",
-            key
-        ))),
-        None => Err(Error::Validation(format!("{} missing", key))),
+fn user_to_attrs(user: &idos::User) -> HashMap<String, AttributeValue> {
+    let mut attrs = HashMap::from([
+        ("id".to_string(), AttributeValue::S(user.id.to_string())),
+        (
+            "first_name".to_string(),
+            AttributeValue::S(user.first_name.clone()),
+        ),
+        (
+            "last_name".to_string(),
+            AttributeValue::S(user.last_name.clone()),
+        ),
+        (
+            "email".to_string(),
+            AttributeValue::S(user.email.to_string()),
+        ),
+        (
+            "email_verified".to_string(),
+            AttributeValue::Bool(user.email_verified),
+        ),
+        ("dob".to_string(), AttributeValue::S(user.dob.to_string())),
+        (
+            "created_at".to_string(),
+            AttributeValue::S(user.created_at.to_rfc3339()),
+        ),
+        (
+            "updated_at".to_string(),
+            AttributeValue::S(user.updated_at.to_rfc3339()),
+        ),
+        (
+            "version".to_string(),
+            AttributeValue::N(user.version.to_string()),
+        ),
+    ]);
+
+    if let Some(deleted_at) = user.deleted_at {
+        attrs.insert(
+            "deleted_at".to_string(),
+            AttributeValue::S(deleted_at.to_rfc3339()),
+        );
     }
+
+    attrs
 }
 
-fn get_string(attrs: &HashMap<String, AttributeValue>, key: &str) -> Result<String, Error> {
-    match attrs.get(key) {
-        Some(AttributeValue::S(val)) => Ok(val.clone()),
-        Some(_) => Err(Error::MalformedResponse(format!(
-            "incorrect type for {}",
-            key
-        ))),
-        None => Err(Error::Validation(format!("{} missing", key))),
+// version_condition builds a condition expression asserting the stored version, it expects
+// "#version" to be mapped onto the version attribute
+fn version_condition(expected_version: u64) -> (String, Option<HashMap<String, AttributeValue>>) {
+    // a version of 0 means the user was written before versioning and has no version attribute
+    if expected_version == 0 {
+        return ("attribute_not_exists(#version)".to_string(), None);
     }
+
+    (
+        "#version = :expected_version".to_string(),
+        Some(HashMap::from([(
+            ":expected_version".to_string(),
+            AttributeValue::N(expected_version.to_string()),
+        )])),
+    )
 }
 
-fn get_optional_string(
-    attrs: &HashMap<String, AttributeValue>,
-    key: &str,
-) -> Result<String, Error> {
-    Ok(match attrs.get(key) {
-        Some(AttributeValue::S(val)) => val.clone(),
-        Some(_) => {
-            return Err(Error::MalformedResponse(format!(
-                "incorrect type for {}",
-                key
-            )));
+// transaction_error maps a failed transaction onto a repo error using the cancellation reason of
+// the first item that failed, labels name the items of the transaction in order
+fn transaction_error<R: std::fmt::Debug>(
+    labels: &[&str],
+    err: SdkError<TransactWriteItemsError, R>,
+) -> Error {
+    let svc_err = match err {
+        SdkError::ServiceError(svc_err) => svc_err,
+        e => {
+            tracing::error!("Non-service error: {:?}", e);
+            return Error::Internal;
         }
-        None => "".to_string(),
-    })
+    };
+
+    let e = match svc_err.err() {
+        TransactWriteItemsError::TransactionCanceledException(e) => e,
+        _ => {
+            tracing::error!("Unexpected error occurred: {:?}", svc_err);
+            return Error::Internal;
+        }
+    };
+
+    tracing::error!("Transaction was cancelled: {:?}", svc_err);
+
+    for (i, reason) in e.cancellation_reasons().iter().enumerate() {
+        if let Some(code) = reason.code()
+            && code != "None"
+        {
+            let op = labels.get(i).copied().unwrap_or("unknown");
+            metrics::DYNAMODB_TRANSACTION_CANCELLATIONS
+                .with_label_values(&[op, code])
+                .inc();
+        }
+    }
+
+    for (i, reason) in e.cancellation_reasons().iter().enumerate() {
+        // items that did not cause the cancellation are reported with a code of "None"
+        let code = match reason.code() {
+            Some(code) if code != "None" => code,
+            _ => continue,
+        };
+
+        let op = labels.get(i).copied().unwrap_or("unknown op");
+        let msg = reason.message().unwrap_or("no message");
+        tracing::error!("Step {} - op: {} failed: [{}] {}", i + 1, op, code, msg);
+
+        return match (op, code) {
+            (PUT_EMAIL | PUT_NEW_EMAIL, "ConditionalCheckFailed") => {
+                Error::EmailAddressAlreadyInUse(
+                    "a user with the provided email already exists".to_string(),
+                )
+            }
+            (CREATE_USER, "ConditionalCheckFailed") => {
+                Error::UserAlreadyExists("a user with the given id already exists".to_string())
+            }
+            // the old lookup row no longer points at this user, someone else changed the email
+            (DELETE_OLD_EMAIL, "ConditionalCheckFailed") => Error::VersionMismatch,
+            (CHECK_USER, "ConditionalCheckFailed") => Error::NotFound,
+            (PUT_USER | DELETE_USER, "ConditionalCheckFailed") => match reason.item() {
+                Some(_) => Error::VersionMismatch,
+                None => Error::NotFound,
+            },
+            (DELETE_EMAIL, "ConditionalCheckFailed") => {
+                tracing::error!("Email lookup record belongs to a different user");
+                Error::Internal
+            }
+            (op, code) => {
+                tracing::error!("Unhandled operation {} with reason: {}", op, code);
+                Error::Internal
+            }
+        };
+    }
+
+    Error::Internal
+}
+
+// dates and timestamps are stored as YYYY-MM-DD and RFC 3339 strings, which order lexicographically
+fn filter_expression(
+    filter: &idos::UserFilter,
+) -> (String, Option<HashMap<String, AttributeValue>>) {
+    let mut conditions = vec![];
+    let mut values = HashMap::new();
+
+    if let Some(prefix) = &filter.last_name_prefix {
+        conditions.push("begins_with(last_name, :last_name_prefix)");
+        values.insert(
+            ":last_name_prefix".to_string(),
+            AttributeValue::S(prefix.clone()),
+        );
+    }
+
+    if let Some(dob_from) = filter.dob_from {
+        conditions.push("dob >= :dob_from");
+        values.insert(
+            ":dob_from".to_string(),
+            AttributeValue::S(dob_from.to_string()),
+        );
+    }
+
+    if let Some(dob_to) = filter.dob_to {
+        conditions.push("dob <= :dob_to");
+        values.insert(":dob_to".to_string(), AttributeValue::S(dob_to.to_string()));
+    }
+
+    if let Some(created_from) = filter.created_from {
+        conditions.push("created_at >= :created_from");
+        values.insert(
+            ":created_from".to_string(),
+            AttributeValue::S(created_from.to_rfc3339()),
+        );
+    }
+
+    if let Some(created_to) = filter.created_to {
+        conditions.push("created_at <= :created_to");
+        values.insert(
+            ":created_to".to_string(),
+            AttributeValue::S(created_to.to_rfc3339()),
+        );
+    }
+
+    match filter.deleted {
+        idos::DeletedFilter::Exclude => conditions.push("attribute_not_exists(deleted_at)"),
+        idos::DeletedFilter::Before(before) => {
+            conditions.push("deleted_at <= :deleted_before");
+            values.insert(
+                ":deleted_before".to_string(),
+                AttributeValue::S(before.to_rfc3339()),
+            );
+        }
+    }
+
+    let values = if values.is_empty() {
+        None
+    } else {
+        Some(values)
+    };
+
+    (conditions.join(" AND "), values)
 }
@@ -0,0 +1,506 @@
+use std::collections::{HashMap, HashSet};
+
+use super::{Repo, user_from_attrs};
+use crate::dynamodb::attrs::{get_string, get_uuid};
+use crate::dynamodb::instrument;
+use crate::users::repo::errors::Error;
+
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity};
+use serde::Serialize;
+use uuid::Uuid;
+
+// Category classifies a drift between the users table and the email lookup table
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    // a lookup row whose user does not exist
+    OrphanedLookup,
+    // a lookup row whose user now has a different email
+    StaleLookup,
+    // a user with no lookup row for its email
+    MissingLookup,
+    // a user whose email's lookup row points at a different user
+    LookupOwnedByOtherUser,
+    // a lookup row that cannot be parsed
+    MalformedLookup,
+    // a user record that cannot be parsed
+    MalformedUser,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    DeleteLookup,
+    CreateLookup,
+    // the right fix depends on which record is correct, so a human has to decide
+    Manual,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Planned,
+    Applied,
+    Failed,
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub category: Category,
+    pub email: Option<String>,
+    pub user_id: Option<String>,
+    pub detail: String,
+    pub action: Action,
+    pub status: Status,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub apply: bool,
+    pub users_scanned: usize,
+    pub lookups_scanned: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Repo {
+    // repair scans both tables and reports every inconsistency between them. With apply set the
+    // fixable ones are fixed using conditional writes, so anything that changed since the scan
+    // is left alone and reported as failed.
+    pub async fn repair(&self, apply: bool) -> Result<Report, Error> {
+        let user_items = self.scan_all(&self.table_name).await?;
+        let lookup_items = self.scan_all(&self.email_lookup_table_name).await?;
+
+        let mut issues = classify(&user_items, &lookup_items);
+
+        if apply {
+            for issue in issues.iter_mut() {
+                if issue.status != Status::Planned {
+                    continue;
+                }
+
+                issue.status = match self.fix(issue).await {
+                    Ok(_) => Status::Applied,
+                    Err(e) => {
+                        tracing::error!("Failed to repair {:?}: {}", issue, e);
+                        Status::Failed
+                    }
+                };
+            }
+        }
+
+        Ok(Report {
+            apply,
+            users_scanned: user_items.len(),
+            lookups_scanned: lookup_items.len(),
+            issues,
+        })
+    }
+
+    async fn fix(&self, issue: &Issue) -> Result<(), Error> {
+        let (email, id) = match (&issue.email, &issue.user_id) {
+            (Some(email), Some(id)) => (email.clone(), id.clone()),
+            _ => {
+                return Err(Error::Validation(
+                    "issue is missing email or id".to_string(),
+                ));
+            }
+        };
+
+        let result = match issue.action {
+            // only delete the row if it still points at the same user
+            Action::DeleteLookup => {
+                let request = self
+                    .client
+                    .delete_item()
+                    .table_name(self.email_lookup_table_name.clone())
+                    .key("email", AttributeValue::S(email))
+                    .condition_expression("id = :id")
+                    .expression_attribute_values(":id", AttributeValue::S(id))
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+                instrument::call(
+                    "DeleteItem",
+                    &[&self.email_lookup_table_name],
+                    request.send(),
+                )
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("{:?}", e))
+            }
+            // only create the row if nobody has claimed the email since the scan
+            Action::CreateLookup => {
+                let request = self
+                    .client
+                    .put_item()
+                    .table_name(self.email_lookup_table_name.clone())
+                    .item("email", AttributeValue::S(email))
+                    .item("id", AttributeValue::S(id))
+                    .condition_expression("attribute_not_exists(email)")
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+                instrument::call("PutItem", &[&self.email_lookup_table_name], request.send())
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| format!("{:?}", e))
+            }
+            Action::Manual => return Ok(()),
+        };
+
+        result.map_err(|e| {
+            tracing::error!("Repair write failed: {}", e);
+            Error::Internal
+        })
+    }
+
+    async fn scan_all(
+        &self,
+        table_name: &str,
+    ) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+        let mut items = vec![];
+        let mut start_key = None;
+
+        loop {
+            let request = self
+                .client
+                .scan()
+                .table_name(table_name)
+                .consistent_read(true)
+                .set_exclusive_start_key(start_key)
+                .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+            let resp = instrument::call("Scan", &[table_name], request.send()).await;
+
+            let output = match resp {
+                Ok(output) => output,
+                Err(e) => {
+                    tracing::error!("Failed to scan {}: {:?}", table_name, e);
+                    return Err(Error::Internal);
+                }
+            };
+
+            items.extend(output.items().iter().cloned());
+
+            match output.last_evaluated_key {
+                Some(key) => start_key = Some(key),
+                None => return Ok(items),
+            }
+        }
+    }
+}
+
+// classify compares every scanned user with every scanned lookup row, planning a fix for each
+// inconsistency that can be fixed without deciding which record is correct
+fn classify(
+    user_items: &[HashMap<String, AttributeValue>],
+    lookup_items: &[HashMap<String, AttributeValue>],
+) -> Vec<Issue> {
+    let mut issues = vec![];
+
+    // user id -> canonical email, for every user that could be parsed. Lookup rows are keyed
+    // by canonical email, so rows written before normalisation show up as stale here.
+    let mut user_emails: HashMap<Uuid, String> = HashMap::new();
+    // the same ids in scan order, so that reports list issues in a stable order
+    let mut user_ids: Vec<Uuid> = vec![];
+    // ids of users that exist but could not be parsed, their lookup rows are not orphaned
+    let mut malformed_user_ids: HashSet<String> = HashSet::new();
+    for attrs in user_items {
+        match user_from_attrs(attrs) {
+            Ok(user) => {
+                user_emails.insert(user.id, user.email.canonical().to_string());
+                user_ids.push(user.id);
+            }
+            Err(e) => {
+                if let Ok(id) = get_string(attrs, "id") {
+                    malformed_user_ids.insert(id);
+                }
+
+                issues.push(Issue {
+                    category: Category::MalformedUser,
+                    email: get_string(attrs, "email").ok(),
+                    user_id: get_string(attrs, "id").ok(),
+                    detail: e.to_string(),
+                    action: Action::Manual,
+                    status: Status::Skipped,
+                });
+            }
+        }
+    }
+
+    // email -> user id, for every lookup row that could be parsed
+    let mut lookups: HashMap<String, Uuid> = HashMap::new();
+    for attrs in lookup_items {
+        let email = get_string(attrs, "email");
+        let id = get_uuid(attrs, "id");
+
+        let (email, id) = match (email, id) {
+            (Ok(email), Ok(id)) => (email, id),
+            (email, id) => {
+                let detail = [email.as_ref().err(), id.as_ref().err()]
+                    .into_iter()
+                    .flatten()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                issues.push(Issue {
+                    category: Category::MalformedLookup,
+                    email: email.ok(),
+                    user_id: get_string(attrs, "id").ok(),
+                    detail,
+                    action: Action::Manual,
+                    status: Status::Skipped,
+                });
+                continue;
+            }
+        };
+
+        match user_emails.get(&id) {
+            None if malformed_user_ids.contains(&id.to_string()) => {}
+            None => issues.push(Issue {
+                category: Category::OrphanedLookup,
+                email: Some(email.clone()),
+                user_id: Some(id.to_string()),
+                detail: "lookup row points at a user that does not exist".to_string(),
+                action: Action::DeleteLookup,
+                status: Status::Planned,
+            }),
+            Some(user_email) if *user_email != email => issues.push(Issue {
+                category: Category::StaleLookup,
+                email: Some(email.clone()),
+                user_id: Some(id.to_string()),
+                detail: format!("user's email is now {}", user_email),
+                action: Action::DeleteLookup,
+                status: Status::Planned,
+            }),
+            Some(_) => {}
+        }
+
+        lookups.insert(email, id);
+    }
+
+    for id in &user_ids {
+        let email = &user_emails[id];
+        match lookups.get(email) {
+            None => issues.push(Issue {
+                category: Category::MissingLookup,
+                email: Some(email.clone()),
+                user_id: Some(id.to_string()),
+                detail: "user has no lookup row for its email".to_string(),
+                action: Action::CreateLookup,
+                status: Status::Planned,
+            }),
+            Some(owner) if owner != id => issues.push(Issue {
+                category: Category::LookupOwnedByOtherUser,
+                email: Some(email.clone()),
+                user_id: Some(id.to_string()),
+                detail: format!("lookup row for the user's email points at user {}", owner),
+                action: Action::Manual,
+                status: Status::Skipped,
+            }),
+            Some(_) => {}
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::user_to_attrs;
+    use super::*;
+    use crate::users::service::idos;
+    use chrono::NaiveDate;
+
+    type Item = HashMap<String, AttributeValue>;
+    // what an issue is about and what is done with it, leaving out the free text detail
+    type Summary<'a> = (Category, Option<&'a str>, Option<&'a str>, Action, Status);
+
+    fn user(email: &str) -> (Uuid, Item) {
+        let user = idos::User::new(
+            "Ada".to_string(),
+            "Lovelace".to_string(),
+            idos::Email::parse(email).unwrap(),
+            NaiveDate::from_ymd_opt(1990, 1, 1).unwrap(),
+        );
+        (user.id, user_to_attrs(&user))
+    }
+
+    fn lookup(email: &str, id: &str) -> Item {
+        HashMap::from([
+            ("email".to_string(), AttributeValue::S(email.to_string())),
+            ("id".to_string(), AttributeValue::S(id.to_string())),
+        ])
+    }
+
+    fn summary(issues: &[Issue]) -> Vec<Summary<'_>> {
+        issues
+            .iter()
+            .map(|issue| {
+                (
+                    issue.category,
+                    issue.email.as_deref(),
+                    issue.user_id.as_deref(),
+                    issue.action,
+                    issue.status,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn consistent_tables_have_no_issues() {
+        let (id, user) = user("ada@example.com");
+
+        let issues = classify(&[user], &[lookup("ada@example.com", &id.to_string())]);
+
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn lookup_for_missing_user_is_orphaned() {
+        let id = Uuid::new_v4().to_string();
+
+        let issues = classify(&[], &[lookup("ada@example.com", &id)]);
+
+        assert_eq!(
+            summary(&issues),
+            [(
+                Category::OrphanedLookup,
+                Some("ada@example.com"),
+                Some(id.as_str()),
+                Action::DeleteLookup,
+                Status::Planned
+            )]
+        );
+    }
+
+    #[test]
+    fn lookup_for_old_email_is_stale() {
+        let (id, user) = user("ada@example.com");
+        let id = id.to_string();
+
+        let issues = classify(
+            &[user],
+            &[
+                lookup("ada@example.com", &id),
+                lookup("old@example.com", &id),
+            ],
+        );
+
+        assert_eq!(
+            summary(&issues),
+            [(
+                Category::StaleLookup,
+                Some("old@example.com"),
+                Some(id.as_str()),
+                Action::DeleteLookup,
+                Status::Planned
+            )]
+        );
+    }
+
+    #[test]
+    fn user_without_lookup_is_missing_one() {
+        let (id, user) = user("ada@example.com");
+        let id = id.to_string();
+
+        let issues = classify(&[user], &[]);
+
+        assert_eq!(
+            summary(&issues),
+            [(
+                Category::MissingLookup,
+                Some("ada@example.com"),
+                Some(id.as_str()),
+                Action::CreateLookup,
+                Status::Planned
+            )]
+        );
+    }
+
+    #[test]
+    fn lookup_owned_by_other_user_is_left_to_a_human() {
+        let (owner, owning_user) = user("ada@example.com");
+        let (id, other_user) = user("ada@example.com");
+        let id = id.to_string();
+
+        let issues = classify(
+            &[owning_user, other_user],
+            &[lookup("ada@example.com", &owner.to_string())],
+        );
+
+        assert_eq!(
+            summary(&issues),
+            [(
+                Category::LookupOwnedByOtherUser,
+                Some("ada@example.com"),
+                Some(id.as_str()),
+                Action::Manual,
+                Status::Skipped
+            )]
+        );
+    }
+
+    #[test]
+    fn malformed_lookups_are_reported() {
+        let mut missing_id = lookup("ada@example.com", "");
+        missing_id.remove("id");
+
+        let issues = classify(&[], &[missing_id, lookup("bob@example.com", "not-a-uuid")]);
+
+        assert_eq!(
+            summary(&issues),
+            [
+                (
+                    Category::MalformedLookup,
+                    Some("ada@example.com"),
+                    None,
+                    Action::Manual,
+                    Status::Skipped
+                ),
+                (
+                    Category::MalformedLookup,
+                    Some("bob@example.com"),
+                    Some("not-a-uuid"),
+                    Action::Manual,
+                    Status::Skipped
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_users_are_reported_and_their_lookups_left_alone() {
+        let (id, mut user) = user("ada@example.com");
+        let id = id.to_string();
+        user.insert(
+            "dob".to_string(),
+            AttributeValue::S("yesterday".to_string()),
+        );
+
+        let issues = classify(&[user], &[lookup("ada@example.com", &id)]);
+
+        assert_eq!(
+            summary(&issues),
+            [(
+                Category::MalformedUser,
+                Some("ada@example.com"),
+                Some(id.as_str()),
+                Action::Manual,
+                Status::Skipped
+            )]
+        );
+    }
+
+    #[test]
+    fn issues_follow_scan_order() {
+        let (first, first_user) = user("ada@example.com");
+        let (second, second_user) = user("bob@example.com");
+
+        let issues = classify(&[first_user, second_user], &[]);
+
+        let ids: Vec<_> = issues.iter().map(|i| i.user_id.clone().unwrap()).collect();
+        assert_eq!(ids, [first.to_string(), second.to_string()]);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::dynamodb::attrs::Malformed;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("user not found")]
@@ -12,6 +14,33 @@ pub enum Error {
     #[error("email address already in use: {0}")]
     EmailAddressAlreadyInUse(String),
 
+    #[error("user already exists: {0}")]
+    UserAlreadyExists(String),
+
+    #[error("user version does not match the expected version")]
+    VersionMismatch,
+
     #[error("internal error")]
     Internal,
 }
+
+impl Error {
+    // kind names the variant, for use as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::NotFound => "not_found",
+            Error::Validation(_) => "validation",
+            Error::MalformedResponse(_) => "malformed_response",
+            Error::EmailAddressAlreadyInUse(_) => "email_address_already_in_use",
+            Error::UserAlreadyExists(_) => "user_already_exists",
+            Error::VersionMismatch => "version_mismatch",
+            Error::Internal => "internal",
+        }
+    }
+}
+
+impl From<Malformed> for Error {
+    fn from(malformed: Malformed) -> Self {
+        Error::MalformedResponse(malformed.0)
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use super::errors::Error;
+use super::pagination::{self, Cursor};
+use crate::users::service;
+use crate::users::service::idos;
+
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+// Store mirrors the DynamoDB tables: the user records keyed by id, the email lookup index, keyed
+// by canonical email, that guarantees an address belongs to at most one user, and the password
+// hashes keyed by user id, along with the one-time tokens keyed by user id and purpose. Users are
+// ordered by id so that listing has a stable order to resume from. Expired tokens are never
+// removed, unlike with the tokens table's TTL.
+#[derive(Default)]
+struct Store {
+    users: BTreeMap<Uuid, idos::User>,
+    email_lookup: HashMap<String, Uuid>,
+    credentials: HashMap<Uuid, String>,
+    tokens: HashMap<(Uuid, idos::TokenPurpose), idos::Token>,
+}
+
+#[derive(Clone, Default)]
+pub struct Repo {
+    store: Arc<RwLock<Store>>,
+}
+
+impl Repo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl service::core::Repo for Repo {
+    #[tracing::instrument(name = "memory.create_user", skip_all, fields(user_id = %user.id))]
+    async fn create_user(
+        &self,
+        user: &idos::User,
+        password_hash: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+
+        if store.email_lookup.contains_key(user.email.canonical()) {
+            tracing::info!(
+                "creation of email lookup record failed as a user with the given address already exists"
+            );
+            return Err(Error::EmailAddressAlreadyInUse(
+                "a user with the given email already exists".to_string(),
+            ));
+        }
+
+        if store.users.contains_key(&user.id) {
+            return Err(Error::UserAlreadyExists(
+                "a user with the given id already exists".to_string(),
+            ));
+        }
+
+        store
+            .email_lookup
+            .insert(user.email.canonical().to_string(), user.id);
+        store.users.insert(user.id, user.clone());
+        if let Some(password_hash) = password_hash {
+            store.credentials.insert(user.id, password_hash.to_string());
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.get_user", skip_all, fields(user_id = %id))]
+    async fn get_user(&self, id: Uuid) -> Result<idos::User, Error> {
+        let store = self.store.read().await;
+
+        store.users.get(&id).cloned().ok_or(Error::NotFound)
+    }
+
+    #[tracing::instrument(name = "memory.get_user_by_email", skip_all)]
+    async fn get_user_by_email(&self, email: &idos::Email) -> Result<idos::User, Error> {
+        let store = self.store.read().await;
+
+        let id = match store.email_lookup.get(email.canonical()) {
+            Some(id) => id,
+            None => return Err(Error::NotFound),
+        };
+
+        match store.users.get(id) {
+            Some(user) => Ok(user.clone()),
+            None => {
+                tracing::error!(
+                    "User record not found for email that exists in lookup table: {}",
+                    email
+                );
+                Err(Error::NotFound)
+            }
+        }
+    }
+
+    #[tracing::instrument(name = "memory.list_users", skip_all, fields(limit = query.limit))]
+    async fn list_users(&self, query: &idos::ListUsers) -> Result<idos::UserPage, Error> {
+        let store = self.store.read().await;
+
+        if let Some(sort) = &query.sort {
+            let users = store
+                .users
+                .values()
+                .filter(|user| query.filter.matches(user))
+                .cloned()
+                .collect();
+
+            return pagination::sorted_page(users, sort, query);
+        }
+
+        let start = match &query.cursor {
+            Some(cursor) => Bound::Excluded(Cursor::decode(cursor, query)?.id),
+            None => Bound::Unbounded,
+        };
+
+        let mut remaining = store
+            .users
+            .range((start, Bound::Unbounded))
+            .map(|(_, user)| user)
+            .filter(|user| query.filter.matches(user));
+
+        let users: Vec<idos::User> = remaining.by_ref().take(query.limit).cloned().collect();
+
+        let next_cursor = match (users.last(), remaining.next()) {
+            (Some(last), Some(_)) => Some(Cursor::after(last, query).encode()),
+            _ => None,
+        };
+
+        Ok(idos::UserPage { users, next_cursor })
+    }
+
+    #[tracing::instrument(
+        name = "memory.update_user",
+        skip_all,
+        fields(user_id = %user.id, expected_version = expected_version)
+    )]
+    async fn update_user(
+        &self,
+        user: &idos::User,
+        expected_version: u64,
+        old_user_email: Option<idos::Email>,
+    ) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+
+        match store.users.get(&user.id) {
+            Some(existing) if existing.version != expected_version => {
+                return Err(Error::VersionMismatch);
+            }
+            Some(_) => {}
+            None => return Err(Error::NotFound),
+        }
+
+        // old_user_email is None if the email has not changed
+        if let Some(old_email) = old_user_email {
+            match store.email_lookup.get(old_email.canonical()) {
+                Some(id) if *id == user.id => {}
+                Some(_) => {
+                    return Err(Error::EmailAddressAlreadyInUse(
+                        "a user with the provided email already exists".to_string(),
+                    ));
+                }
+                None => {
+                    tracing::error!(
+                        "Old email lookup record not found for email: {}, user ID: {}",
+                        old_email,
+                        user.id
+                    );
+                    return Err(Error::Internal);
+                }
+            }
+
+            if store.email_lookup.contains_key(user.email.canonical()) {
+                return Err(Error::EmailAddressAlreadyInUse(
+                    "a user with the provided email already exists".to_string(),
+                ));
+            }
+
+            store.email_lookup.remove(old_email.canonical());
+            store
+                .email_lookup
+                .insert(user.email.canonical().to_string(), user.id);
+        }
+
+        store.users.insert(user.id, user.clone());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "memory.delete_user",
+        skip_all,
+        fields(user_id = %id, expected_version = ?expected_version)
+    )]
+    async fn delete_user(&self, id: Uuid, expected_version: Option<u64>) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+
+        match store.users.get(&id) {
+            Some(existing) if expected_version.is_some_and(|v| v != existing.version) => {
+                return Err(Error::VersionMismatch);
+            }
+            Some(_) => {}
+            None => return Err(Error::NotFound),
+        }
+
+        store.credentials.remove(&id);
+        store.tokens.retain(|(user_id, _), _| *user_id != id);
+        if let Some(user) = store.users.remove(&id) {
+            // only release the lookup entry if it still belongs to this user
+            if store.email_lookup.get(user.email.canonical()) == Some(&id) {
+                store.email_lookup.remove(user.email.canonical());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.get_password_hash", skip_all, fields(user_id = %id))]
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, Error> {
+        let store = self.store.read().await;
+
+        Ok(store.credentials.get(&id).cloned())
+    }
+
+    #[tracing::instrument(name = "memory.set_password_hash", skip_all, fields(user_id = %id))]
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+
+        if !store.users.contains_key(&id) {
+            return Err(Error::NotFound);
+        }
+
+        store.credentials.insert(id, password_hash.to_string());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.put_token", skip_all, fields(user_id = %token.user_id))]
+    async fn put_token(&self, token: &idos::Token) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+
+        store
+            .tokens
+            .insert((token.user_id, token.purpose), token.clone());
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "memory.take_token", skip_all, fields(user_id = %user_id))]
+    async fn take_token(
+        &self,
+        user_id: Uuid,
+        purpose: idos::TokenPurpose,
+        hash: &str,
+    ) -> Result<idos::Token, Error> {
+        let mut store = self.store.write().await;
+
+        match store.tokens.get(&(user_id, purpose)) {
+            Some(token) if token.hash == hash => {}
+            _ => return Err(Error::NotFound),
+        }
+
+        store
+            .tokens
+            .remove(&(user_id, purpose))
+            .ok_or(Error::NotFound)
+    }
+}
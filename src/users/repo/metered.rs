@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::time::Instant;
+
+use super::errors::Error;
+use crate::metrics;
+use crate::users::service;
+use crate::users::service::idos;
+
+use uuid::Uuid;
+
+// Repo wraps another repo, timing every operation and counting the errors it returns by kind
+#[derive(Clone)]
+pub struct Repo<R: service::core::Repo> {
+    inner: R,
+    // the metrics' repo label, telling the backends apart
+    name: &'static str,
+}
+
+impl<R: service::core::Repo> Repo<R> {
+    pub fn new(name: &'static str, inner: R) -> Self {
+        Self { inner, name }
+    }
+
+    async fn observe<T>(
+        &self,
+        operation: &'static str,
+        call: impl Future<Output = Result<T, Error>>,
+    ) -> Result<T, Error> {
+        let start = Instant::now();
+        let result = call.await;
+
+        metrics::REPO_OPERATION_DURATION
+            .with_label_values(&[self.name, operation])
+            .observe(start.elapsed().as_secs_f64());
+
+        if let Err(e) = &result {
+            metrics::REPO_ERRORS
+                .with_label_values(&[self.name, operation, e.kind()])
+                .inc();
+        }
+
+        result
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: service::core::Repo> service::core::Repo for Repo<R> {
+    async fn create_user(
+        &self,
+        user: &idos::User,
+        password_hash: Option<&str>,
+    ) -> Result<(), Error> {
+        self.observe("create_user", self.inner.create_user(user, password_hash))
+            .await
+    }
+
+    async fn get_user(&self, id: Uuid) -> Result<idos::User, Error> {
+        self.observe("get_user", self.inner.get_user(id)).await
+    }
+
+    async fn get_user_by_email(&self, email: &idos::Email) -> Result<idos::User, Error> {
+        self.observe("get_user_by_email", self.inner.get_user_by_email(email))
+            .await
+    }
+
+    async fn list_users(&self, query: &idos::ListUsers) -> Result<idos::UserPage, Error> {
+        self.observe("list_users", self.inner.list_users(query))
+            .await
+    }
+
+    async fn update_user(
+        &self,
+        user: &idos::User,
+        expected_version: u64,
+        old_user_email: Option<idos::Email>,
+    ) -> Result<(), Error> {
+        self.observe(
+            "update_user",
+            self.inner
+                .update_user(user, expected_version, old_user_email),
+        )
+        .await
+    }
+
+    async fn delete_user(&self, id: Uuid, expected_version: Option<u64>) -> Result<(), Error> {
+        self.observe("delete_user", self.inner.delete_user(id, expected_version))
+            .await
+    }
+
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, Error> {
+        self.observe("get_password_hash", self.inner.get_password_hash(id))
+            .await
+    }
+
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), Error> {
+        self.observe(
+            "set_password_hash",
+            self.inner.set_password_hash(id, password_hash),
+        )
+        .await
+    }
+
+    async fn put_token(&self, token: &idos::Token) -> Result<(), Error> {
+        self.observe("put_token", self.inner.put_token(token)).await
+    }
+
+    async fn take_token(
+        &self,
+        user_id: Uuid,
+        purpose: idos::TokenPurpose,
+        hash: &str,
+    ) -> Result<idos::Token, Error> {
+        self.observe("take_token", self.inner.take_token(user_id, purpose, hash))
+            .await
+    }
+}
@@ -0,0 +1,333 @@
+use std::cmp::Ordering;
+
+use super::errors::Error;
+use crate::users::service::idos;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+// Neither repo can sort at the storage layer, so a sorted listing reads and sorts every matching
+// user on each page request. Past this many matches the listing is refused, and clients have to
+// narrow the filter or page through unsorted instead.
+pub const MAX_SORTED_USERS: usize = 10_000;
+
+// Cursor is the position of the last user on a page, handed to clients as base64url encoded JSON.
+// Unsorted listings resume from the id alone, sorted listings also need the sort field's value.
+#[derive(Serialize, Deserialize)]
+pub struct Cursor {
+    pub id: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_name: Option<String>,
+    // fingerprint of the listing the cursor was issued for
+    #[serde(default)]
+    pub query: String,
+}
+
+impl Cursor {
+    pub fn after(user: &idos::User, query: &idos::ListUsers) -> Self {
+        let mut cursor = Cursor {
+            id: user.id,
+            created_at: None,
+            last_name: None,
+            query: fingerprint(query),
+        };
+
+        match query.sort.map(|sort| sort.field) {
+            Some(idos::SortField::CreatedAt) => cursor.created_at = Some(user.created_at),
+            Some(idos::SortField::LastName) => cursor.last_name = Some(user.last_name.clone()),
+            None => {}
+        }
+
+        cursor
+    }
+
+    pub fn sort_key(&self, sort: &idos::Sort) -> Result<idos::SortKey, Error> {
+        let key = match sort.field {
+            idos::SortField::CreatedAt => self
+                .created_at
+                .map(|created_at| idos::SortKey::CreatedAt(created_at, self.id)),
+            idos::SortField::LastName => self
+                .last_name
+                .clone()
+                .map(|last_name| idos::SortKey::LastName(last_name, self.id)),
+        };
+
+        // a cursor issued for a different sort order cannot be resumed from
+        key.ok_or_else(invalid_cursor)
+    }
+
+    pub fn encode(&self) -> String {
+        // the cursor only holds plain values, so serialisation cannot fail
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    // decode fails for a cursor issued for a listing with another filter or sort than query's,
+    // as resuming from it would silently skip or repeat users
+    pub fn decode(cursor: &str, query: &idos::ListUsers) -> Result<Self, Error> {
+        let json = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .map_err(|_| invalid_cursor())?;
+
+        let cursor: Self = serde_json::from_slice(&json).map_err(|_| invalid_cursor())?;
+        if cursor.query != fingerprint(query) {
+            return Err(Error::Validation(
+                "cursor was issued for a different filter or sort".to_string(),
+            ));
+        }
+
+        Ok(cursor)
+    }
+}
+
+// too_many_to_sort is the error for a sorted listing matching more than MAX_SORTED_USERS users
+pub fn too_many_to_sort() -> Error {
+    Error::Validation(format!(
+        "more than {} users match, narrow the filter or list without sort",
+        MAX_SORTED_USERS
+    ))
+}
+
+// sorted_page sorts every matching user and returns the page following the query's cursor
+pub fn sorted_page(
+    mut users: Vec<idos::User>,
+    sort: &idos::Sort,
+    query: &idos::ListUsers,
+) -> Result<idos::UserPage, Error> {
+    if users.len() > MAX_SORTED_USERS {
+        return Err(too_many_to_sort());
+    }
+
+    let after = match &query.cursor {
+        Some(cursor) => Some(Cursor::decode(cursor, query)?.sort_key(sort)?),
+        None => None,
+    };
+
+    users.sort_by(|a, b| sort.compare(a, b));
+
+    let start = match after {
+        Some(key) => users
+            .partition_point(|user| sort.compare_key(&sort.key(user), &key) != Ordering::Greater),
+        None => 0,
+    };
+
+    let mut page = users.split_off(start);
+    let has_more = page.len() > query.limit;
+    page.truncate(query.limit);
+
+    let next_cursor = match page.last() {
+        Some(last) if has_more => Some(Cursor::after(last, query).encode()),
+        _ => None,
+    };
+
+    Ok(idos::UserPage {
+        users: page,
+        next_cursor,
+    })
+}
+
+// fingerprint identifies a listing by its filter and sort, everything but the page size
+fn fingerprint(query: &idos::ListUsers) -> String {
+    let listing = format!("{:?} {:?}", query.filter, query.sort);
+    URL_SAFE_NO_PAD.encode(&Sha256::digest(listing.as_bytes())[..8])
+}
+
+fn invalid_cursor() -> Error {
+    Error::Validation("invalid cursor".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, NaiveDate};
+
+    fn user(last_name: &str, created_at: DateTime<Utc>) -> idos::User {
+        let mut user = idos::User::new(
+            "First".to_string(),
+            last_name.to_string(),
+            idos::Email::parse(&format!("{}@example.com", Uuid::new_v4())).unwrap(),
+            NaiveDate::from_ymd_opt(1990, 1, 1).unwrap(),
+        );
+        user.created_at = created_at;
+        user
+    }
+
+    fn users() -> Vec<idos::User> {
+        let now = Utc::now();
+        ["Carter", "Adams", "Brown", "Adams", "Davis"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| user(name, now + Duration::seconds(i as i64)))
+            .collect()
+    }
+
+    fn query(sort: Option<idos::Sort>, limit: usize, cursor: Option<String>) -> idos::ListUsers {
+        idos::ListUsers {
+            limit,
+            cursor,
+            filter: idos::UserFilter::default(),
+            sort,
+        }
+    }
+
+    fn sort(field: idos::SortField, order: idos::SortOrder) -> idos::Sort {
+        idos::Sort { field, order }
+    }
+
+    // every_page follows cursors from the first page to the last, collecting each page's users
+    fn every_page(users: &[idos::User], sort: idos::Sort, limit: usize) -> Vec<Vec<Uuid>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let page =
+                sorted_page(users.to_vec(), &sort, &query(Some(sort), limit, cursor)).unwrap();
+            pages.push(page.users.iter().map(|user| user.id).collect());
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_cover_the_sorted_users_exactly_once() {
+        let users = users();
+
+        for field in [idos::SortField::CreatedAt, idos::SortField::LastName] {
+            for order in [idos::SortOrder::Asc, idos::SortOrder::Desc] {
+                let sort = sort(field, order);
+                let mut expected = users.clone();
+                expected.sort_by(|a, b| sort.compare(a, b));
+                let expected: Vec<Uuid> = expected.iter().map(|user| user.id).collect();
+
+                let pages = every_page(&users, sort, 2);
+
+                assert_eq!(
+                    pages.iter().map(Vec::len).collect::<Vec<_>>(),
+                    [2, 2, 1],
+                    "{:?}",
+                    sort
+                );
+                assert_eq!(pages.concat(), expected, "{:?}", sort);
+            }
+        }
+    }
+
+    #[test]
+    fn orders_by_the_sort_field_then_id() {
+        let users = users();
+        let sort = sort(idos::SortField::LastName, idos::SortOrder::Desc);
+
+        let page = sorted_page(users, &sort, &query(Some(sort), 10, None)).unwrap();
+
+        let names: Vec<&str> = page.users.iter().map(|u| u.last_name.as_str()).collect();
+        assert_eq!(names, ["Davis", "Carter", "Brown", "Adams", "Adams"]);
+        assert!(page.users[3].id > page.users[4].id);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let user = user("Adams", Utc::now());
+        let sort = sort(idos::SortField::CreatedAt, idos::SortOrder::Asc);
+        let query = query(Some(sort), 10, None);
+
+        let cursor = Cursor::decode(&Cursor::after(&user, &query).encode(), &query).unwrap();
+
+        assert_eq!(cursor.id, user.id);
+        assert_eq!(cursor.created_at, Some(user.created_at));
+        assert_eq!(cursor.last_name, None);
+        assert_eq!(
+            cursor.sort_key(&sort).unwrap(),
+            idos::SortKey::CreatedAt(user.created_at, user.id)
+        );
+    }
+
+    #[test]
+    fn page_size_can_change_between_pages() {
+        let user = user("Adams", Utc::now());
+        let cursor = Cursor::after(&user, &query(None, 2, None)).encode();
+
+        assert_eq!(
+            Cursor::decode(&cursor, &query(None, 50, None)).unwrap().id,
+            user.id
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_cursors() {
+        let query = query(None, 10, None);
+        let not_a_cursor = URL_SAFE_NO_PAD.encode(b"{\"foo\":1}");
+
+        for cursor in ["", "not base64!", "bm90IGpzb24", not_a_cursor.as_str()] {
+            assert!(
+                matches!(Cursor::decode(cursor, &query), Err(Error::Validation(_))),
+                "{}",
+                cursor
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_cursors_for_another_sort() {
+        let users = users();
+        let by_name = sort(idos::SortField::LastName, idos::SortOrder::Asc);
+        let first = sorted_page(users.clone(), &by_name, &query(Some(by_name), 2, None)).unwrap();
+        let cursor = first.next_cursor.unwrap();
+
+        let other_sorts = [
+            Some(sort(idos::SortField::LastName, idos::SortOrder::Desc)),
+            Some(sort(idos::SortField::CreatedAt, idos::SortOrder::Asc)),
+            None,
+        ];
+        for other in other_sorts {
+            let result = match other {
+                Some(other) => sorted_page(
+                    users.clone(),
+                    &other,
+                    &query(Some(other), 2, Some(cursor.clone())),
+                )
+                .map(|_| ()),
+                None => Cursor::decode(&cursor, &query(None, 2, None)).map(|_| ()),
+            };
+
+            assert!(matches!(result, Err(Error::Validation(_))), "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn rejects_cursors_for_another_filter() {
+        let user = user("Adams", Utc::now());
+        let cursor = Cursor::after(&user, &query(None, 10, None)).encode();
+
+        let filtered = idos::ListUsers {
+            filter: idos::UserFilter {
+                last_name_prefix: Some("Ad".to_string()),
+                ..Default::default()
+            },
+            ..query(None, 10, None)
+        };
+
+        assert!(matches!(
+            Cursor::decode(&cursor, &filtered),
+            Err(Error::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_to_sort_too_many_users() {
+        let now = Utc::now();
+        let users = vec![user("Adams", now); MAX_SORTED_USERS + 1];
+        let sort = sort(idos::SortField::LastName, idos::SortOrder::Asc);
+
+        assert!(matches!(
+            sorted_page(users, &sort, &query(Some(sort), 10, None)),
+            Err(Error::Validation(_))
+        ));
+    }
+}
@@ -1,3 +1,4 @@
 pub(crate) mod core;
 pub(crate) mod errors;
 pub(crate) mod idos;
+pub(crate) mod password;
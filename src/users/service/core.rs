@@ -1,52 +1,307 @@
+use std::sync::Arc;
+
 use super::errors::Error;
-use super::idos::{User, UserUpdate};
+use super::idos::{
+    DeletedFilter, Email, ListUsers, OneTimeToken, Password, Token, TokenPurpose, User, UserFilter,
+    UserPage, UserUpdate,
+};
+use super::password;
+use crate::auth;
+use crate::notifications::Mailer;
+use crate::notifications::templates::{Template, Templates};
 use crate::users::app::core::Service as AppService;
 use crate::users::repo::errors::Error as RepoError;
 
+use chrono::{DateTime, TimeDelta, Utc};
+use minijinja::context;
+use tracing::Instrument;
 use uuid::Uuid;
 
+// number of times an unconditional update is re-applied after losing a race with another writer
+const MAX_UPDATE_ATTEMPTS: usize = 3;
+
+// page size used when scanning for users to purge
+const PURGE_PAGE_SIZE: usize = 100;
+
 #[async_trait::async_trait]
 pub trait Repo: Send + Sync + Clone + 'static {
-    async fn create_user(&self, user: &User) -> Result<(), RepoError>;
+    // create_user stores the user along with its password hash, when it was given a password
+    async fn create_user(&self, user: &User, password_hash: Option<&str>) -> Result<(), RepoError>;
 
     async fn get_user(&self, id: Uuid) -> Result<User, RepoError>;
 
-    async fn get_user_by_email(&self, email: &str) -> Result<User, RepoError>;
+    async fn get_user_by_email(&self, email: &Email) -> Result<User, RepoError>;
+
+    async fn list_users(&self, query: &ListUsers) -> Result<UserPage, RepoError>;
 
+    // update_user fails with VersionMismatch unless the stored user is still at expected_version.
+    // Soft deletes and restores are updates that set or clear deleted_at.
     async fn update_user(
         &self,
         user: &User,
-        old_user_email: Option<String>,
+        expected_version: u64,
+        old_user_email: Option<Email>,
     ) -> Result<(), RepoError>;
 
-    async fn delete_user(&self, id: Uuid) -> Result<(), RepoError>;
+    // delete_user permanently removes the user and releases its email address
+    async fn delete_user(&self, id: Uuid, expected_version: Option<u64>) -> Result<(), RepoError>;
+
+    // get_password_hash returns None for users who have never had a password set
+    async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, RepoError>;
+
+    async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), RepoError>;
+
+    // put_token stores a one-time token, replacing any the user already has for its purpose
+    async fn put_token(&self, token: &Token) -> Result<(), RepoError>;
+
+    // take_token deletes and returns the user's token for purpose, failing with NotFound unless
+    // the stored token has the given hash, so that each token can only be used once
+    async fn take_token(
+        &self,
+        user_id: Uuid,
+        purpose: TokenPurpose,
+        hash: &str,
+    ) -> Result<Token, RepoError>;
 }
 
 #[derive(Clone)]
 pub struct Service<R: Repo> {
     repo: R,
+    // mails users the one-time tokens that verify their email and reset their password, and
+    // tells them about changes to their account
+    mailer: Arc<dyn Mailer>,
+    templates: Templates,
+    // how long a token can be used for once it has been sent
+    verify_email_ttl: TimeDelta,
+    reset_password_ttl: TimeDelta,
 }
 
 impl<R: Repo> Service<R> {
-    pub fn new(repo: R) -> Self {
-        Self { repo }
+    pub fn new(
+        repo: R,
+        mailer: Arc<dyn Mailer>,
+        verify_email_ttl: TimeDelta,
+        reset_password_ttl: TimeDelta,
+    ) -> Self {
+        Self {
+            repo,
+            mailer,
+            templates: Templates::new(),
+            verify_email_ttl,
+            reset_password_ttl,
+        }
+    }
+
+    // modify_user applies modify to the stored user and writes it back, conditional on the user
+    // not having been written in between. Without an expected_version the caller only cares
+    // about their own change, so it is re-applied on top of whatever a concurrent writer stored.
+    // The user's previous email is returned along with it when the modification changed it.
+    async fn modify_user<F>(
+        &self,
+        id: Uuid,
+        expected_version: Option<u64>,
+        modify: F,
+    ) -> Result<(User, Option<Email>), Error>
+    where
+        F: Fn(&mut User) -> Result<(), Error> + Send + Sync,
+    {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let mut user = match self.repo.get_user(id).await {
+                Ok(user) => user,
+                Err(e) => return Err(Error::from_repo_error(e)),
+            };
+
+            if let Some(expected) = expected_version
+                && user.version != expected
+            {
+                return Err(Error::PreconditionFailed(format!(
+                    "user is at version {}, expected {}",
+                    user.version, expected
+                )));
+            }
+
+            let read_version = user.version;
+            let old_user_email = user.email.clone();
+            modify(&mut user)?;
+
+            // None tells the repo that the email lookup does not need to change. A change to the
+            // display form alone keeps the same canonical lookup key.
+            let old_user_email = if user.email != old_user_email {
+                Some(old_user_email)
+            } else {
+                None
+            };
+
+            match self
+                .repo
+                .update_user(&user, read_version, old_user_email.clone())
+                .await
+            {
+                Ok(_) => return Ok((user, old_user_email)),
+                Err(RepoError::VersionMismatch) if expected_version.is_none() => {
+                    tracing::info!("user {} was modified concurrently, retrying", id);
+                }
+                Err(e) => return Err(Error::from_repo_error(e)),
+            }
+        }
+
+        Err(Error::ConflictingUser(
+            "user is being modified concurrently, please retry".to_string(),
+        ))
+    }
+
+    // send_token mails the user a new one-time token for purpose, which replaces any they were
+    // sent for it before
+    async fn send_token(&self, user: &User, purpose: TokenPurpose) -> Result<(), Error> {
+        let now = Utc::now();
+        let ttl = match purpose {
+            TokenPurpose::VerifyEmail => self.verify_email_ttl,
+            TokenPurpose::ResetPassword => self.reset_password_ttl,
+        };
+
+        let secret = OneTimeToken::generate(user.id, purpose);
+        let token = Token {
+            user_id: user.id,
+            purpose,
+            hash: secret.hash(),
+            email: user.email.clone(),
+            created_at: now,
+            expires_at: now + ttl,
+        };
+
+        self.repo
+            .put_token(&token)
+            .await
+            .map_err(Error::from_repo_error)?;
+
+        let template = match purpose {
+            TokenPurpose::VerifyEmail => Template::VerifyEmail,
+            TokenPurpose::ResetPassword => Template::ResetPassword,
+        };
+        let context = context! {
+            first_name => user.first_name,
+            token => secret.as_str(),
+            expires_at => token.expires_at.to_rfc3339(),
+        };
+
+        let sent = match self
+            .templates
+            .render(template, user.email.to_string(), context)
+        {
+            Ok(message) => self.mailer.send(message).await,
+            Err(e) => Err(e),
+        };
+        sent.map_err(|e| {
+            tracing::error!(
+                "Failed to mail {} token to user {}: {}",
+                purpose.as_str(),
+                user.id,
+                e
+            );
+            Error::Internal
+        })?;
+
+        tracing::info!("Sent {} token to user {}", purpose.as_str(), user.id);
+
+        Ok(())
+    }
+
+    // send_password_reset mails a reset token to whoever has email, if anyone does. Nobody is
+    // waiting on the outcome, so failures are only logged.
+    async fn send_password_reset(&self, email: Email) {
+        let user = match self.repo.get_user_by_email(&email).await {
+            Ok(user) if !user.is_deleted() => user,
+            Ok(_) | Err(RepoError::NotFound) => return,
+            Err(e) => {
+                tracing::error!("Failed to look up user for password reset: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.send_token(&user, TokenPurpose::ResetPassword).await {
+            tracing::error!("Failed to send password reset to user {}: {}", user.id, e);
+        }
+    }
+
+    // notify mails the user about a change to their account. The change has been made by now,
+    // so failing to tell them about it is only logged.
+    async fn notify(&self, user: &User, to: &Email, template: Template, context: minijinja::Value) {
+        let sent = match self.templates.render(template, to.to_string(), context) {
+            Ok(message) => self.mailer.send(message).await,
+            Err(e) => Err(e),
+        };
+
+        match sent {
+            Ok(_) => tracing::info!("Sent {} message to user {}", template.name(), user.id),
+            Err(e) => tracing::error!(
+                "Failed to send {} message to user {}: {}",
+                template.name(),
+                user.id,
+                e
+            ),
+        }
+    }
+
+    // take_token uses up a one-time token, failing with InvalidOneTimeToken when it is
+    // malformed, was never issued, has already been used or has expired
+    async fn take_token(&self, value: &str, purpose: TokenPurpose) -> Result<Token, auth::Error> {
+        let secret = OneTimeToken::parse(value, purpose).ok_or(auth::Error::InvalidOneTimeToken)?;
+
+        let token = match self
+            .repo
+            .take_token(secret.user_id(), purpose, &secret.hash())
+            .await
+        {
+            Ok(token) => token,
+            Err(RepoError::NotFound) => return Err(auth::Error::InvalidOneTimeToken),
+            Err(e) => {
+                tracing::error!("Failed to take {} token: {}", purpose.as_str(), e);
+                return Err(auth::Error::Unavailable);
+            }
+        };
+
+        if token.is_expired(Utc::now()) {
+            return Err(auth::Error::InvalidOneTimeToken);
+        }
+
+        Ok(token)
     }
 }
 
 #[async_trait::async_trait]
 impl<R: Repo> AppService for Service<R> {
-    async fn create_user(&self, user: User) -> Result<User, Error> {
+    #[tracing::instrument(name = "service.create_user", skip_all, fields(user_id = %user.id))]
+    async fn create_user(&self, user: User, password: Option<Password>) -> Result<User, Error> {
         if user.id.is_nil() {
             tracing::error!("missing uuid");
             return Err(Error::Validation("user id must be populated".to_string()));
         }
 
-        match self.repo.create_user(&user).await {
-            Ok(_) => Ok(user),
-            Err(e) => Err(Error::from_repo_error(e)),
+        let password_hash = match password {
+            Some(password) => Some(password::hash(password).await?),
+            None => None,
+        };
+
+        if let Err(e) = self.repo.create_user(&user, password_hash.as_deref()).await {
+            return Err(Error::from_repo_error(e));
+        }
+
+        let context = context! {
+            first_name => user.first_name,
+            email => user.email.to_string(),
+        };
+        self.notify(&user, &user.email, Template::Welcome, context)
+            .await;
+
+        // the user exists either way, and can ask for another verification email
+        if let Err(e) = self.send_token(&user, TokenPurpose::VerifyEmail).await {
+            tracing::error!("Failed to send verification email to new user: {}", e);
         }
+
+        Ok(user)
     }
 
+    #[tracing::instrument(name = "service.get_user", skip_all, fields(user_id = %id))]
     async fn get_user(&self, id: Uuid) -> Result<User, Error> {
         if id.is_nil() {
             tracing::error!("missing uuid");
@@ -54,24 +309,74 @@ impl<R: Repo> AppService for Service<R> {
         }
 
         match self.repo.get_user(id).await {
+            Ok(user) if user.is_deleted() => Err(Error::Deleted),
             Ok(user) => Ok(user),
             Err(e) => Err(Error::from_repo_error(e)),
         }
     }
 
-    async fn get_user_by_email(&self, email: &str) -> Result<User, Error> {
-        if email.is_empty() {
-            tracing::error!("missing email");
-            return Err(Error::Validation("email must be populated".to_string()));
-        }
-
+    #[tracing::instrument(name = "service.get_user_by_email", skip_all)]
+    async fn get_user_by_email(&self, email: &Email) -> Result<User, Error> {
         match self.repo.get_user_by_email(email).await {
+            Ok(user) if user.is_deleted() => Err(Error::Deleted),
             Ok(user) => Ok(user),
             Err(e) => Err(Error::from_repo_error(e)),
         }
     }
 
-    async fn update_user(&self, id: Uuid, update: UserUpdate) -> Result<User, Error> {
+    #[tracing::instrument(name = "service.list_users", skip_all, fields(limit = query.limit))]
+    async fn list_users(&self, query: ListUsers) -> Result<UserPage, Error> {
+        if query.limit == 0 {
+            tracing::error!("invalid page size");
+            return Err(Error::Validation(
+                "limit must be greater than 0".to_string(),
+            ));
+        }
+
+        let filter = &query.filter;
+        if filter
+            .last_name_prefix
+            .as_ref()
+            .is_some_and(|prefix| prefix.is_empty())
+        {
+            return Err(Error::Validation(
+                "last_name_prefix must not be empty".to_string(),
+            ));
+        }
+
+        if let (Some(from), Some(to)) = (filter.dob_from, filter.dob_to)
+            && from > to
+        {
+            return Err(Error::Validation(
+                "dob_from must not be after dob_to".to_string(),
+            ));
+        }
+
+        if let (Some(from), Some(to)) = (filter.created_from, filter.created_to)
+            && from > to
+        {
+            return Err(Error::Validation(
+                "created_from must not be after created_to".to_string(),
+            ));
+        }
+
+        match self.repo.list_users(&query).await {
+            Ok(page) => Ok(page),
+            Err(e) => Err(Error::from_repo_error(e)),
+        }
+    }
+
+    #[tracing::instrument(
+        name = "service.update_user",
+        skip_all,
+        fields(user_id = %id, expected_version = ?expected_version)
+    )]
+    async fn update_user(
+        &self,
+        id: Uuid,
+        update: UserUpdate,
+        expected_version: Option<u64>,
+    ) -> Result<User, Error> {
         if id.is_nil() {
             tracing::error!("missing uuid");
             return Err(Error::Validation("user id must be populated".to_string()));
@@ -87,40 +392,501 @@ impl<R: Repo> AppService for Service<R> {
             ));
         }
 
-        // fetch the existing user to update
-        let mut user = match self.repo.get_user(id).await {
+        let (user, old_email) = self
+            .modify_user(id, expected_version, |user| {
+                if user.is_deleted() {
+                    return Err(Error::Deleted);
+                }
+
+                user.update(update.clone());
+                Ok(())
+            })
+            .await?;
+
+        // the old address is told about the change, in case it was not its owner who made it.
+        // The new one is unverified until its owner uses the token sent to it, the update stands
+        // either way.
+        if let Some(old_email) = old_email {
+            let context = context! {
+                first_name => user.first_name,
+                old_email => old_email.to_string(),
+                new_email => user.email.to_string(),
+            };
+            self.notify(&user, &old_email, Template::EmailChanged, context)
+                .await;
+
+            if let Err(e) = self.send_token(&user, TokenPurpose::VerifyEmail).await {
+                tracing::error!("Failed to send verification email for changed email: {}", e);
+            }
+        }
+
+        Ok(user)
+    }
+
+    #[tracing::instrument(
+        name = "service.delete_user",
+        skip_all,
+        fields(user_id = %id, expected_version = ?expected_version)
+    )]
+    async fn delete_user(&self, id: Uuid, expected_version: Option<u64>) -> Result<(), Error> {
+        if id.is_nil() {
+            tracing::error!("missing uuid");
+            return Err(Error::Validation("user id must be populated".to_string()));
+        }
+
+        // users are only soft deleted here, keeping their email reserved so they can be restored
+        let (user, _) = self
+            .modify_user(id, expected_version, |user| {
+                if user.is_deleted() {
+                    return Err(Error::Deleted);
+                }
+
+                user.soft_delete();
+                Ok(())
+            })
+            .await?;
+
+        let context = context! {
+            first_name => user.first_name,
+            email => user.email.to_string(),
+        };
+        self.notify(&user, &user.email, Template::AccountDeleted, context)
+            .await;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "service.restore_user", skip_all, fields(user_id = %id))]
+    async fn restore_user(&self, id: Uuid) -> Result<User, Error> {
+        if id.is_nil() {
+            tracing::error!("missing uuid");
+            return Err(Error::Validation("user id must be populated".to_string()));
+        }
+
+        self.modify_user(id, None, |user| {
+            if !user.is_deleted() {
+                return Err(Error::ConflictingUser("user is not deleted".to_string()));
+            }
+
+            user.restore();
+            Ok(())
+        })
+        .await
+        .map(|(user, _)| user)
+    }
+
+    #[tracing::instrument(name = "service.send_email_verification", skip_all, fields(user_id = %id))]
+    async fn send_email_verification(&self, id: Uuid) -> Result<(), Error> {
+        if id.is_nil() {
+            tracing::error!("missing uuid");
+            return Err(Error::Validation("user id must be populated".to_string()));
+        }
+
+        let user = match self.repo.get_user(id).await {
+            Ok(user) if user.is_deleted() => return Err(Error::Deleted),
             Ok(user) => user,
             Err(e) => return Err(Error::from_repo_error(e)),
         };
 
-        let old_user_email = match &update.email {
-            Some(new_email) => {
-                if !new_email.is_empty() && *new_email != user.email {
-                    Some(user.email.clone())
-                } else {
-                    None
+        if user.email_verified {
+            return Err(Error::ConflictingUser(
+                "email is already verified".to_string(),
+            ));
+        }
+
+        self.send_token(&user, TokenPurpose::VerifyEmail).await
+    }
+
+    #[tracing::instrument(name = "service.change_password", skip_all, fields(user_id = %id))]
+    async fn change_password(
+        &self,
+        id: Uuid,
+        current: Option<Password>,
+        new: Password,
+    ) -> Result<(), Error> {
+        if id.is_nil() {
+            tracing::error!("missing uuid");
+            return Err(Error::Validation("user id must be populated".to_string()));
+        }
+
+        match self.repo.get_user(id).await {
+            Ok(user) if user.is_deleted() => return Err(Error::Deleted),
+            Ok(_) => {}
+            Err(e) => return Err(Error::from_repo_error(e)),
+        }
+
+        // a user who has never had a password has no current password to give, so only
+        // callers allowed to skip the check can set their first one
+        if let Some(current) = current {
+            let hash = self
+                .repo
+                .get_password_hash(id)
+                .await
+                .map_err(Error::from_repo_error)?;
+
+            if !password::verify(current, hash).await {
+                return Err(Error::IncorrectPassword);
+            }
+        }
+
+        let hash = password::hash(new).await?;
+
+        self.repo
+            .set_password_hash(id, &hash)
+            .await
+            .map_err(Error::from_repo_error)
+    }
+
+    #[tracing::instrument(
+        name = "service.purge_deleted_users",
+        skip_all,
+        fields(deleted_before = %deleted_before)
+    )]
+    async fn purge_deleted_users(&self, deleted_before: DateTime<Utc>) -> Result<Vec<Uuid>, Error> {
+        let mut query = ListUsers {
+            limit: PURGE_PAGE_SIZE,
+            cursor: None,
+            filter: UserFilter {
+                deleted: DeletedFilter::Before(deleted_before),
+                ..UserFilter::default()
+            },
+            sort: None,
+        };
+
+        // collect everything up front rather than deleting from under the scan
+        let mut users = vec![];
+        loop {
+            let page = match self.repo.list_users(&query).await {
+                Ok(page) => page,
+                Err(e) => return Err(Error::from_repo_error(e)),
+            };
+            users.extend(page.users);
+
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+
+        let mut purged = vec![];
+        for user in users {
+            // the version condition stops us purging a user that was restored since the scan
+            match self.repo.delete_user(user.id, Some(user.version)).await {
+                Ok(_) => {
+                    tracing::info!("purged user {}", user.id);
+                    purged.push(user.id);
+                }
+                Err(RepoError::VersionMismatch) | Err(RepoError::NotFound) => {
+                    tracing::info!(
+                        "user {} changed since it was scanned, skipping purge",
+                        user.id
+                    );
                 }
+                Err(e) => return Err(Error::from_repo_error(e)),
             }
+        }
+
+        Ok(purged)
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: Repo> auth::Credentials for Service<R> {
+    // every way of failing still verifies a password, so that unknown and deleted users take as
+    // long to reject as a wrong password
+    #[tracing::instrument(name = "service.verify_password", skip_all)]
+    async fn verify_password(&self, email: &str, password: &str) -> Result<String, auth::Error> {
+        let password = Password::new(password.to_string());
+
+        let user = match Email::parse(email) {
+            Ok(email) => match self.repo.get_user_by_email(&email).await {
+                Ok(user) if !user.is_deleted() => Some(user),
+                Ok(_) | Err(RepoError::NotFound) => None,
+                Err(e) => {
+                    tracing::error!("Failed to look up user for login: {}", e);
+                    return Err(auth::Error::Unavailable);
+                }
+            },
+            Err(_) => None,
+        };
+
+        let hash = match &user {
+            Some(user) => match self.repo.get_password_hash(user.id).await {
+                Ok(hash) => hash,
+                Err(e) => {
+                    tracing::error!("Failed to get password hash for login: {}", e);
+                    return Err(auth::Error::Unavailable);
+                }
+            },
             None => None,
         };
 
-        user.update(update);
+        // without a hash this checks against a dummy one, which never matches
+        let matched = password::verify(password, hash).await;
 
-        match self.repo.update_user(&user, old_user_email).await {
-            Ok(_) => Ok(user),
-            Err(e) => Err(Error::from_repo_error(e)),
+        match user {
+            Some(user) if matched => Ok(user.id.to_string()),
+            _ => Err(auth::Error::InvalidCredentials),
         }
     }
+}
 
-    async fn delete_user(&self, id: Uuid) -> Result<(), Error> {
-        if id.is_nil() {
-            tracing::error!("missing uuid");
-            return Err(Error::Validation("user id must be populated".to_string()));
+#[async_trait::async_trait]
+impl<R: Repo> auth::Recovery for Service<R> {
+    #[tracing::instrument(name = "service.verify_email", skip_all)]
+    async fn verify_email(&self, token: &str) -> Result<(), auth::Error> {
+        let token = self.take_token(token, TokenPurpose::VerifyEmail).await?;
+
+        // the token only vouches for the address it was sent to
+        let result = self
+            .modify_user(token.user_id, None, |user| {
+                if user.is_deleted() {
+                    return Err(Error::Deleted);
+                }
+
+                if user.email != token.email {
+                    return Err(Error::ConflictingUser(
+                        "email has changed since the token was sent".to_string(),
+                    ));
+                }
+
+                user.verify_email();
+                Ok(())
+            })
+            .await;
+
+        match result {
+            Ok(_) => {
+                tracing::info!("Verified email of user {}", token.user_id);
+                Ok(())
+            }
+            Err(Error::NotFound | Error::Deleted | Error::ConflictingUser(_)) => {
+                Err(auth::Error::InvalidOneTimeToken)
+            }
+            Err(e) => {
+                tracing::error!("Failed to verify email of user {}: {}", token.user_id, e);
+                Err(auth::Error::Unavailable)
+            }
         }
+    }
 
-        match self.repo.delete_user(id).await {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Error::from_repo_error(e)),
+    // the outcome is the same whether or not the email belongs to anyone, so that asking for a
+    // reset does not reveal which accounts exist. The lookup and the token are dealt with after
+    // responding, so that neither how long they take nor whether they fail can give it away.
+    #[tracing::instrument(name = "service.request_password_reset", skip_all)]
+    async fn request_password_reset(&self, email: &str) -> Result<(), auth::Error> {
+        let Ok(email) = Email::parse(email) else {
+            return Ok(());
+        };
+
+        let span = tracing::info_span!("service.send_password_reset");
+        span.follows_from(tracing::Span::current());
+
+        let service = self.clone();
+        tokio::spawn(async move { service.send_password_reset(email).await }.instrument(span));
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "service.reset_password", skip_all)]
+    async fn reset_password(&self, token: &str, password: &str) -> Result<String, auth::Error> {
+        let token = self.take_token(token, TokenPurpose::ResetPassword).await?;
+
+        match self.repo.get_user(token.user_id).await {
+            Ok(user) if !user.is_deleted() => {}
+            Ok(_) | Err(RepoError::NotFound) => return Err(auth::Error::InvalidOneTimeToken),
+            Err(e) => {
+                tracing::error!("Failed to get user for password reset: {}", e);
+                return Err(auth::Error::Unavailable);
+            }
+        }
+
+        let hash = password::hash(Password::new(password.to_string()))
+            .await
+            .map_err(|_| auth::Error::Unavailable)?;
+
+        match self.repo.set_password_hash(token.user_id, &hash).await {
+            Ok(_) => {
+                tracing::info!("Reset password of user {}", token.user_id);
+                Ok(token.user_id.to_string())
+            }
+            Err(RepoError::NotFound) => Err(auth::Error::InvalidOneTimeToken),
+            Err(e) => {
+                tracing::error!("Failed to set password for reset: {}", e);
+                Err(auth::Error::Unavailable)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::notifications;
+    use crate::users::repo::memory;
+
+    use chrono::NaiveDate;
+
+    // Racing loses the next races updates to a concurrent writer, which changes the user's last
+    // name just before each of them is written
+    #[derive(Clone)]
+    struct Racing {
+        inner: memory::Repo,
+        races: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl Repo for Racing {
+        async fn create_user(
+            &self,
+            user: &User,
+            password_hash: Option<&str>,
+        ) -> Result<(), RepoError> {
+            self.inner.create_user(user, password_hash).await
+        }
+
+        async fn get_user(&self, id: Uuid) -> Result<User, RepoError> {
+            self.inner.get_user(id).await
+        }
+
+        async fn get_user_by_email(&self, email: &Email) -> Result<User, RepoError> {
+            self.inner.get_user_by_email(email).await
+        }
+
+        async fn list_users(&self, query: &ListUsers) -> Result<UserPage, RepoError> {
+            self.inner.list_users(query).await
+        }
+
+        async fn update_user(
+            &self,
+            user: &User,
+            expected_version: u64,
+            old_user_email: Option<Email>,
+        ) -> Result<(), RepoError> {
+            let raced = self
+                .races
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |races| {
+                    races.checked_sub(1)
+                })
+                .is_ok();
+
+            if raced {
+                let mut concurrent = self.inner.get_user(user.id).await?;
+                let read_version = concurrent.version;
+                concurrent.update(UserUpdate {
+                    first_name: None,
+                    last_name: Some("Concurrent".to_string()),
+                    email: None,
+                    dob: None,
+                });
+                self.inner
+                    .update_user(&concurrent, read_version, None)
+                    .await?;
+            }
+
+            self.inner
+                .update_user(user, expected_version, old_user_email)
+                .await
+        }
+
+        async fn delete_user(
+            &self,
+            id: Uuid,
+            expected_version: Option<u64>,
+        ) -> Result<(), RepoError> {
+            self.inner.delete_user(id, expected_version).await
+        }
+
+        async fn get_password_hash(&self, id: Uuid) -> Result<Option<String>, RepoError> {
+            self.inner.get_password_hash(id).await
+        }
+
+        async fn set_password_hash(&self, id: Uuid, password_hash: &str) -> Result<(), RepoError> {
+            self.inner.set_password_hash(id, password_hash).await
+        }
+
+        async fn put_token(&self, token: &Token) -> Result<(), RepoError> {
+            self.inner.put_token(token).await
+        }
+
+        async fn take_token(
+            &self,
+            user_id: Uuid,
+            purpose: TokenPurpose,
+            hash: &str,
+        ) -> Result<Token, RepoError> {
+            self.inner.take_token(user_id, purpose, hash).await
+        }
+    }
+
+    // racing returns a service over a repo that loses the next races updates, along with a user
+    // stored in it
+    async fn racing(races: usize) -> (Service<Racing>, User) {
+        let repo = Racing {
+            inner: memory::Repo::new(),
+            races: Arc::new(AtomicUsize::new(0)),
+        };
+
+        let user = User::new(
+            "Ada".to_string(),
+            "Lovelace".to_string(),
+            Email::parse("ada@example.com").unwrap(),
+            NaiveDate::from_ymd_opt(1990, 12, 10).unwrap(),
+        );
+        repo.create_user(&user, None).await.unwrap();
+        repo.races.store(races, Ordering::SeqCst);
+
+        let mailer = notifications::log::Mailer::new("users@example.com".to_string(), None);
+        let service = Service::new(
+            repo,
+            Arc::new(mailer),
+            TimeDelta::days(1),
+            TimeDelta::hours(1),
+        );
+
+        (service, user)
+    }
+
+    fn rename() -> UserUpdate {
+        UserUpdate {
+            first_name: Some("Augusta".to_string()),
+            last_name: None,
+            email: None,
+            dob: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn unconditional_updates_are_reapplied_after_losing_a_race() {
+        let (service, user) = racing(1).await;
+
+        let updated = service.update_user(user.id, rename(), None).await.unwrap();
+
+        // the update went on top of the concurrent one, rather than overwriting it
+        assert_eq!(updated.first_name, "Augusta");
+        assert_eq!(updated.last_name, "Concurrent");
+        assert_eq!(updated.version, 3);
+    }
+
+    #[tokio::test]
+    async fn conditional_updates_are_not_retried() {
+        let (service, user) = racing(1).await;
+
+        let result = service
+            .update_user(user.id, rename(), Some(user.version))
+            .await;
+
+        assert!(matches!(result, Err(Error::PreconditionFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn retries_give_up_eventually() {
+        let (service, user) = racing(MAX_UPDATE_ATTEMPTS).await;
+
+        let result = service.update_user(user.id, rename(), None).await;
+
+        assert!(matches!(result, Err(Error::ConflictingUser(_))));
+    }
+}
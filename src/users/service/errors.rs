@@ -1,19 +1,32 @@
 use crate::users::repo;
+use crate::validation::FieldError;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("user not found")]
     NotFound,
 
+    #[error("user has been deleted")]
+    Deleted,
+
     #[error("validation error: {0}")]
     Validation(String),
 
+    #[error("{} invalid field(s)", .0.len())]
+    InvalidFields(Vec<FieldError>),
+
     #[error("missing parameters: {0}")]
     MissingParameters(String),
 
     #[error("conflicting user: {0}")]
     ConflictingUser(String),
 
+    #[error("precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("current password is incorrect")]
+    IncorrectPassword,
+
     #[error("internal error")]
     Internal,
 }
@@ -23,7 +36,11 @@ impl Error {
         match repo_err {
             repo::errors::Error::NotFound => Error::NotFound,
             repo::errors::Error::Validation(e) => Error::Validation(e),
-            repo::errors::Error::EmailAddressAlreadyInUse(e) => Error::ConflictingUser(e),
+            repo::errors::Error::EmailAddressAlreadyInUse(e)
+            | repo::errors::Error::UserAlreadyExists(e) => Error::ConflictingUser(e),
+            repo::errors::Error::VersionMismatch => Error::PreconditionFailed(
+                "user has been modified since it was last read".to_string(),
+            ),
             repo::errors::Error::Internal | repo::errors::Error::MalformedResponse(_) => {
                 Error::Internal
             }
@@ -1,32 +1,177 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::tokens;
+
 use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+// Email is a validated address. It keeps the address as the user gave it for display, alongside
+// a canonical form with a lower-cased local part and an ASCII (punycode) domain, which is what
+// uniqueness is decided on. Two emails are equal when their canonical forms are.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Email {
+    display: String,
+    canonical: String,
+}
+
+// characters allowed in an unquoted local part besides ASCII alphanumerics (RFC 5322 atext)
+const ATEXT_SPECIALS: &str = "!#$%&'*+-/=?^_`{|}~";
+
+impl Email {
+    // parse accepts the dot-atom subset of RFC 5322, i.e. no quoted local parts, comments or
+    // address literals, with internationalised domains converted to ASCII
+    pub fn parse(email: &str) -> Result<Self, String> {
+        let display = email.trim();
+
+        let (local, domain) = match display.rsplit_once('@') {
+            Some(parts) => parts,
+            None => return Err("email must contain an @".to_string()),
+        };
+
+        if local.is_empty() || local.len() > 64 {
+            return Err("email local part must be between 1 and 64 characters".to_string());
+        }
+
+        let valid_local = local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c))
+        });
+        if !valid_local {
+            return Err("email local part contains invalid characters".to_string());
+        }
+
+        let domain = match idna::domain_to_ascii_strict(domain) {
+            Ok(domain) => domain,
+            Err(_) => return Err("email domain is invalid".to_string()),
+        };
+
+        let labels: Vec<&str> = domain.split('.').collect();
+        let valid_domain = labels.len() >= 2
+            && labels.iter().all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && !label.starts_with('-')
+                    && !label.ends_with('-')
+                    && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !valid_domain {
+            return Err("email domain is invalid".to_string());
+        }
+
+        let canonical = format!("{}@{}", local.to_ascii_lowercase(), domain);
+        if canonical.len() > 254 {
+            return Err("email must be at most 254 characters".to_string());
+        }
+
+        Ok(Email {
+            display: display.to_string(),
+            canonical,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.display
+    }
+
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+}
+
+impl PartialEq for Email {
+    fn eq(&self, other: &Self) -> bool {
+        self.canonical == other.canonical
+    }
+}
+
+impl Eq for Email {}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display)
+    }
+}
+
+impl TryFrom<String> for Email {
+    type Error = String;
+
+    fn try_from(email: String) -> Result<Self, Self::Error> {
+        Email::parse(&email)
+    }
+}
+
+impl From<Email> for String {
+    fn from(email: Email) -> Self {
+        email.display
+    }
+}
+
+// Password is a password as the user gave it, which is only ever hashed. It is kept out of Debug
+// output so it cannot end up in a log line.
+#[derive(Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        Self(password)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
     pub id: Uuid,
     pub first_name: String,
     pub last_name: String,
-    pub email: String,
+    pub email: Email,
+    // whether the owner of email has shown they receive mail sent to it, which is reset whenever
+    // the email changes
+    #[serde(default)]
+    pub email_verified: bool,
     pub dob: NaiveDate,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    // incremented on every write, used for optimistic concurrency control
+    pub version: u64,
+    // set while the user is soft deleted, until it is either restored or purged
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 impl User {
-    pub fn new(first_name: String, last_name: String, email: String, dob: NaiveDate) -> Self {
+    pub fn new(first_name: String, last_name: String, email: Email, dob: NaiveDate) -> Self {
         let now = Utc::now();
         User {
             id: Uuid::new_v4(),
             first_name,
             last_name,
             email,
+            email_verified: false,
             dob,
             created_at: now,
             updated_at: now,
+            version: 1,
+            deleted_at: None,
         }
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
     pub fn update(&mut self, update: UserUpdate) {
         if let Some(first_name) = update.first_name {
             self.first_name = first_name;
@@ -37,6 +182,10 @@ impl User {
         }
 
         if let Some(email) = update.email {
+            // a change to the display form alone is still the same mailbox
+            if email != self.email {
+                self.email_verified = false;
+            }
             self.email = email;
         }
 
@@ -44,13 +193,319 @@ impl User {
             self.dob = dob;
         }
 
+        self.touch();
+    }
+
+    pub fn verify_email(&mut self) {
+        self.email_verified = true;
+        self.touch();
+    }
+
+    pub fn soft_delete(&mut self) {
+        self.touch();
+        self.deleted_at = Some(self.updated_at);
+    }
+
+    pub fn restore(&mut self) {
+        self.deleted_at = None;
+        self.touch();
+    }
+
+    fn touch(&mut self) {
         self.updated_at = Utc::now();
+        self.version += 1;
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct UserUpdate {
     pub first_name: Option<String>,
     pub last_name: Option<String>,
-    pub email: Option<String>,
+    pub email: Option<Email>,
     pub dob: Option<NaiveDate>,
 }
+
+pub struct ListUsers {
+    pub limit: usize,
+    // opaque continuation token issued by the repo with the previous page
+    pub cursor: Option<String>,
+    pub filter: UserFilter,
+    // None leaves the order up to the repo, which is cheaper to page through
+    pub sort: Option<Sort>,
+}
+
+// all bounds are inclusive
+#[derive(Debug, Default)]
+pub struct UserFilter {
+    pub last_name_prefix: Option<String>,
+    pub dob_from: Option<NaiveDate>,
+    pub dob_to: Option<NaiveDate>,
+    pub created_from: Option<DateTime<Utc>>,
+    pub created_to: Option<DateTime<Utc>>,
+    pub deleted: DeletedFilter,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub enum DeletedFilter {
+    // only users that are not soft deleted
+    #[default]
+    Exclude,
+    // only users soft deleted at or before the given time
+    Before(DateTime<Utc>),
+}
+
+impl UserFilter {
+    pub fn matches(&self, user: &User) -> bool {
+        self.last_name_prefix
+            .as_ref()
+            .is_none_or(|prefix| user.last_name.starts_with(prefix.as_str()))
+            && self.dob_from.is_none_or(|from| user.dob >= from)
+            && self.dob_to.is_none_or(|to| user.dob <= to)
+            && self.created_from.is_none_or(|from| user.created_at >= from)
+            && self.created_to.is_none_or(|to| user.created_at <= to)
+            && match self.deleted {
+                DeletedFilter::Exclude => user.deleted_at.is_none(),
+                DeletedFilter::Before(before) => user.deleted_at.is_some_and(|at| at <= before),
+            }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortField {
+    CreatedAt,
+    LastName,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+// SortKey is the position of a user within a sorted listing
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortKey {
+    CreatedAt(DateTime<Utc>, Uuid),
+    LastName(String, Uuid),
+}
+
+impl Sort {
+    pub fn key(&self, user: &User) -> SortKey {
+        match self.field {
+            SortField::CreatedAt => SortKey::CreatedAt(user.created_at, user.id),
+            SortField::LastName => SortKey::LastName(user.last_name.clone(), user.id),
+        }
+    }
+
+    // ties on the sort field are broken by id so that the order is total and pages never overlap
+    pub fn compare(&self, a: &User, b: &User) -> Ordering {
+        self.compare_key(&self.key(a), &self.key(b))
+    }
+
+    pub fn compare_key(&self, a: &SortKey, b: &SortKey) -> Ordering {
+        match self.order {
+            SortOrder::Asc => a.cmp(b),
+            SortOrder::Desc => b.cmp(a),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UserPage {
+    pub users: Vec<User>,
+    pub next_cursor: Option<String>,
+}
+
+// TokenPurpose is what a one-time token can be used for. A user has at most one outstanding token
+// for each purpose, as issuing another replaces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "verify_email",
+            TokenPurpose::ResetPassword => "reset_password",
+        }
+    }
+
+    pub fn parse(purpose: &str) -> Option<Self> {
+        match purpose {
+            "verify_email" => Some(TokenPurpose::VerifyEmail),
+            "reset_password" => Some(TokenPurpose::ResetPassword),
+            _ => None,
+        }
+    }
+
+    // each purpose has its own prefix, so a token for one cannot be used for the other
+    fn prefix(&self) -> &'static str {
+        match self {
+            TokenPurpose::VerifyEmail => "ev_",
+            TokenPurpose::ResetPassword => "pr_",
+        }
+    }
+}
+
+// Token is a stored one-time token. Only its hash is kept, so a leaked table gives away no
+// working tokens, and it is deleted as it is used.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub user_id: Uuid,
+    pub purpose: TokenPurpose,
+    pub hash: String,
+    // the address the token was mailed to, which is the one using it proves ownership of
+    pub email: Email,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Token {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+// OneTimeToken is a token as mailed to a user, <prefix><user id>_<random>. Carrying the user id
+// lets the stored token be fetched directly, rather than by searching for its hash.
+#[derive(Debug)]
+pub struct OneTimeToken(tokens::Secret<1>);
+
+impl OneTimeToken {
+    pub fn generate(user_id: Uuid, purpose: TokenPurpose) -> Self {
+        Self(tokens::Secret::generate(purpose.prefix(), [user_id]))
+    }
+
+    // parse fails for a token meant for another purpose
+    pub fn parse(value: &str, purpose: TokenPurpose) -> Option<Self> {
+        tokens::Secret::parse(purpose.prefix(), value).map(Self)
+    }
+
+    pub fn user_id(&self) -> Uuid {
+        self.0.ids()[0]
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn hash(&self) -> String {
+        self.0.hash()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_form_decides_uniqueness() {
+        for (email, canonical) in [
+            ("ada@example.com", "ada@example.com"),
+            ("Ada@Example.COM", "ada@example.com"),
+            ("  ada@example.com\t", "ada@example.com"),
+            (
+                "Ada.King+Notes@example.co.uk",
+                "ada.king+notes@example.co.uk",
+            ),
+            ("o'neil@example.com", "o'neil@example.com"),
+            ("ada@bücher.de", "ada@xn--bcher-kva.de"),
+            ("ada@BÜCHER.de", "ada@xn--bcher-kva.de"),
+            ("ada@xn--bcher-kva.de", "ada@xn--bcher-kva.de"),
+            ("ada@例え.jp", "ada@xn--r8jz45g.jp"),
+        ] {
+            assert_eq!(
+                Email::parse(email).unwrap().canonical(),
+                canonical,
+                "{}",
+                email
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_the_address_as_given_for_display() {
+        let email = Email::parse(" Ada@Bücher.de ").unwrap();
+
+        assert_eq!(email.as_str(), "Ada@Bücher.de");
+        assert_eq!(email.to_string(), "Ada@Bücher.de");
+        assert_eq!(String::from(email), "Ada@Bücher.de");
+    }
+
+    #[test]
+    fn emails_are_equal_when_their_canonical_forms_are() {
+        assert_eq!(
+            Email::parse("ADA@bücher.de").unwrap(),
+            Email::parse("ada@xn--bcher-kva.de").unwrap()
+        );
+        assert_ne!(
+            Email::parse("ada@example.com").unwrap(),
+            Email::parse("ada2@example.com").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let long_local = "a".repeat(65);
+        let long_label = "a".repeat(64);
+
+        for email in [
+            "",
+            "ada.example.com",
+            "@example.com",
+            "ada@",
+            &format!("{}@example.com", long_local),
+            ".ada@example.com",
+            "ada.@example.com",
+            "ada..king@example.com",
+            "ada king@example.com",
+            "\"ada\"@example.com",
+            "adä@example.com",
+            "ada@localhost",
+            "ada@example..com",
+            "ada@-example.com",
+            "ada@example-.com",
+            "ada@exa_mple.com",
+            "ada@[127.0.0.1]",
+            &format!("ada@{}.com", long_label),
+        ] {
+            assert!(Email::parse(email).is_err(), "{}", email);
+        }
+    }
+
+    #[test]
+    fn length_limits_are_inclusive() {
+        let local = "a".repeat(64);
+        let label = "b".repeat(63);
+
+        assert!(Email::parse(&format!("{}@example.com", local)).is_ok());
+        assert!(Email::parse(&format!("ada@{}.com", label)).is_ok());
+
+        // 64 + 1 + 189 = 254 characters is the longest address allowed
+        let domain = format!("{}.{}.{}.com", label, label, "c".repeat(57));
+        assert_eq!(domain.len(), 189);
+        assert!(Email::parse(&format!("{}@{}", local, domain)).is_ok());
+
+        let domain = format!("{}.{}.{}.com", label, label, "c".repeat(58));
+        assert_eq!(
+            Email::parse(&format!("{}@{}", local, domain)).unwrap_err(),
+            "email must be at most 254 characters"
+        );
+    }
+
+    #[test]
+    fn deserializes_through_parse() {
+        let email: Email = serde_json::from_str("\"Ada@Example.com\"").unwrap();
+
+        assert_eq!(email.canonical(), "ada@example.com");
+        assert!(serde_json::from_str::<Email>("\"not an email\"").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::LazyLock;
+
+use super::errors::Error;
+use super::idos::Password;
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand::RngCore;
+
+// checked against when there is no real hash to check, so an unknown email takes as long to
+// reject as a wrong password and response times don't reveal which accounts exist
+static DUMMY_HASH: LazyLock<Option<String>> = LazyLock::new(|| {
+    hash_blocking(&Password::new("not a real password".to_string()))
+        .inspect_err(|e| tracing::error!("Failed to hash dummy password: {}", e))
+        .ok()
+});
+
+// hash derives an Argon2id hash with the default parameters (19 MiB, 2 iterations), which are
+// the OWASP recommendation. Hashing is deliberately slow, so it runs off the async workers.
+pub async fn hash(password: Password) -> Result<String, Error> {
+    tokio::task::spawn_blocking(move || hash_blocking(&password))
+        .await
+        .map_err(|e| {
+            tracing::error!("Password hashing task failed: {}", e);
+            Error::Internal
+        })?
+        .map_err(|e| {
+            tracing::error!("Failed to hash password: {}", e);
+            Error::Internal
+        })
+}
+
+// verify checks password against hash, or against a dummy hash when there is none so that the
+// check costs the same either way
+pub async fn verify(password: Password, hash: Option<String>) -> bool {
+    let result = tokio::task::spawn_blocking(move || {
+        let matched = match hash.as_deref().or(DUMMY_HASH.as_deref()) {
+            Some(hash) => verify_blocking(&password, hash),
+            None => false,
+        };
+
+        matched && hash.is_some()
+    })
+    .await;
+
+    match result {
+        Ok(matched) => matched,
+        Err(e) => {
+            tracing::error!("Password verification task failed: {}", e);
+            false
+        }
+    }
+}
+
+fn hash_blocking(password: &Password) -> Result<String, argon2::password_hash::Error> {
+    let mut salt = [0u8; 16];
+    rand::rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt)?;
+
+    Argon2::default()
+        .hash_password(password.as_str().as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+}
+
+// the parameters are read from the hash itself, so hashes made with older parameters still verify
+fn verify_blocking(password: &Password, hash: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_str().as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Stored password hash is malformed: {}", e);
+            false
+        }
+    }
+}